@@ -0,0 +1,48 @@
+/// Errors returned by the admin namespace, as JSON-RPC errors.
+#[derive(Debug)]
+pub enum AdminError {
+    Unauthorized,
+    ParseError,
+    InvalidRequest,
+    MethodNotFound(String),
+    InvalidParams(String),
+    Readonly,
+    Internal(String),
+}
+
+impl AdminError {
+    /// JSON-RPC 2.0 error code
+    pub fn code(&self) -> i64 {
+        match self {
+            AdminError::ParseError => -32700,
+            AdminError::InvalidRequest => -32600,
+            AdminError::MethodNotFound(_) => -32601,
+            AdminError::InvalidParams(_) => -32602,
+            AdminError::Internal(_) => -32603,
+            AdminError::Unauthorized => -32001,
+            AdminError::Readonly => -32002,
+        }
+    }
+}
+
+impl std::fmt::Display for AdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AdminError::Unauthorized => write!(f, "Unauthorized"),
+            AdminError::ParseError => write!(f, "Parse error"),
+            AdminError::InvalidRequest => write!(f, "Invalid request"),
+            AdminError::MethodNotFound(method) => write!(f, "Method not found: {}", method),
+            AdminError::InvalidParams(reason) => write!(f, "Invalid params: {}", reason),
+            AdminError::Readonly => write!(f, "Admin namespace is readonly"),
+            AdminError::Internal(reason) => write!(f, "Internal error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for AdminError {}
+
+impl From<sled::Error> for AdminError {
+    fn from(error: sled::Error) -> Self {
+        AdminError::Internal(error.to_string())
+    }
+}
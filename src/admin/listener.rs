@@ -0,0 +1,222 @@
+use crate::{
+    admin::{
+        error::AdminError,
+        methods::execute_method,
+    },
+    core::{
+        body::{
+            full,
+            ResponseBody,
+        },
+        cache::ResponseCache,
+        keys::ApiKeys,
+        quorum::QuorumStats,
+        shadow::Shadow,
+    },
+    log_err,
+    log_info,
+    Settings,
+};
+
+use http::request::Parts;
+use http_body_util::{
+    BodyExt,
+    Limited,
+};
+use hyper::{
+    body::Incoming,
+    header,
+    server::conn::http1,
+    service::service_fn,
+    Request,
+    StatusCode,
+};
+use hyper_util_blutgang::rt::TokioIo;
+use jsonwebtoken::{
+    decode,
+    Algorithm,
+    DecodingKey,
+    Validation,
+};
+use serde_json::{
+    json,
+    Value,
+};
+use std::{
+    convert::Infallible,
+    sync::{Arc, RwLock},
+};
+use tokio::net::TcpListener;
+
+// Admin requests are small, anything bigger than this is a mistake
+const MAX_ADMIN_REQUEST_SIZE: usize = 1024 * 1024;
+
+/// Everything admin methods can look at or change.
+#[derive(Clone)]
+pub struct AdminParams {
+    pub config: Arc<RwLock<Settings>>,
+    pub api_keys: Arc<ApiKeys>,
+    pub quorum_stats: Arc<QuorumStats>,
+    pub shadow: Arc<Shadow>,
+    pub cache: Arc<ResponseCache>,
+}
+
+/// Bind the admin namespace to its own address and serve it until trident exits.
+pub async fn listen_for_admin_requests(
+    params: AdminParams,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let address = params.config.read().unwrap().admin.address;
+    let listener = TcpListener::bind(address).await?;
+    log_info!("Admin namespace bound to: {}", address);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let io = TokioIo::new(stream);
+        let params = params.clone();
+
+        tokio::task::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(
+                    io,
+                    service_fn(|req| accept_admin_request(req, params.clone())),
+                )
+                .await
+            {
+                log_err!("Error serving admin connection: {:?}", err);
+            }
+        });
+    }
+}
+
+/// Check the `Authorization: Bearer <token>` header against our JWT secret
+fn is_authorized(parts: &Parts, key: &DecodingKey) -> bool {
+    let token = parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok())
+        .and_then(|auth| auth.strip_prefix("Bearer "));
+
+    match token {
+        Some(token) => decode::<Value>(token, key, &Validation::new(Algorithm::HS256)).is_ok(),
+        None => false,
+    }
+}
+
+fn admin_response(status: StatusCode, body: Value) -> hyper::Response<ResponseBody> {
+    hyper::Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(full(body.to_string()))
+        .unwrap()
+}
+
+fn error_response(id: Value, error: AdminError) -> hyper::Response<ResponseBody> {
+    let status = match error {
+        AdminError::Unauthorized => StatusCode::UNAUTHORIZED,
+        _ => StatusCode::OK,
+    };
+
+    admin_response(
+        status,
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {
+                "code": error.code(),
+                "message": error.to_string(),
+            },
+        }),
+    )
+}
+
+async fn accept_admin_request(
+    req: Request<Incoming>,
+    params: AdminParams,
+) -> Result<hyper::Response<ResponseBody>, Infallible> {
+    let (parts, body) = req.into_parts();
+
+    let (jwt_key, readonly) = {
+        let config_guard = params.config.read().unwrap();
+        let admin = &config_guard.admin;
+        (admin.jwt.then(|| admin.key.clone()), admin.readonly)
+    };
+
+    if let Some(key) = jwt_key {
+        if !is_authorized(&parts, &key) {
+            return Ok(error_response(Value::Null, AdminError::Unauthorized));
+        }
+    }
+
+    let body = match Limited::new(body, MAX_ADMIN_REQUEST_SIZE).collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(_) => return Ok(error_response(Value::Null, AdminError::InvalidRequest)),
+    };
+
+    let request: Value = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(_) => return Ok(error_response(Value::Null, AdminError::ParseError)),
+    };
+
+    let id = request["id"].clone();
+    let method = match request["method"].as_str() {
+        Some(method) => method,
+        None => return Ok(error_response(id, AdminError::InvalidRequest)),
+    };
+
+    match execute_method(method, &request["params"], readonly, &params).await {
+        Ok(result) => Ok(admin_response(
+            StatusCode::OK,
+            json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "result": result,
+            }),
+        )),
+        Err(error) => Ok(error_response(id, error)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::parts;
+    use jsonwebtoken::{
+        encode,
+        EncodingKey,
+        Header,
+    };
+
+    fn token(secret: &[u8]) -> String {
+        let exp = chrono::Utc::now().timestamp() + 60;
+        encode(&Header::default(), &json!({ "exp": exp }), &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    #[test]
+    fn bearer_tokens() {
+        let key = DecodingKey::from_secret(b"secret");
+
+        let authorized = |authorization: &str| {
+            is_authorized(&parts("POST", "/", &[("Authorization", authorization)]), &key)
+        };
+
+        assert!(authorized(&format!("Bearer {}", token(b"secret"))));
+        assert!(!authorized(&format!("Bearer {}", token(b"other"))));
+        assert!(!authorized(&token(b"secret")));
+        assert!(!is_authorized(&parts("POST", "/", &[]), &key));
+    }
+
+    #[tokio::test]
+    async fn errors_are_json_rpc() {
+        let response = error_response(json!(7), AdminError::MethodNotFound("nope".to_string()));
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["jsonrpc"], "2.0");
+        assert_eq!(body["id"], 7);
+        assert_eq!(body["error"]["code"], -32601);
+
+        let response = error_response(Value::Null, AdminError::Unauthorized);
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
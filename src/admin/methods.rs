@@ -0,0 +1,305 @@
+use crate::{
+    admin::{
+        error::AdminError,
+        listener::AdminParams,
+    },
+    config::system::{
+        log_settings,
+        update_log_settings,
+        Level,
+    },
+    core::keys::ApiKey,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+/// Run an admin method and return its result.
+///
+/// Methods that change state are refused if the admin namespace is `readonly`.
+pub async fn execute_method(
+    method: &str,
+    params: &Value,
+    readonly: bool,
+    admin: &AdminParams,
+) -> Result<Value, AdminError> {
+    match method {
+        "trident_list_keys" => list_keys(admin),
+        "trident_key_usage" => key_usage(params, admin),
+        "trident_quorum_divergences" => Ok(admin.quorum_stats.export()),
+        "trident_shadow_stats" => Ok(admin.shadow.export()),
+        "trident_cache_stats" => Ok(admin.cache.stats()),
+        "trident_cache_get" => cache_get(params, admin).await,
+        "trident_log_levels" => Ok(log_levels()),
+        "trident_add_key"
+        | "trident_remove_key"
+        | "trident_cache_purge"
+        | "trident_set_log_level"
+            if readonly =>
+        {
+            Err(AdminError::Readonly)
+        }
+        "trident_cache_purge" => cache_purge(params, admin).await,
+        "trident_add_key" => add_key(params, admin),
+        "trident_remove_key" => remove_key(params, admin),
+        "trident_set_log_level" => set_log_level(params),
+        _ => Err(AdminError::MethodNotFound(method.to_string())),
+    }
+}
+
+/// `params: [{"key"?, "name", "daily_quota"?, "monthly_quota"?, "routes"?, "group"?}]`
+///
+/// If no `key` is given, a random one is generated. Returns the key.
+fn add_key(params: &Value, admin: &AdminParams) -> Result<Value, AdminError> {
+    let settings = &params[0];
+    if !settings.is_object() {
+        return Err(AdminError::InvalidParams(
+            "expected an object with the key settings".to_string(),
+        ));
+    }
+
+    let key = match &settings["key"] {
+        Value::Null => None,
+        Value::String(key) if !key.is_empty() && !key.contains('/') => Some(key.clone()),
+        _ => {
+            return Err(AdminError::InvalidParams(
+                "key must be a non-empty string without slashes".to_string(),
+            ))
+        }
+    };
+
+    let api_key: ApiKey = serde_json::from_value(settings.clone())
+        .map_err(|e| AdminError::InvalidParams(e.to_string()))?;
+
+    let key = admin.api_keys.insert(key, &api_key)?;
+    Ok(json!(key))
+}
+
+/// `params: ["<key>"]`, returns false if the key didn't exist.
+fn remove_key(params: &Value, admin: &AdminParams) -> Result<Value, AdminError> {
+    let key = params[0]
+        .as_str()
+        .ok_or_else(|| AdminError::InvalidParams("expected the key to remove".to_string()))?;
+
+    Ok(json!(admin.api_keys.remove(key)?))
+}
+
+fn list_keys(admin: &AdminParams) -> Result<Value, AdminError> {
+    let keys: Vec<Value> = admin
+        .api_keys
+        .list()
+        .into_iter()
+        .map(|(key, api_key)| {
+            let mut entry = json!(api_key);
+            entry["key"] = json!(key);
+            entry
+        })
+        .collect();
+
+    Ok(json!(keys))
+}
+
+/// `params: ["<key>"]` for a single key, or nothing for all of them.
+fn key_usage(params: &Value, admin: &AdminParams) -> Result<Value, AdminError> {
+    let key = params[0].as_str();
+    Ok(admin.api_keys.usage(key))
+}
+
+/// `params: ["<cache key>"]`, returns null if nothing is cached under it.
+async fn cache_get(params: &Value, admin: &AdminParams) -> Result<Value, AdminError> {
+    let key = params[0]
+        .as_str()
+        .ok_or_else(|| AdminError::InvalidParams("expected the cache key".to_string()))?;
+
+    Ok(admin.cache.lookup(key).await)
+}
+
+/// `params: ["<key prefix>"]`, returns how many entries were dropped.
+/// An empty prefix empties the whole cache.
+async fn cache_purge(params: &Value, admin: &AdminParams) -> Result<Value, AdminError> {
+    let prefix = params[0]
+        .as_str()
+        .ok_or_else(|| AdminError::InvalidParams("expected a cache key prefix".to_string()))?;
+
+    Ok(json!(admin.cache.purge(prefix).await))
+}
+
+fn log_levels() -> Value {
+    let settings = log_settings();
+    let filters: serde_json::Map<String, Value> = settings
+        .filters
+        .iter()
+        .map(|(module, level)| (module.clone(), json!(level.name())))
+        .collect();
+
+    json!({
+        "level": settings.level.name(),
+        "filters": filters,
+    })
+}
+
+/// `params: ["<level>"]` for the default level, or `["<module>", "<level>"]`
+/// for a module like `utils::aptos`. Returns the levels in effect afterwards.
+fn set_log_level(params: &Value) -> Result<Value, AdminError> {
+    let (module, level) = match (params[0].as_str(), params[1].as_str()) {
+        (Some(module), Some(level)) => (Some(module), level),
+        (Some(level), None) => (None, level),
+        _ => {
+            return Err(AdminError::InvalidParams(
+                "expected a level, optionally after a module".to_string(),
+            ))
+        }
+    };
+    let level = Level::from_name(level)
+        .ok_or_else(|| AdminError::InvalidParams(format!("invalid log level {}", level)))?;
+
+    update_log_settings(|settings| settings.set_level(module, level));
+    Ok(log_levels())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::types::{
+            CacheSettings,
+            Settings,
+            ShadowSettings,
+        },
+        core::{
+            backend::SledBackend,
+            cache::ResponseCache,
+            keys::ApiKeys,
+            quorum::QuorumStats,
+            shadow::Shadow,
+        },
+    };
+    use std::sync::{
+        Arc,
+        RwLock,
+    };
+
+    async fn admin() -> AdminParams {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cache = ResponseCache::open(
+            Box::new(SledBackend::open(&db).unwrap()),
+            &CacheSettings::default(),
+            0,
+        )
+        .await
+        .unwrap();
+
+        AdminParams {
+            config: Arc::new(RwLock::new(Settings::default())),
+            api_keys: Arc::new(ApiKeys::open(&db).unwrap()),
+            quorum_stats: Arc::new(QuorumStats::new()),
+            shadow: Arc::new(Shadow::new(&ShadowSettings::default())),
+            cache: Arc::new(cache),
+        }
+    }
+
+    async fn call(admin: &AdminParams, method: &str, params: Value) -> Result<Value, AdminError> {
+        execute_method(method, &params, false, admin).await
+    }
+
+    #[tokio::test]
+    async fn readonly_refuses_changes() {
+        let admin = admin().await;
+        for method in [
+            "trident_add_key",
+            "trident_remove_key",
+            "trident_cache_purge",
+            "trident_set_log_level",
+        ] {
+            let result = execute_method(method, &json!(["x"]), true, &admin).await;
+            assert!(matches!(result, Err(AdminError::Readonly)), "{}", method);
+        }
+
+        assert!(execute_method("trident_list_keys", &Value::Null, true, &admin).await.is_ok());
+        assert!(matches!(
+            call(&admin, "trident_nope", Value::Null).await,
+            Err(AdminError::MethodNotFound(method)) if method == "trident_nope"
+        ));
+    }
+
+    #[tokio::test]
+    async fn keys() {
+        let admin = admin().await;
+        let key = call(
+            &admin,
+            "trident_add_key",
+            json!([{"key": "abc", "name": "indexer", "daily_quota": 10}]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(key, "abc");
+        let generated = call(&admin, "trident_add_key", json!([{"name": "wallet"}]))
+            .await
+            .unwrap();
+        assert!(generated.as_str().is_some_and(|key| !key.is_empty()));
+
+        assert!(matches!(
+            call(&admin, "trident_add_key", json!([{"key": "a/b", "name": "bad"}])).await,
+            Err(AdminError::InvalidParams(_))
+        ));
+        assert!(matches!(
+            call(&admin, "trident_add_key", json!(["abc"])).await,
+            Err(AdminError::InvalidParams(_))
+        ));
+
+        let keys = call(&admin, "trident_list_keys", Value::Null).await.unwrap();
+        let indexer = keys
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|key| key["key"] == "abc")
+            .unwrap();
+        assert_eq!(indexer["name"], "indexer");
+        assert_eq!(indexer["daily_quota"], 10);
+
+        assert_eq!(call(&admin, "trident_remove_key", json!(["abc"])).await.unwrap(), true);
+        assert_eq!(call(&admin, "trident_remove_key", json!(["abc"])).await.unwrap(), false);
+    }
+
+    #[tokio::test]
+    async fn cache() {
+        let admin = admin().await;
+        admin.cache.insert("GET /v1/accounts/0x1", 200, None, b"{}", None, None).await;
+        admin.cache.insert("GET /v1/blocks/by_height/1", 200, None, b"{}", None, None).await;
+
+        let get = |key: &'static str| call(&admin, "trident_cache_get", json!([key]));
+        assert_eq!(get("GET /v1/accounts/0x1").await.unwrap()["status"], 200);
+
+        let purged = call(&admin, "trident_cache_purge", json!(["GET /v1/accounts/"]))
+            .await
+            .unwrap();
+        assert_eq!(purged, 1);
+        assert_eq!(get("GET /v1/accounts/0x1").await.unwrap(), Value::Null);
+        assert_ne!(get("GET /v1/blocks/by_height/1").await.unwrap(), Value::Null);
+
+        assert!(matches!(
+            call(&admin, "trident_cache_purge", json!([])).await,
+            Err(AdminError::InvalidParams(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn set_log_levels() {
+        let admin = admin().await;
+        let set = "trident_set_log_level";
+
+        let levels = call(&admin, set, json!(["admin::methods", "debug"])).await.unwrap();
+        assert_eq!(levels["filters"]["admin::methods"], "debug");
+
+        assert!(matches!(
+            call(&admin, set, json!(["loud"])).await,
+            Err(AdminError::InvalidParams(_))
+        ));
+        assert!(matches!(
+            call(&admin, set, json!([])).await,
+            Err(AdminError::InvalidParams(_))
+        ));
+    }
+}
@@ -0,0 +1,3 @@
+mod error;
+pub mod listener;
+mod methods;
@@ -1,4 +1,13 @@
-use crate::{config::error::ConfigError, log_err, Rpc};
+use crate::{
+    config::error::ConfigError,
+    core::{
+        networks::Network,
+        upstream_stats::UpstreamStats,
+    },
+    log_err,
+    log_info,
+    Rpc,
+};
 use std::time::Instant;
 use tokio::sync::mpsc;
 
@@ -40,7 +49,7 @@ async fn set_starting_latency(
     let avg_latency = latencies.iter().sum::<f64>() / latencies.len() as f64;
     rpc.update_latency(avg_latency);
 
-    println!("{}: {}ns", rpc.name, rpc.status.latency);
+    log_info!(UPSTREAM = rpc.name; "{}: {}ns", rpc.name, rpc.status.latency);
 
     tx.send(StartingLatencyResp::Ok(rpc)).await?;
 
@@ -92,3 +101,109 @@ pub async fn sort_by_latency(
     Ok((sorted_rpc_list, poverty_list))
 }
 
+
+/// Get the RPCs of `network` ready to serve.
+///
+/// RPCs with saved stats newer than `max_age` ms get them back, so with
+/// `sort_on_startup` they only need one request to show they're still up.
+/// The rest get measured `ma_length` times like before.
+pub async fn prepare_network(
+    network: &Network,
+    stats: &UpstreamStats,
+    sort_on_startup: bool,
+    ma_length: f64,
+    max_age: u64,
+) -> Result<(), ConfigError> {
+    let rpcs = std::mem::take(&mut *network.rpc_list.write().unwrap());
+
+    let mut known = Vec::new();
+    let mut unknown = Vec::new();
+    let mut poverty_list = Vec::new();
+    for mut rpc in rpcs {
+        match stats.load(network.name.as_deref(), &rpc, max_age) {
+            Some(saved) => {
+                rpc.restore_latency(saved.latency_data);
+                // Probing on startup tells us whether it's back up anyway
+                if saved.is_erroring && !sort_on_startup {
+                    rpc.status.is_erroring = true;
+                    poverty_list.push(rpc);
+                } else {
+                    known.push(rpc);
+                }
+            }
+            None => unknown.push(rpc),
+        }
+    }
+    if !known.is_empty() || !poverty_list.is_empty() {
+        log_info!(
+            "Restored saved stats for {} RPCs",
+            known.len() + poverty_list.len()
+        );
+    }
+
+    let mut rpc_list = Vec::new();
+    if sort_on_startup {
+        for (rpcs, samples) in [(known, 1.0), (unknown, ma_length)] {
+            if rpcs.is_empty() {
+                continue;
+            }
+            let (sorted, poverty) = sort_by_latency(rpcs, Vec::new(), samples).await?;
+            rpc_list.extend(sorted);
+            poverty_list.extend(poverty);
+        }
+        rpc_list.sort_by(|a, b| a.status.latency.total_cmp(&b.status.latency));
+    } else {
+        rpc_list.extend(known);
+        rpc_list.extend(unknown);
+    }
+
+    *network.rpc_list.write().unwrap() = rpc_list;
+    network.poverty_list.write().unwrap().extend(poverty_list);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::network;
+    use std::sync::{
+        Arc,
+        RwLock,
+    };
+
+    fn rpc(port: u16) -> Rpc {
+        Rpc::new(format!("http://127.0.0.1:{}", port), None, 15, 0, 3.0)
+    }
+
+    #[tokio::test]
+    async fn saved_stats_are_restored() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let stats = UpstreamStats::open(&db).unwrap();
+
+        let mut known = rpc(9101);
+        known.restore_latency(vec![1.0, 2.0, 3.0]);
+        let mut down = rpc(9102);
+        down.status.is_erroring = true;
+
+        let network = Network {
+            rpc_list: Arc::new(RwLock::new(vec![known, down])),
+            ..network(None)
+        };
+        stats.save(std::slice::from_ref(&network));
+
+        *network.rpc_list.write().unwrap() = vec![rpc(9103), rpc(9101), rpc(9102)];
+        prepare_network(&network, &stats, false, 3.0, 60_000)
+            .await
+            .unwrap();
+
+        let rpc_list = network.rpc_list.read().unwrap();
+        let urls: Vec<&str> = rpc_list.iter().map(|rpc| rpc.url.as_str()).collect();
+        assert_eq!(urls, vec!["http://127.0.0.1:9101", "http://127.0.0.1:9103"]);
+        assert_eq!(rpc_list[0].status.latency, 2.0);
+        assert!(rpc_list[1].status.latency_data.is_empty());
+
+        let poverty_list = network.poverty_list.read().unwrap();
+        assert_eq!(poverty_list.len(), 1);
+        assert!(poverty_list[0].status.is_erroring);
+    }
+}
@@ -1,72 +1,287 @@
+use crate::config::types::LogSettings;
+
+use std::{
+    fmt,
+    io::{
+        IsTerminal,
+        Write,
+    },
+    sync::{
+        OnceLock,
+        RwLock,
+    },
+};
 
 // Version consts, dont impact functionality
 pub const VERSION_STR: &str = "trident 0.1.0";
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    // Syslog priority, which is what journald goes by
+    #[cfg(feature = "journald")]
+    fn priority(&self) -> u32 {
+        match self {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug => 7,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Level::Error => "Err:",
+            Level::Warn => "Wrn:",
+            Level::Info => "Info:",
+            Level::Debug => "Dbg:",
+        }
+    }
+
+    fn color(&self) -> &'static str {
+        match self {
+            Level::Error => "\x1b[31m",
+            Level::Warn => "\x1b[93m",
+            Level::Info => "\x1b[35m",
+            Level::Debug => "\x1b[34m",
+        }
+    }
+}
+
+/// How log lines get written to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LogFormat {
+    // `Info: <message>`, colored when stdout is a terminal
+    #[default]
+    Text,
+    // One JSON object per line, fields included
+    Json,
+}
+
+// The defaults until the config is read
+static LOGGER: RwLock<LogSettings> = RwLock::new(LogSettings::DEFAULT);
+static COLOR: OnceLock<bool> = OnceLock::new();
+
+pub fn set_log_settings(settings: LogSettings) {
+    *LOGGER.write().unwrap_or_else(|e| e.into_inner()) = settings;
+}
+
+/// Change the settings in place, like the admin namespace does at runtime
+pub fn update_log_settings(update: impl FnOnce(&mut LogSettings)) {
+    update(&mut LOGGER.write().unwrap_or_else(|e| e.into_inner()));
+}
+
+pub fn log_settings() -> LogSettings {
+    LOGGER.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Format to log `level` from `module` in, or `None` if it's filtered out
+pub fn log_format(level: Level, module: &str) -> Option<LogFormat> {
+    format_for(&LOGGER.read().unwrap_or_else(|e| e.into_inner()), level, module)
+}
+
+fn format_for(settings: &LogSettings, level: Level, module: &str) -> Option<LogFormat> {
+    (level <= settings.level_for(module)).then_some(settings.format)
+}
+
+/// Write a log line. Use `log_info!` and friends instead, they skip
+/// formatting when the level is filtered out.
+///
+/// `fields` end up in JSON lines and journald entries, text lines only get the message.
+pub fn log(
+    format: LogFormat,
+    level: Level,
+    module: &str,
+    fields: &[(&str, &dyn fmt::Display)],
+    message: fmt::Arguments,
+) {
+    let module = short_module(module);
+    let message = message.to_string();
+
+    #[cfg(feature = "journald")]
+    log_journald(level, module, fields, &message);
+
+    let color = format == LogFormat::Text && *COLOR.get_or_init(|| std::io::stdout().is_terminal());
+    let line = format_line(format, color, level, module, fields, message);
+
+    // Nowhere to report it if stdout is gone
+    let _ = writeln!(std::io::stdout().lock(), "{}", line);
+}
+
+fn format_line(
+    format: LogFormat,
+    color: bool,
+    level: Level,
+    module: &str,
+    fields: &[(&str, &dyn fmt::Display)],
+    message: String,
+) -> String {
+    match format {
+        LogFormat::Text => {
+            if color {
+                format!("{}{}\x1b[0m {}", level.color(), level.label(), message)
+            } else {
+                format!("{} {}", level.label(), message)
+            }
+        }
+        LogFormat::Json => {
+            let mut entry = serde_json::Map::new();
+            entry.insert(
+                "time".to_string(),
+                chrono::Utc::now()
+                    .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+                    .into(),
+            );
+            entry.insert("level".to_string(), level.name().into());
+            entry.insert("module".to_string(), module.into());
+            entry.insert("message".to_string(), message.into());
+            for (key, value) in fields {
+                entry.insert(key.to_lowercase(), value.to_string().into());
+            }
+            serde_json::Value::Object(entry).to_string()
+        }
+    }
+}
+
 #[cfg(feature = "journald")]
-pub fn log_journald(level: u32, message: &str) {
+fn log_journald(level: Level, module: &str, fields: &[(&str, &dyn fmt::Display)], message: &str) {
     use systemd::journal;
-    journal::print(level, message);
+
+    let mut entry = vec![
+        format!("MESSAGE={}", message),
+        format!("PRIORITY={}", level.priority()),
+        format!("MODULE={}", module),
+    ];
+    for (key, value) in fields {
+        entry.push(format!("{}={}", key.to_uppercase(), value));
+    }
+    journal::send(&entry.iter().map(|field| field.as_str()).collect::<Vec<_>>());
+}
+
+// `trident::utils::aptos` goes by `utils::aptos`, and the crate root by `trident`
+pub fn short_module(module: &str) -> &str {
+    module.strip_prefix("trident::").unwrap_or(module)
 }
 
+/// Log at `$level`. Structured fields can go in front of the message, like
+/// `log_at!(Level::Warn, UPSTREAM = rpc.name; "{} is down", rpc.name)`.
 #[macro_export]
-macro_rules! log_info {
-    ($fmt:expr, $($arg:tt)*) => {
-        let message = format!($fmt, $($arg)*);
-        #[cfg(feature = "journald")]
-        {
-            use $crate::config::system::log_journald;
-            log_journald(6, &message);
+macro_rules! log_at {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        if let Some(format) = $crate::config::system::log_format($level, module_path!()) {
+            $crate::config::system::log(
+                format,
+                $level,
+                module_path!(),
+                &[$((stringify!($key), &$value as &dyn std::fmt::Display)),+],
+                format_args!($($arg)+),
+            );
         }
-        println!("\x1b[35mInfo:\x1b[0m {}", message)
     };
-    ($fmt:expr) => {
-        #[cfg(feature = "journald")]
-        {
-            use $crate::config::system::log_journald;
-            log_journald(6, $fmt);
+    ($level:expr, $($arg:tt)+) => {
+        if let Some(format) = $crate::config::system::log_format($level, module_path!()) {
+            $crate::config::system::log(format, $level, module_path!(), &[], format_args!($($arg)+));
         }
-        println!(concat!("\x1b[35mInfo:\x1b[0m ", $fmt))
     };
 }
 
 #[macro_export]
-macro_rules! log_wrn {
-    ($fmt:expr, $($arg:tt)*) => {
-        let message = format!($fmt, $($arg)*);
-        #[cfg(feature = "journald")]
-        {
-            use $crate::config::system::log_journald;
-            log_journald(4, &message);
-        }
-        println!("\x1b[93mWrn:\x1b[0m {}", message)
+macro_rules! log_dbg {
+    ($($arg:tt)+) => {
+        $crate::log_at!($crate::config::system::Level::Debug, $($arg)+)
     };
-    ($fmt:expr) => {
-        #[cfg(feature = "journald")]
-        {
-            use $crate::config::system::log_journald;
-            log_journald(4, $fmt);
-        }
-        println!(concat!("\x1b[93mWrn:\x1b[0m ", $fmt))
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)+) => {
+        $crate::log_at!($crate::config::system::Level::Info, $($arg)+)
     };
 }
 
 #[macro_export]
-macro_rules! log_err {
-    ($fmt:expr, $($arg:tt)*) => {
-        let message = format!($fmt, $($arg)*);
-        #[cfg(feature = "journald")]
-        {
-            use $crate::config::system::log_journald;
-            log_journald(3, &message);
-        }
-        println!("\x1b[31mErr:\x1b[0m {}", message)
+macro_rules! log_wrn {
+    ($($arg:tt)+) => {
+        $crate::log_at!($crate::config::system::Level::Warn, $($arg)+)
     };
-    ($fmt:expr) => {
-        #[cfg(feature = "journald")]
-        {
-            use $crate::config::system::log_journald;
-            log_journald(3, $fmt);
-        }
-        println!(concat!("\x1b[31mErr:\x1b[0m ", $fmt))
+}
+
+#[macro_export]
+macro_rules! log_err {
+    ($($arg:tt)+) => {
+        $crate::log_at!($crate::config::system::Level::Error, $($arg)+)
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_filtering() {
+        let mut settings = LogSettings::DEFAULT;
+        settings.level = Level::Warn;
+        settings.set_level(Some("core::cache"), Level::Debug);
+        settings.format = LogFormat::Json;
+
+        assert_eq!(format_for(&settings, Level::Error, "trident::core::algo"), Some(LogFormat::Json));
+        assert_eq!(format_for(&settings, Level::Warn, "trident::core::algo"), Some(LogFormat::Json));
+        assert_eq!(format_for(&settings, Level::Info, "trident::core::algo"), None);
+        assert_eq!(format_for(&settings, Level::Debug, "trident::core::cache"), Some(LogFormat::Json));
+    }
+
+    #[test]
+    fn text_lines() {
+        let line = format_line(LogFormat::Text, false, Level::Warn, "core::algo", &[], "hi".to_string());
+        assert_eq!(line, "Wrn: hi");
+
+        let line = format_line(LogFormat::Text, true, Level::Error, "core::algo", &[], "hi".to_string());
+        assert_eq!(line, "\x1b[31mErr:\x1b[0m hi");
+    }
+
+    #[test]
+    fn json_lines() {
+        let name = "fast";
+        let line = format_line(
+            LogFormat::Json,
+            false,
+            Level::Info,
+            "core::algo",
+            &[("UPSTREAM", &name), ("STATUS", &200)],
+            "picked fast".to_string(),
+        );
+
+        let entry: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert!(entry["time"].as_str().is_some_and(|time| time.ends_with('Z')));
+        assert_eq!(entry["level"], "info");
+        assert_eq!(entry["module"], "core::algo");
+        assert_eq!(entry["message"], "picked fast");
+        assert_eq!(entry["upstream"], "fast");
+        assert_eq!(entry["status"], "200");
+    }
+}
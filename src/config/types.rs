@@ -1,4 +1,14 @@
-use crate::{config::setup::sort_by_latency, log_info, Rpc};
+use crate::{
+    config::system::{
+        set_log_settings,
+        short_module,
+        Level,
+        LogFormat,
+    },
+    log_info,
+    utils::rpc::DEFAULT_MAX_RESPONSE_SIZE,
+    Rpc,
+};
 use clap::{ArgMatches, Command};
 use jsonwebtoken::DecodingKey;
 
@@ -7,10 +17,15 @@ use std::{
     fmt::Debug,
     fs::{self},
     net::SocketAddr,
-    println,
 };
 
-use toml::Value;
+use toml::{
+    Table,
+    Value,
+};
+
+// Top level tables that configure trident itself. Everything else is an RPC.
+const RESERVED_TABLES: &[&str] = &["trident", "sled", "admin", "ratelimit", "api_keys", "route", "networks", "sessions", "shadow", "cache", "log"];
 
 #[derive(Clone)]
 pub struct AdminSettings {
@@ -24,8 +39,8 @@ pub struct AdminSettings {
 impl Default for AdminSettings {
     fn default() -> Self {
         Self {
-            enabled: true,
-            address: "127.0.0.1:3001".parse::<SocketAddr>().unwrap(),
+            enabled: false,
+            address: "127.0.0.1:5715".parse::<SocketAddr>().unwrap(),
             readonly: false,
             jwt: false,
             key: DecodingKey::from_secret(b""),
@@ -44,15 +59,912 @@ impl Debug for AdminSettings {
     }
 }
 
+impl AdminSettings {
+    fn from_table(table: &Table) -> Self {
+        let enabled = table
+            .get("enabled")
+            .expect("\x1b[31mErr:\x1b[0m Missing admin enabled toggle!")
+            .as_bool()
+            .expect("\x1b[31mErr:\x1b[0m Could not parse admin enabled as bool!");
+
+        let address = table
+            .get("address")
+            .expect("\x1b[31mErr:\x1b[0m Missing admin address!")
+            .as_str()
+            .expect("\x1b[31mErr:\x1b[0m Could not parse admin address as str!")
+            .replace("localhost", "127.0.0.1")
+            .parse::<SocketAddr>()
+            .expect("\x1b[31mErr:\x1b[0m Could not parse admin address to SocketAddr!");
+
+        let readonly = table
+            .get("readonly")
+            .map(|readonly| {
+                readonly
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse admin readonly as bool!")
+            })
+            .unwrap_or(false);
+
+        let jwt = table
+            .get("jwt")
+            .map(|jwt| {
+                jwt.as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse admin jwt as bool!")
+            })
+            .unwrap_or(false);
+
+        let key = if jwt {
+            let secret = table
+                .get("key")
+                .expect("\x1b[31mErr:\x1b[0m Missing admin JWT key!")
+                .as_str()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse admin JWT key as str!");
+            DecodingKey::from_secret(secret.as_bytes())
+        } else {
+            DecodingKey::from_secret(b"")
+        };
+
+        Self {
+            enabled,
+            address,
+            readonly,
+            jwt,
+            key,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiKeySettings {
+    // Require a valid API key on every request
+    pub enabled: bool,
+    // Header clients send their key in
+    pub header: String,
+    // Also accept keys as a `/key/<key>/...` path prefix
+    pub path_prefix: bool,
+}
+
+impl Default for ApiKeySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            header: "X-API-Key".to_string(),
+            path_prefix: false,
+        }
+    }
+}
+
+impl ApiKeySettings {
+    fn from_table(table: &Table) -> Self {
+        let enabled = table
+            .get("enabled")
+            .map(|enabled| {
+                enabled
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse api_keys enabled as bool!")
+            })
+            .unwrap_or(true);
+
+        let header = table
+            .get("header")
+            .map(|header| {
+                header
+                    .as_str()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse api_keys header as str!")
+                    .to_string()
+            })
+            .unwrap_or("X-API-Key".to_string());
+
+        let path_prefix = table
+            .get("path_prefix")
+            .map(|prefix| {
+                prefix
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse api_keys path_prefix as bool!")
+            })
+            .unwrap_or(false);
+
+        Self {
+            enabled,
+            header,
+            path_prefix,
+        }
+    }
+}
+
+/// What we use to tell clients apart when rate limiting
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientKey {
+    Ip,
+    ApiKey,
+    Header(String),
+}
+
+/// Token bucket parameters for one class of requests
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitSettings {
+    pub enabled: bool,
+    pub key: ClientKey,
+    pub trust_forwarded_for: bool,
+    // Proxies in front of us that append to `X-Forwarded-For`
+    pub trusted_proxies: usize,
+    pub read: Option<Limit>,
+    pub view: Option<Limit>,
+    pub submit: Option<Limit>,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            key: ClientKey::Ip,
+            trust_forwarded_for: false,
+            trusted_proxies: 1,
+            read: None,
+            view: None,
+            submit: None,
+        }
+    }
+}
+
+impl RateLimitSettings {
+    fn from_table(table: &Table) -> Self {
+        let enabled = table
+            .get("enabled")
+            .map(|enabled| {
+                enabled
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse ratelimit enabled as bool!")
+            })
+            .unwrap_or(true);
+
+        let key = match table.get("key").and_then(|key| key.as_str()) {
+            None | Some("ip") => ClientKey::Ip,
+            Some("api_key") => ClientKey::ApiKey,
+            Some("header") => ClientKey::Header(
+                table
+                    .get("header")
+                    .expect("\x1b[31mErr:\x1b[0m Missing ratelimit header!")
+                    .as_str()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse ratelimit header as str!")
+                    .to_string(),
+            ),
+            Some(key) => panic!("\x1b[31mErr:\x1b[0m Unknown ratelimit key: {}", key),
+        };
+
+        let trust_forwarded_for = table
+            .get("trust_forwarded_for")
+            .map(|trust| {
+                trust
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse trust_forwarded_for as bool!")
+            })
+            .unwrap_or(false);
+
+        let trusted_proxies = table
+            .get("trusted_proxies")
+            .map(|proxies| {
+                proxies
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse trusted_proxies as int!")
+                    as usize
+            })
+            .unwrap_or(1)
+            .max(1);
+
+        // A class without a configured rate isn't limited at all
+        let limit = |class: &str| {
+            let per_second = table.get(&format!("{}_per_second", class))?.as_integer().expect(
+                "\x1b[31mErr:\x1b[0m Could not parse ratelimit per_second as int!",
+            ) as f64;
+            if per_second <= 0.0 {
+                panic!(
+                    "\x1b[31mErr:\x1b[0m {}_per_second has to be at least 1!",
+                    class
+                );
+            }
+            let burst = table
+                .get(&format!("{}_burst", class))
+                .map(|burst| {
+                    burst
+                        .as_integer()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse ratelimit burst as int!")
+                        as f64
+                })
+                .unwrap_or(per_second);
+
+            Some(Limit { per_second, burst })
+        };
+
+        Self {
+            enabled,
+            key,
+            trust_forwarded_for,
+            trusted_proxies,
+            read: limit("read"),
+            view: limit("view"),
+            submit: limit("submit"),
+        }
+    }
+}
+
+/// What we use to tell client sessions apart
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionKey {
+    ApiKey,
+    Cookie(String),
+    Header(String),
+}
+
+/// Keeps the ledger version a session reads at from going backwards
+#[derive(Debug, Clone)]
+pub struct SessionSettings {
+    pub enabled: bool,
+    pub key: SessionKey,
+    // Forget sessions we haven't heard from in this long, in seconds
+    pub idle_timeout: u64,
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            key: SessionKey::Header("X-Trident-Session".to_string()),
+            idle_timeout: 600,
+        }
+    }
+}
+
+impl SessionSettings {
+    fn from_table(table: &Table) -> Self {
+        let enabled = table
+            .get("enabled")
+            .map(|enabled| {
+                enabled
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse sessions enabled as bool!")
+            })
+            .unwrap_or(true);
+
+        let name = |field: &str, default: &str| {
+            table
+                .get(field)
+                .map(|name| {
+                    name.as_str()
+                        .unwrap_or_else(|| {
+                            panic!("\x1b[31mErr:\x1b[0m Could not parse sessions {} as str!", field)
+                        })
+                        .to_string()
+                })
+                .unwrap_or(default.to_string())
+        };
+
+        let key = match table.get("key").and_then(|key| key.as_str()) {
+            None | Some("header") => SessionKey::Header(name("header", "X-Trident-Session")),
+            Some("cookie") => SessionKey::Cookie(name("cookie", "trident_session")),
+            Some("api_key") => SessionKey::ApiKey,
+            Some(key) => panic!("\x1b[31mErr:\x1b[0m Unknown sessions key: {}", key),
+        };
+
+        let integer = |field: &str, default: u64| {
+            table
+                .get(field)
+                .map(|value| {
+                    value.as_integer().unwrap_or_else(|| {
+                        panic!("\x1b[31mErr:\x1b[0m Could not parse sessions {} as int!", field)
+                    }) as u64
+                })
+                .unwrap_or(default)
+        };
+
+        Self {
+            enabled,
+            key,
+            idle_timeout: integer("idle_timeout", 600),
+        }
+    }
+}
+
+/// What to evict first once the cache goes over budget
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Eviction {
+    // Least recently used
+    #[default]
+    Lru,
+    // Closest to expiring
+    Ttl,
+}
+
+/// Where cached responses are stored
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum CacheBackendKind {
+    // The separate sled DB at `<db_path>-responses`, kept apart from API keys and stats
+    #[default]
+    Sled,
+    // Shared with other instances through Redis at this URL
+    Redis(String),
+}
+
+/// Request whose response gets fetched into the cache ahead of time
+#[derive(Debug, Clone)]
+pub struct WarmRequest {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+}
+
+impl WarmRequest {
+    /// Parses `[METHOD] <path> [body]`, like `POST /v1/view {"function": ...}`.
+    /// The method defaults to GET.
+    fn parse(template: &str) -> Self {
+        let template = template.trim();
+        let (method, rest) = match template.split_once(char::is_whitespace) {
+            _ if template.starts_with('/') => ("GET", template),
+            Some((method, rest)) => (method, rest.trim_start()),
+            None => panic!("\x1b[31mErr:\x1b[0m Invalid cache warm request: {}", template),
+        };
+        let (path, body) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+
+        if !path.starts_with('/') || !matches!(method, "GET" | "POST") {
+            panic!("\x1b[31mErr:\x1b[0m Invalid cache warm request: {}", template);
+        }
+
+        Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: body.trim().to_string(),
+        }
+    }
+}
+
+/// Limits for the response cache
+#[derive(Debug, Clone)]
+pub struct CacheSettings {
+    pub backend: CacheBackendKind,
+    // Put in front of every Redis key
+    pub redis_prefix: String,
+    // How long to wait on Redis before treating it as a miss, in ms
+    pub redis_timeout: u64,
+    // Budgets for the responses kept in sled, unbounded if not set
+    pub max_bytes: Option<u64>,
+    pub max_entries: Option<u64>,
+    pub eviction: Eviction,
+    // Responses kept in memory in front of sled
+    pub hot_entries: usize,
+    // Time between eviction passes in ms
+    pub eviction_interval: u64,
+    // Evict cached account data as new transactions write to it
+    pub invalidate: bool,
+    // How often to look for new ledger versions, in ms
+    pub invalidate_interval: u64,
+    // Transactions fetched per request
+    pub invalidate_batch: u64,
+    // Versions we can fall behind before purging all account data instead
+    pub max_backlog: u64,
+    // Requests to prefetch on boot, from `warm` and `warm_file`
+    pub warm: Vec<WarmRequest>,
+    // Prefetch them again every this many ledger versions
+    pub warm_every: Option<u64>,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            backend: CacheBackendKind::Sled,
+            redis_prefix: "trident:".to_string(),
+            redis_timeout: 200,
+            max_bytes: None,
+            max_entries: None,
+            eviction: Eviction::Lru,
+            hot_entries: 1024,
+            eviction_interval: 10000,
+            invalidate: false,
+            invalidate_interval: 1000,
+            invalidate_batch: 100,
+            max_backlog: 10000,
+            warm: Vec::new(),
+            warm_every: None,
+        }
+    }
+}
+
+impl CacheSettings {
+    fn from_table(table: &Table) -> Self {
+        let integer = |field: &str| {
+            table.get(field).map(|value| {
+                value.as_integer().unwrap_or_else(|| {
+                    panic!("\x1b[31mErr:\x1b[0m Could not parse cache {} as int!", field)
+                }) as u64
+            })
+        };
+
+        let eviction = match table.get("eviction").map(|eviction| {
+            eviction
+                .as_str()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse cache eviction as str!")
+        }) {
+            None | Some("lru") => Eviction::Lru,
+            Some("ttl") => Eviction::Ttl,
+            Some(eviction) => panic!("\x1b[31mErr:\x1b[0m Unknown cache eviction: {}", eviction),
+        };
+
+        let string = |field: &str| {
+            table.get(field).map(|value| {
+                value
+                    .as_str()
+                    .unwrap_or_else(|| {
+                        panic!("\x1b[31mErr:\x1b[0m Could not parse cache {} as str!", field)
+                    })
+                    .to_string()
+            })
+        };
+
+        let backend = match string("backend").as_deref() {
+            None | Some("sled") => CacheBackendKind::Sled,
+            Some("redis") => CacheBackendKind::Redis(string("redis_url").expect(
+                "\x1b[31mErr:\x1b[0m The redis cache backend needs a redis_url!",
+            )),
+            Some(backend) => panic!("\x1b[31mErr:\x1b[0m Unknown cache backend: {}", backend),
+        };
+
+        let mut warm: Vec<WarmRequest> = table
+            .get("warm")
+            .map(|warm| {
+                warm.as_array()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse cache warm as array!")
+                    .iter()
+                    .map(|template| {
+                        WarmRequest::parse(template.as_str().expect(
+                            "\x1b[31mErr:\x1b[0m Could not parse cache warm request as str!",
+                        ))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // One request per line, lines starting with # are skipped
+        if let Some(path) = string("warm_file") {
+            let file = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+                panic!("\x1b[31mErr:\x1b[0m Could not read cache warm_file {}: {}", path, e)
+            });
+            warm.extend(
+                file.lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(WarmRequest::parse),
+            );
+        }
+
+        Self {
+            backend,
+            redis_prefix: string("redis_prefix").unwrap_or_else(|| "trident:".to_string()),
+            redis_timeout: integer("redis_timeout").unwrap_or(200),
+            max_bytes: integer("max_bytes"),
+            max_entries: integer("max_entries"),
+            eviction,
+            hot_entries: integer("hot_entries").unwrap_or(1024) as usize,
+            eviction_interval: integer("eviction_interval").unwrap_or(10000),
+            invalidate: table
+                .get("invalidate")
+                .map(|invalidate| {
+                    invalidate
+                        .as_bool()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse cache invalidate as bool!")
+                })
+                .unwrap_or(false),
+            invalidate_interval: integer("invalidate_interval").unwrap_or(1000),
+            invalidate_batch: integer("invalidate_batch").unwrap_or(100),
+            max_backlog: integer("max_backlog").unwrap_or(10000),
+            warm,
+            warm_every: integer("warm_every").filter(|every| *every > 0),
+        }
+    }
+}
+
+/// Candidate node that gets a copy of some live reads, without ever answering clients
+#[derive(Debug, Clone)]
+pub struct ShadowSettings {
+    pub enabled: bool,
+    pub url: String,
+    // Percentage of reads that get mirrored
+    pub sample: f64,
+    // How long to wait for the candidate to answer, in ms
+    pub ttl: u64,
+    // Network whose traffic gets mirrored, the default one if not set
+    pub network: Option<String>,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: String::new(),
+            sample: 1.0,
+            ttl: 5000,
+            network: None,
+        }
+    }
+}
+
+impl ShadowSettings {
+    fn from_table(table: &Table) -> Self {
+        let enabled = table
+            .get("enabled")
+            .map(|enabled| {
+                enabled
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse shadow enabled as bool!")
+            })
+            .unwrap_or(true);
+
+        let url = table
+            .get("url")
+            .expect("\x1b[31mErr:\x1b[0m Missing shadow url!")
+            .as_str()
+            .expect("\x1b[31mErr:\x1b[0m Could not parse shadow url as str!")
+            .to_string();
+
+        let sample = table
+            .get("sample")
+            .map(|sample| {
+                as_number(sample)
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse shadow sample as a number!")
+            })
+            .unwrap_or(1.0);
+
+        let ttl = table
+            .get("ttl")
+            .map(|ttl| {
+                ttl.as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse shadow ttl as int!")
+                    as u64
+            })
+            .unwrap_or(5000);
+
+        let network = table.get("network").map(|network| {
+            network
+                .as_str()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse shadow network as str!")
+                .to_string()
+        });
+
+        Self {
+            enabled,
+            url,
+            sample,
+            ttl,
+            network,
+        }
+    }
+}
+
+/// What gets logged, and how
+#[derive(Debug, Clone)]
+pub struct LogSettings {
+    pub level: Level,
+    // Levels for modules like `utils::aptos`, which cover their submodules too
+    pub filters: Vec<(String, Level)>,
+    pub format: LogFormat,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl LogSettings {
+    pub const DEFAULT: Self = Self {
+        level: if cfg!(feature = "debug-verbose") {
+            Level::Debug
+        } else {
+            Level::Info
+        },
+        filters: Vec::new(),
+        format: LogFormat::Text,
+    };
+
+    fn from_table(table: &Table) -> Self {
+        let parse_level = |level: &Value| {
+            let level = level
+                .as_str()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse log level as str!");
+            Level::from_name(level)
+                .unwrap_or_else(|| panic!("\x1b[31mErr:\x1b[0m Invalid log level: {}", level))
+        };
+
+        let level = table
+            .get("level")
+            .map(parse_level)
+            .unwrap_or(LogSettings::DEFAULT.level);
+
+        let filters = table
+            .get("filters")
+            .map(|filters| {
+                filters
+                    .as_table()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse log filters as table!")
+                    .iter()
+                    .map(|(module, level)| {
+                        (short_module(module).to_string(), parse_level(level))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let format = match table.get("format").map(|format| {
+            format
+                .as_str()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse log format as str!")
+        }) {
+            None | Some("text") => LogFormat::Text,
+            Some("json") => LogFormat::Json,
+            Some(format) => panic!("\x1b[31mErr:\x1b[0m Invalid log format: {}", format),
+        };
+
+        Self {
+            level,
+            filters,
+            format,
+        }
+    }
+
+    /// Level that applies to `module`. The longest matching filter wins.
+    pub fn level_for(&self, module: &str) -> Level {
+        let module = short_module(module);
+        self.filters
+            .iter()
+            .filter(|(filter, _)| {
+                module == filter
+                    || (module.starts_with(filter.as_str())
+                        && module[filter.len()..].starts_with("::"))
+            })
+            .max_by_key(|(filter, _)| filter.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+
+    /// Set the level of `module`, or the default one if that's `None`
+    pub fn set_level(&mut self, module: Option<&str>, level: Level) {
+        match module {
+            Some(module) => {
+                let module = short_module(module);
+                self.filters.retain(|(filter, _)| filter != module);
+                self.filters.push((module.to_string(), level));
+            }
+            None => self.level = level,
+        }
+    }
+}
+
+/// How to choose between the RPCs a request can go to
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Strategy {
+    // Whatever selection algo trident was built with
+    #[default]
+    Default,
+    // Always the lowest latency RPC
+    Fastest,
+    // Uniformly at random
+    Random,
+}
+
+/// A `[[route]]` rule. Requests matching every set condition get the overrides.
+#[derive(Debug, Clone, Default)]
+pub struct RouteRule {
+    // Conditions
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub headers: Vec<(String, String)>,
+    // Overrides
+    pub group: Option<String>,
+    pub ttl: Option<u128>,
+    pub max_retries: Option<u32>,
+    pub cache: Option<bool>,
+    pub cache_ttl: Option<u64>,
+    pub strategy: Option<Strategy>,
+    // Only answer if this many RPCs agree on the response
+    pub quorum: Option<usize>,
+    pub stale_while_revalidate: Option<u64>,
+    pub stale_if_error: Option<u64>,
+    pub negative_ttl: Option<u64>,
+}
+
+impl RouteRule {
+    fn from_table(table: &Table) -> Self {
+        let string = |name: &str| {
+            table.get(name).map(|value| {
+                value
+                    .as_str()
+                    .unwrap_or_else(|| {
+                        panic!("\x1b[31mErr:\x1b[0m Could not parse route {} as str!", name)
+                    })
+                    .to_string()
+            })
+        };
+        let integer = |name: &str| {
+            table.get(name).map(|value| {
+                value.as_integer().unwrap_or_else(|| {
+                    panic!("\x1b[31mErr:\x1b[0m Could not parse route {} as int!", name)
+                })
+            })
+        };
+
+        let headers = table
+            .get("headers")
+            .map(|headers| {
+                headers
+                    .as_table()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse route headers as table!")
+                    .iter()
+                    .map(|(name, value)| {
+                        let value = value
+                            .as_str()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse route header as str!");
+                        (name.clone(), value.to_string())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let cache = table.get("cache").map(|cache| {
+            cache
+                .as_bool()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse route cache as bool!")
+        });
+
+        let strategy = string("strategy").map(|strategy| match strategy.as_str() {
+            "default" => Strategy::Default,
+            "fastest" => Strategy::Fastest,
+            "random" => Strategy::Random,
+            strategy => panic!("\x1b[31mErr:\x1b[0m Unknown route strategy: {}", strategy),
+        });
+
+        let quorum = integer("quorum").map(|quorum| {
+            if quorum < 1 {
+                panic!("\x1b[31mErr:\x1b[0m Route quorum has to be at least 1!");
+            }
+            quorum as usize
+        });
+
+        Self {
+            method: string("method").map(|method| method.to_uppercase()),
+            path: string("path"),
+            headers,
+            group: string("group"),
+            ttl: integer("ttl").map(|ttl| ttl as u128),
+            max_retries: integer("max_retries").map(|retries| retries as u32),
+            cache,
+            cache_ttl: integer("cache_ttl").map(|ttl| ttl as u64),
+            strategy,
+            quorum,
+            stale_while_revalidate: integer("stale_while_revalidate").map(|stale| stale as u64),
+            stale_if_error: integer("stale_if_error").map(|stale| stale as u64),
+            negative_ttl: integer("negative_ttl").map(|ttl| ttl as u64),
+        }
+    }
+}
+
+/// A named Aptos network with its own RPCs, next to the default one.
+#[derive(Debug, Clone)]
+pub struct NetworkSettings {
+    pub name: String,
+    // Chain ID every RPC in the network has to report
+    pub chain_id: Option<u32>,
+    // Requests starting with this prefix go to the network, which gets stripped
+    pub path_prefix: String,
+    // Requests with one of these `Host` headers go to the network
+    pub hosts: Vec<String>,
+    // Extra listener that only serves this network
+    pub address: Option<SocketAddr>,
+    pub rpc_list: Vec<Rpc>,
+    pub poverty_list: Vec<Rpc>,
+}
+
+impl NetworkSettings {
+    fn from_table(name: &str, table: &Table) -> Self {
+        let chain_id = table.get("chain_id").map(|chain_id| {
+            chain_id
+                .as_integer()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse network chain_id as int!")
+                as u32
+        });
+
+        let path_prefix = table
+            .get("path_prefix")
+            .map(|prefix| {
+                prefix
+                    .as_str()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse network path_prefix as str!")
+                    .trim_end_matches('/')
+                    .to_string()
+            })
+            .unwrap_or(format!("/{}", name));
+
+        let hosts = table
+            .get("hosts")
+            .map(|hosts| {
+                hosts
+                    .as_array()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse network hosts as array!")
+                    .iter()
+                    .map(|host| {
+                        host.as_str()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse network host as str!")
+                            .to_string()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let address = table.get("address").map(|address| {
+            address
+                .as_str()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse network address as str!")
+                .replace("localhost", "127.0.0.1")
+                .parse::<SocketAddr>()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse network address to SocketAddr!")
+        });
+
+        Self {
+            name: name.to_string(),
+            chain_id,
+            path_prefix,
+            hosts,
+            address,
+            rpc_list: Vec::new(),
+            poverty_list: Vec::new(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Settings {
     pub rpc_list: Vec<Rpc>,
     pub poverty_list: Vec<Rpc>,
+    // Measure RPC latencies before serving, done in main once the DB is open
+    pub sort_on_startup: bool,
+    pub ma_length: f64,
+    // How often upstream stats get saved in ms, 0 to never save them
+    pub stats_interval: u64,
+    // Saved stats older than this many ms are ignored on boot
+    pub stats_max_age: u64,
+    // How long open connections get to finish on shutdown, in ms
+    pub drain_timeout: u64,
+    // Chain ID every RPC in the default network has to report
+    pub chain_id: Option<u32>,
+    pub networks: Vec<NetworkSettings>,
     pub address: SocketAddr,
     pub health_check: bool,
     pub ttl: u128,
     pub max_retries: u32,
     pub health_check_ttl: u64,
+    pub hedge: bool,
+    pub hedge_percentile: f64,
+    pub hedge_budget: f64,
+    pub deadline: Option<u64>,
+    pub negative_ttl: Option<u64>,
+    pub ledger_wait: u64,
+    pub max_block_lag: Option<u64>,
+    pub retry_budget: f64,
+    pub max_request_size: usize,
+    pub stream_responses: bool,
+    pub coalesce: bool,
+    pub max_response_size: usize,
+    pub ratelimit: RateLimitSettings,
+    pub api_keys: ApiKeySettings,
+    pub routes: Vec<RouteRule>,
+    pub sessions: SessionSettings,
+    pub shadow: ShadowSettings,
+    pub cache: CacheSettings,
+    pub admin: AdminSettings,
+    pub sled_config: sled::Config,
 }
 
 impl Default for Settings {
@@ -60,11 +972,38 @@ impl Default for Settings {
         Self {
             rpc_list: Vec::new(),
             poverty_list: Vec::new(),
+            sort_on_startup: false,
+            ma_length: 100.0,
+            stats_interval: 30000,
+            stats_max_age: 300000,
+            drain_timeout: 30000,
+            chain_id: None,
+            networks: Vec::new(),
             address: "127.0.0.1:3000".parse::<SocketAddr>().unwrap(),
             health_check: false,
             ttl: 1000,
             max_retries: 32,
             health_check_ttl: 1000,
+            hedge: false,
+            hedge_percentile: 95.0,
+            hedge_budget: 10.0,
+            deadline: None,
+            negative_ttl: None,
+            ledger_wait: 2000,
+            max_block_lag: None,
+            retry_budget: 20.0,
+            max_request_size: 8 * 1024 * 1024,
+            stream_responses: false,
+            coalesce: true,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            ratelimit: RateLimitSettings::default(),
+            api_keys: ApiKeySettings::default(),
+            routes: Vec::new(),
+            sessions: SessionSettings::default(),
+            shadow: ShadowSettings::default(),
+            cache: CacheSettings::default(),
+            admin: AdminSettings::default(),
+            sled_config: sled::Config::default().path("./trident-cache"),
         }
     }
 }
@@ -81,8 +1020,9 @@ impl Settings {
         };
 
         if let Some(file) = file {
+            let settings = Settings::create_from_file(file).await;
             log_info!("Using config file at {}", path);
-            return Settings::create_from_file(file).await;
+            return settings;
         }
 
         log_info!("Using command line arguments for settings...");
@@ -92,6 +1032,19 @@ impl Settings {
     async fn create_from_file(conf_file: String) -> Settings {
         let parsed_toml = conf_file.parse::<Value>().expect("Error parsing TOML");
 
+        // Parse the optional `log` table first, so the rest of the config gets logged with it
+        let log = parsed_toml
+            .get("log")
+            .map(|table| {
+                LogSettings::from_table(
+                    table
+                        .as_table()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse log table!"),
+                )
+            })
+            .unwrap_or_default();
+        set_log_settings(log);
+
         let table_names: Vec<&String> = parsed_toml.as_table().unwrap().keys().collect::<Vec<_>>();
 
         // Parse the `trident` table
@@ -161,9 +1114,271 @@ impl Settings {
             u64::MAX
         };
 
+        // Hedging is optional, so fall back to defaults if it's not configured
+        let hedge = trident_table
+            .get("hedge")
+            .map(|hedge| {
+                hedge
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse hedge as bool!")
+            })
+            .unwrap_or(false);
+
+        let hedge_percentile = trident_table
+            .get("hedge_percentile")
+            .map(|percentile| {
+                as_number(percentile)
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse hedge_percentile as a number!")
+            })
+            .unwrap_or(95.0);
+
+        let hedge_budget = trident_table
+            .get("hedge_budget")
+            .map(|budget| {
+                as_number(budget)
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse hedge_budget as a number!")
+            })
+            .unwrap_or(10.0);
+
+        // Total time a request can take, retries included. Unbounded if not set.
+        let deadline = trident_table.get("deadline").map(|deadline| {
+            deadline
+                .as_integer()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse deadline as int!") as u64
+        });
+
+        // How long to cache "not found" errors for. They aren't if not set.
+        let negative_ttl = trident_table.get("negative_ttl").map(|ttl| {
+            ttl.as_integer()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse negative_ttl as int!") as u64
+        });
+
+        let ledger_wait = trident_table
+            .get("ledger_wait")
+            .map(|wait| {
+                wait.as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse ledger_wait as int!")
+                    as u64
+            })
+            .unwrap_or(2000);
+
+        // Upstream stats get saved to sled this often, so restarts don't start from scratch
+        let stats_interval = trident_table
+            .get("stats_interval")
+            .map(|interval| {
+                interval
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse stats_interval as int!")
+                    as u64
+            })
+            .unwrap_or(30000);
+        let stats_max_age = trident_table
+            .get("stats_max_age")
+            .map(|age| {
+                age.as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse stats_max_age as int!")
+                    as u64
+            })
+            .unwrap_or(300000);
+
+        let drain_timeout = trident_table
+            .get("drain_timeout")
+            .map(|timeout| {
+                timeout
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse drain_timeout as int!")
+                    as u64
+            })
+            .unwrap_or(30000);
+
+        // How many blocks an RPC can fall behind the rest before we stop using it
+        let max_block_lag = trident_table.get("max_block_lag").map(|lag| {
+            lag.as_integer()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse max_block_lag as int!")
+                as u64
+        });
+
+        let retry_budget = trident_table
+            .get("retry_budget")
+            .map(|budget| {
+                as_number(budget)
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse retry_budget as a number!")
+            })
+            .unwrap_or(20.0);
+
+        let max_request_size = trident_table
+            .get("max_request_size")
+            .map(|size| {
+                size.as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse max_request_size as int!")
+                    as usize
+            })
+            .unwrap_or(8 * 1024 * 1024);
+
+        let stream_responses = trident_table
+            .get("stream_responses")
+            .map(|stream| {
+                stream
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse stream_responses as bool!")
+            })
+            .unwrap_or(false);
+
+        let coalesce = trident_table
+            .get("coalesce")
+            .map(|coalesce| {
+                coalesce
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse coalesce as bool!")
+            })
+            .unwrap_or(true);
+
+        let max_response_size = trident_table
+            .get("max_response_size")
+            .map(|size| {
+                size.as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse max_response_size as int!")
+                    as usize
+            })
+            .unwrap_or(DEFAULT_MAX_RESPONSE_SIZE);
+
+        let chain_id = trident_table.get("chain_id").map(|chain_id| {
+            chain_id
+                .as_integer()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse chain_id as int!")
+                as u32
+        });
+
+        // Parse the optional `ratelimit` table
+        let ratelimit = parsed_toml
+            .get("ratelimit")
+            .map(|table| {
+                RateLimitSettings::from_table(
+                    table
+                        .as_table()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse ratelimit table!"),
+                )
+            })
+            .unwrap_or_default();
+
+        // Parse the optional `api_keys` table
+        let api_keys = parsed_toml
+            .get("api_keys")
+            .map(|table| {
+                ApiKeySettings::from_table(
+                    table
+                        .as_table()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse api_keys table!"),
+                )
+            })
+            .unwrap_or_default();
+
+        // Parse the `[[route]]` rules, in order
+        let routes = parsed_toml
+            .get("route")
+            .map(|routes| {
+                routes
+                    .as_array()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse route as array of tables!")
+                    .iter()
+                    .map(|route| {
+                        RouteRule::from_table(
+                            route
+                                .as_table()
+                                .expect("\x1b[31mErr:\x1b[0m Could not parse route table!"),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // Parse the optional `sessions` table
+        let sessions = parsed_toml
+            .get("sessions")
+            .map(|table| {
+                SessionSettings::from_table(
+                    table
+                        .as_table()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse sessions table!"),
+                )
+            })
+            .unwrap_or_default();
+
+        // Parse the optional `shadow` table
+        let shadow = parsed_toml
+            .get("shadow")
+            .map(|table| {
+                ShadowSettings::from_table(
+                    table
+                        .as_table()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse shadow table!"),
+                )
+            })
+            .unwrap_or_default();
+
+        // Parse the optional `cache` table
+        let cache = parsed_toml
+            .get("cache")
+            .map(|table| {
+                CacheSettings::from_table(
+                    table
+                        .as_table()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse cache table!"),
+                )
+            })
+            .unwrap_or_default();
+
+        // Parse the optional `admin` table
+        let admin = parsed_toml
+            .get("admin")
+            .map(|table| {
+                AdminSettings::from_table(
+                    table
+                        .as_table()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse admin table!"),
+                )
+            })
+            .unwrap_or_default();
+
+        // Parse the optional `sled` table
+        let sled_config = match parsed_toml.get("sled") {
+            Some(table) => sled_config_from_table(
+                table
+                    .as_table()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse sled table!"),
+            ),
+            None => sled::Config::default().path("./trident-cache"),
+        };
+
+        // Parse the named networks. RPCs pick one with their `network` field.
+        let mut networks: Vec<NetworkSettings> = parsed_toml
+            .get("networks")
+            .map(|networks| {
+                networks
+                    .as_table()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse networks table!")
+                    .iter()
+                    .map(|(name, network)| {
+                        NetworkSettings::from_table(
+                            name,
+                            network
+                                .as_table()
+                                .expect("\x1b[31mErr:\x1b[0m Could not parse network table!"),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if let Some(network) = &shadow.network {
+            if !networks.iter().any(|settings| &settings.name == network) {
+                panic!("\x1b[31mErr:\x1b[0m Unknown shadow network: {}", network);
+            }
+        }
+
         let mut rpc_list: Vec<Rpc> = Vec::new();
         for table_name in table_names {
-            if table_name != "trident" && table_name != "sled" && table_name != "admin" {
+            if !RESERVED_TABLES.contains(&table_name.as_str()) {
                 let rpc_table = parsed_toml.get(table_name).unwrap().as_table().unwrap();
 
                 let max_consecutive = rpc_table
@@ -208,30 +1423,90 @@ impl Settings {
                     }
                 };
 
-                let rpc = Rpc::new(url, ws_url, max_consecutive, delta.into(), ma_length);
-                rpc_list.push(rpc);
-            }
-        }
+                let group = rpc_table.get("group").map(|group| {
+                    group
+                        .as_str()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse group as str!")
+                        .to_string()
+                });
 
-        let mut poverty_list = Vec::new();
-        if sort_on_startup {
-            (rpc_list, poverty_list) =
-                match sort_by_latency(rpc_list, poverty_list, ma_length).await {
-                    Ok(rax) => rax,
-                    Err(e) => {
-                        panic!("{:?}", e);
-                    }
+                // `priority` takes a number directly, `tier` is a named shorthand for one
+                let priority = match (rpc_table.get("priority"), rpc_table.get("tier")) {
+                    (Some(priority), _) => priority
+                        .as_integer()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse priority as int!")
+                        as u32,
+                    (None, Some(tier)) => match tier
+                        .as_str()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse tier as str!")
+                    {
+                        "primary" => 0,
+                        "backup" => 1,
+                        "last_resort" => 2,
+                        tier => panic!("\x1b[31mErr:\x1b[0m Invalid tier: {}", tier),
+                    },
+                    (None, None) => 0,
                 };
+
+                let mut rpc = Rpc::new(url, ws_url, max_consecutive, delta.into(), ma_length);
+                rpc.group = group;
+                rpc.priority = priority;
+                rpc.max_response_size = max_response_size;
+
+                match rpc_table.get("network") {
+                    Some(network) => {
+                        let network = network
+                            .as_str()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse network as str!");
+                        networks
+                            .iter_mut()
+                            .find(|settings| settings.name == network)
+                            .unwrap_or_else(|| {
+                                panic!("\x1b[31mErr:\x1b[0m Unknown network: {}", network)
+                            })
+                            .rpc_list
+                            .push(rpc);
+                    }
+                    None => rpc_list.push(rpc),
+                }
+            }
         }
 
         Settings {
             rpc_list,
-            poverty_list,
+            poverty_list: Vec::new(),
+            sort_on_startup,
+            ma_length,
+            stats_interval,
+            stats_max_age,
+            drain_timeout,
+            chain_id,
+            networks,
             address,
             health_check,
             ttl,
             max_retries,
             health_check_ttl,
+            hedge,
+            hedge_percentile,
+            hedge_budget,
+            deadline,
+            negative_ttl,
+            ledger_wait,
+            max_block_lag,
+            retry_budget,
+            max_request_size,
+            stream_responses,
+            coalesce,
+            max_response_size,
+            ratelimit,
+            api_keys,
+            routes,
+            sessions,
+            shadow,
+            cache,
+            admin,
+            sled_config,
         }
     }
 
@@ -307,11 +1582,203 @@ impl Settings {
         Settings {
             rpc_list,
             poverty_list: Vec::new(),
+            ma_length,
             address,
             health_check,
             ttl,
             max_retries,
             health_check_ttl,
+            ..Default::default()
         }
     }
 }
+
+/// TOML keeps integers and floats apart, but `95` and `99.9` are both fine percentiles.
+fn as_number(value: &Value) -> Option<f64> {
+    value
+        .as_float()
+        .or_else(|| value.as_integer().map(|int| int as f64))
+}
+
+fn sled_config_from_table(table: &Table) -> sled::Config {
+    let db_path = table
+        .get("db_path")
+        .map(|path| {
+            path.as_str()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse db_path as str!")
+        })
+        .unwrap_or("./trident-cache");
+
+    let mode = match table.get("mode").and_then(|mode| mode.as_str()) {
+        None | Some("HighThroughput") => sled::Mode::HighThroughput,
+        Some("LowSpace") => sled::Mode::LowSpace,
+        Some(mode) => panic!("\x1b[31mErr:\x1b[0m Invalid sled mode: {}", mode),
+    };
+
+    let cache_capacity = table
+        .get("cache_capacity")
+        .map(|capacity| {
+            capacity
+                .as_integer()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse cache_capacity as int!")
+                as u64
+        })
+        .unwrap_or(1024 * 1024 * 1024);
+
+    let compression = table
+        .get("compression")
+        .map(|compression| {
+            compression
+                .as_bool()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse compression as bool!")
+        })
+        .unwrap_or(false);
+
+    let print_profile = table
+        .get("print_profile")
+        .map(|profile| {
+            profile
+                .as_bool()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse print_profile as bool!")
+        })
+        .unwrap_or(false);
+
+    let flush_every_ms = table
+        .get("flush_every_ms")
+        .map(|flush| {
+            flush
+                .as_integer()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse flush_every_ms as int!")
+                as u64
+        })
+        .unwrap_or(240);
+
+    sled::Config::default()
+        .path(db_path)
+        .mode(mode)
+        .cache_capacity(cache_capacity)
+        .use_compression(compression)
+        .print_profile_on_drop(print_profile)
+        .flush_every_ms(Some(flush_every_ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_levels() {
+        let mut settings = LogSettings::DEFAULT;
+        settings.level = Level::Warn;
+        settings.set_level(Some("trident::core"), Level::Info);
+        settings.set_level(Some("core::cache"), Level::Debug);
+        settings.set_level(Some("core"), Level::Error);
+
+        // The longest matching filter wins, and setting one again replaces it
+        assert_eq!(settings.level_for("trident::core::cache::backend"), Level::Debug);
+        assert_eq!(settings.level_for("trident::core::algo"), Level::Error);
+        assert_eq!(settings.filters.len(), 2);
+        // Prefixes only match whole modules
+        assert_eq!(settings.level_for("trident::core_extra"), Level::Warn);
+        assert_eq!(settings.level_for("trident"), Level::Warn);
+
+        settings.set_level(None, Level::Debug);
+        assert_eq!(settings.level_for("trident::admin"), Level::Debug);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(as_number(&Value::Integer(95)), Some(95.0));
+        assert_eq!(as_number(&Value::Float(99.9)), Some(99.9));
+        assert_eq!(as_number(&Value::String("95".to_string())), None);
+    }
+
+    #[tokio::test]
+    async fn budgets_take_floats() {
+        let config = include_str!("../../default.config.toml")
+            .replace("hedge_percentile = 95", "hedge_percentile = 99.5")
+            .replace("hedge_budget = 10", "hedge_budget = 2.5")
+            .replace("retry_budget = 20", "retry_budget = 2.5");
+        let settings = Settings::create_from_file(config).await;
+        assert_eq!(settings.hedge_percentile, 99.5);
+        assert_eq!(settings.hedge_budget, 2.5);
+        assert_eq!(settings.retry_budget, 2.5);
+
+        let settings =
+            Settings::create_from_file(include_str!("../../default.config.toml").to_string()).await;
+        assert_eq!(settings.hedge_percentile, 95.0);
+        assert_eq!(settings.hedge_budget, 10.0);
+        assert_eq!(settings.retry_budget, 20.0);
+    }
+
+    #[test]
+    fn ratelimit_rates() {
+        let table = "read_per_second = 5".parse::<Table>().unwrap();
+        let settings = RateLimitSettings::from_table(&table);
+        assert_eq!(settings.read.unwrap().per_second, 5.0);
+        assert_eq!(settings.read.unwrap().burst, 5.0);
+        assert!(settings.view.is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "read_per_second has to be at least 1")]
+    fn ratelimit_rejects_zero_rates() {
+        let table = "read_per_second = 0".parse::<Table>().unwrap();
+        RateLimitSettings::from_table(&table);
+    }
+
+    #[test]
+    fn admin_is_off_unless_configured() {
+        let admin = AdminSettings::default();
+        assert!(!admin.enabled);
+        assert_eq!(admin.address, "127.0.0.1:5715".parse::<SocketAddr>().unwrap());
+    }
+
+    #[test]
+    fn route_quorums() {
+        let table = "quorum = 3".parse::<Table>().unwrap();
+        assert_eq!(RouteRule::from_table(&table).quorum, Some(3));
+    }
+
+    #[test]
+    #[should_panic(expected = "Route quorum has to be at least 1")]
+    fn route_quorum_rejects_zero() {
+        let table = "quorum = 0".parse::<Table>().unwrap();
+        RouteRule::from_table(&table);
+    }
+
+    #[test]
+    fn shadow_sample_takes_floats() {
+        let table = "url = \"http://127.0.0.1:9110\"\nsample = 0.25".parse::<Table>().unwrap();
+        assert_eq!(ShadowSettings::from_table(&table).sample, 0.25);
+
+        let table = "url = \"http://127.0.0.1:9110\"\nsample = 1".parse::<Table>().unwrap();
+        assert_eq!(ShadowSettings::from_table(&table).sample, 1.0);
+    }
+
+    #[test]
+    fn warm_requests() {
+        let get = WarmRequest::parse("  /v1/accounts/0x1 ");
+        assert_eq!((get.method.as_str(), get.path.as_str(), get.body.as_str()), ("GET", "/v1/accounts/0x1", ""));
+
+        let post = WarmRequest::parse(r#"POST /v1/view {"function": "0x1::coin::supply"}"#);
+        assert_eq!(post.method, "POST");
+        assert_eq!(post.path, "/v1/view");
+        assert_eq!(post.body, r#"{"function": "0x1::coin::supply"}"#);
+
+        let explicit = WarmRequest::parse("GET   /v1/blocks/by_height/1");
+        assert_eq!(explicit.path, "/v1/blocks/by_height/1");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid cache warm request")]
+    fn warm_requests_need_a_path() {
+        WarmRequest::parse("GET v1/accounts");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid cache warm request")]
+    fn warm_requests_only_read() {
+        WarmRequest::parse("DELETE /v1/accounts/0x1");
+    }
+}
@@ -1,15 +1,75 @@
 use crate::{
-    core::{algo::pick, processing::update_rpc_latency},
-    log_info, no_rpc_available, timed_out,
-    utils::rpc::Rpc,
+    core::{
+        algo::pick_with,
+        body::{
+            full,
+            Deadline,
+            ResponseBody,
+        },
+        budget::Budget,
+        cache::{
+            cache_key,
+            Lookup,
+            ResponseCache,
+        },
+        coalesce::{
+            Flight,
+            InFlight,
+        },
+        errors::TridentError,
+        hedge::{
+            is_hedgeable,
+            send_hedged,
+        },
+        keys::ApiKeys,
+        networks::{
+            select_network,
+            Network,
+        },
+        processing::update_rpc_latency,
+        quorum::{
+            quorum_read,
+            QuorumStats,
+        },
+        ratelimit::RateLimiter,
+        routes::match_route,
+        sessions::SessionTracker,
+        shadow::Shadow,
+    },
+    config::types::Strategy,
+    log_wrn,
+    utils::{
+        aptos::{
+            endpoints::{
+                is_immutable,
+                is_not_found,
+                is_pending_transaction,
+                RequestClass,
+            },
+            ledger::{
+                ledger_version,
+                LedgerVersioned,
+                APTOS_HEADER_PREFIX,
+            },
+        },
+        rpc::Rpc,
+    },
     Settings,
 };
-use futures::executor::block_on;
-use http::request::Parts;
-use http_body_util::{BodyExt, Full};
+use http::{
+    header,
+    request::Parts,
+    HeaderMap,
+};
+use http_body_util::{
+    BodyExt,
+    LengthLimitError,
+    Limited,
+};
 use hyper::{body::Bytes, Request};
 use std::{
     convert::Infallible,
+    net::SocketAddr,
     sync::{Arc, RwLock},
     time::{Duration, Instant},
 };
@@ -18,21 +78,71 @@ use tokio::time::timeout;
 
 #[derive(Debug, Clone)]
 pub struct ConnectionParams {
-    pub rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
+    // Index 0 is the default network
+    pub networks: Arc<Vec<Network>>,
     pub channels: RequestChannels,
     pub config: Arc<RwLock<Settings>>,
+    pub hedge_budget: Arc<Budget>,
+    pub retry_budget: Arc<Budget>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub api_keys: Arc<ApiKeys>,
+    pub cache: Arc<ResponseCache>,
+    pub sessions: Arc<SessionTracker>,
+    pub quorum_stats: Arc<QuorumStats>,
+    pub shadow: Arc<Shadow>,
+    pub in_flight: Arc<InFlight>,
+    // Address of the client on the other end of this connection
+    pub remote_addr: Option<SocketAddr>,
+    // Network this connection is pinned to, if it came in on a network's own listener
+    pub network: Option<usize>,
 }
 
 impl ConnectionParams {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+        networks: &Arc<Vec<Network>>,
         channels: RequestChannels,
         config: &Arc<RwLock<Settings>>,
+        hedge_budget: &Arc<Budget>,
+        retry_budget: &Arc<Budget>,
+        rate_limiter: &Arc<RateLimiter>,
+        api_keys: &Arc<ApiKeys>,
+        cache: &Arc<ResponseCache>,
+        sessions: &Arc<SessionTracker>,
+        quorum_stats: &Arc<QuorumStats>,
+        shadow: &Arc<Shadow>,
+        in_flight: &Arc<InFlight>,
     ) -> Self {
         ConnectionParams {
-            rpc_list_rwlock: rpc_list_rwlock.clone(),
+            networks: networks.clone(),
             channels,
             config: config.clone(),
+            hedge_budget: hedge_budget.clone(),
+            retry_budget: retry_budget.clone(),
+            rate_limiter: rate_limiter.clone(),
+            api_keys: api_keys.clone(),
+            cache: cache.clone(),
+            sessions: sessions.clone(),
+            quorum_stats: quorum_stats.clone(),
+            shadow: shadow.clone(),
+            in_flight: in_flight.clone(),
+            remote_addr: None,
+            network: None,
+        }
+    }
+
+    pub fn with_remote_addr(&self, remote_addr: SocketAddr) -> Self {
+        ConnectionParams {
+            remote_addr: Some(remote_addr),
+            ..self.clone()
+        }
+    }
+
+    /// Serve only the network at `network` on this connection
+    pub fn with_network(&self, network: usize) -> Self {
+        ConnectionParams {
+            network: Some(network),
+            ..self.clone()
         }
     }
 }
@@ -40,8 +150,84 @@ impl ConnectionParams {
 struct RequestParams {
     ttl: u128,
     max_retries: u32,
+    // Latency percentile after which we hedge. `None` if we shouldn't hedge.
+    hedge_percentile: Option<f64>,
+    // Point in time after which we give up on the request, retries included.
+    deadline: Option<Instant>,
+    // Largest request body we're willing to read, in bytes.
+    max_request_size: usize,
+    // Forward the response body as it comes in instead of buffering it.
+    stream: bool,
+    // Largest response body we're willing to forward, in bytes.
+    max_response_size: usize,
+    // Index of the `[[route]]` rule the request matched, if any.
+    route: Option<usize>,
+    // Upstream group the request is pinned to by its route or API key, if any.
+    group: Option<String>,
+    // How to choose between the RPCs in the group.
+    strategy: Strategy,
+    // Look up and store the response in the cache.
+    cache: bool,
+    // How long cached responses stay valid in ms. They never expire if `None`.
+    cache_ttl: Option<u64>,
+    // Lowest ledger version the client may be answered at, if it has seen one already.
+    min_ledger_version: Option<u64>,
+    // Until when we wait for an RPC to catch up to `min_ledger_version`.
+    ledger_wait_until: Option<Instant>,
+    // Blocks an RPC can be behind the highest one we know of and still get traffic.
+    max_block_lag: Option<u64>,
+    // Number of RPCs that have to agree on the response, if set by the route.
+    quorum: Option<usize>,
+    // Share the upstream response with identical requests that are in flight.
+    coalesce: bool,
+    // How long past their TTL cached responses get served while being refreshed, in ms.
+    stale_while_revalidate: u64,
+    // How long past their TTL cached responses get served if no RPC answers, in ms.
+    stale_if_error: u64,
+    // How long "not found" errors get cached in ms, `None` if they don't.
+    negative_ttl: Option<u64>,
+}
+
+impl RequestParams {
+    /// Whether `rpc` may serve this request. RPCs that just failed to respond sit out a bit.
+    fn eligible(&self, rpc: &Rpc) -> bool {
+        if rpc.status.is_backing_off() {
+            return false;
+        }
+
+        match &self.group {
+            Some(group) => rpc.group.as_ref() == Some(group),
+            None => true,
+        }
+    }
+
+    /// Whether we know `rpc` is at or past the ledger version the client needs
+    fn caught_up(&self, rpc: &Rpc) -> bool {
+        match self.min_ledger_version {
+            Some(min) => rpc.ledger.version().is_some_and(|version| version >= min),
+            None => true,
+        }
+    }
+
+    /// How long past its TTL a cached response can still be of use
+    fn max_stale(&self) -> u64 {
+        self.stale_while_revalidate.max(self.stale_if_error)
+    }
+
+    /// Whether `rpc` is too far behind `head`, the highest block height in its list
+    fn lagging(&self, rpc: &Rpc, head: Option<u64>) -> bool {
+        rpc.ledger.is_lagging(head, self.max_block_lag)
+    }
 }
 
+/// Highest block height any RPC in `list` has told us about
+fn head_block_height(list: &[Rpc]) -> Option<u64> {
+    list.iter().filter_map(|rpc| rpc.ledger.block_height()).max()
+}
+
+// How long to wait before asking an RPC that's behind the client again
+const LEDGER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 #[derive(Debug)]
 pub struct RequestChannels {
     pub finalized_rx: Arc<watch::Receiver<u64>>,
@@ -66,10 +252,11 @@ impl Clone for RequestChannels {
 macro_rules! accept {
     (
         $io:expr,
-        $connection_params:expr
+        $connection_params:expr,
+        $stopping:expr
     ) => {
         // Bind the incoming connection to our service
-        if let Err(err) = http1::Builder::new()
+        let connection = http1::Builder::new()
             .serve_connection(
                 $io,
                 service_fn(|req| {
@@ -77,9 +264,18 @@ macro_rules! accept {
                     response
                 }),
             )
-            .with_upgrades()
-            .await
-        {
+            .with_upgrades();
+        tokio::pin!(connection);
+
+        let result = tokio::select! {
+            result = connection.as_mut() => result,
+            // Let the request in progress finish, then close the connection
+            _ = $stopping => {
+                connection.as_mut().graceful_shutdown();
+                connection.await
+            }
+        };
+        if let Err(err) = result {
             log_err!("Error serving connection: {:?}", err);
         }
     };
@@ -93,10 +289,11 @@ macro_rules! get_response {
         $finalized_rx:expr,
         $named_numbers:expr,
         $head_cache:expr,
-        $ttl:expr,
-        $max_retries:expr,
+        $params:expr,
+        $connection_params:expr,
         $parts:expr,
-        $bytes:expr
+        $bytes:expr,
+        $send:expr
     ) => {
         fetch_from_rpc!(
             $rpc_list_rwlock,
@@ -105,10 +302,11 @@ macro_rules! get_response {
             $finalized_rx,
             $named_numbers,
             $head_cache,
-            $ttl,
-            $max_retries,
+            $params,
+            $connection_params,
             $parts,
-            $bytes
+            $bytes,
+            $send
         )
     };
 }
@@ -121,149 +319,708 @@ macro_rules! fetch_from_rpc {
         $finalized_rx:expr,
         $named_numbers:expr,
         $head_cache:expr,
-        $ttl:expr,
-        $max_retries:expr,
+        $params:expr,
+        $connection_params:expr,
         $parts:expr,
-        $bytes:expr
+        $bytes:expr,
+        $send:expr
     ) => {{
 
         // Loop until we get a response
         let  rx;
-        let status;
         let mut retries = 0;
-        let mut rpc_name;
+        let mut last_error = TridentError::TimedOut;
+        let rpc_name;
         loop {
             // Get the next Rpc in line.
             let mut rpc;
             {
                 let mut rpc_list = $rpc_list_rwlock.write().unwrap();
-                (rpc, $rpc_position) = pick(&mut rpc_list);
+                let head = head_block_height(&rpc_list);
+                (rpc, $rpc_position) = pick_with(
+                    &mut rpc_list,
+                    |rpc| $params.eligible(rpc) && $params.caught_up(rpc) && !$params.lagging(rpc, head),
+                    $params.strategy,
+                );
+
+                // Better a lagging RPC than none at all
+                if $rpc_position.is_none() && $params.max_block_lag.is_some() {
+                    (rpc, $rpc_position) = pick_with(
+                        &mut rpc_list,
+                        |rpc| $params.eligible(rpc) && $params.caught_up(rpc),
+                        $params.strategy,
+                    );
+                }
+
+                // None we know of is caught up to the client, but one of them might
+                // have moved on without us noticing. The response will tell.
+                if $rpc_position.is_none() && $params.min_ledger_version.is_some() {
+                    (rpc, $rpc_position) =
+                        pick_with(&mut rpc_list, |rpc| $params.eligible(rpc), $params.strategy);
+                }
             }
-            rpc_name = rpc.name.clone();
             // log_info!("Forwarding to: {}", rpc_name);
             // Check if we have any RPCs in the list, if not return error
             if $rpc_position == None {
-                return (no_rpc_available!(), None);
+                return (Ok(TridentError::NoRpcAvailable.into()), None);
+            }
+
+            // A single attempt can't outlive the deadline of the whole request
+            let mut attempt_ttl = Duration::from_millis($params.ttl.try_into().unwrap());
+            if let Some(deadline) = $params.deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return (Ok(last_error.into()), $rpc_position);
+                }
+                attempt_ttl = attempt_ttl.min(remaining);
             }
 
             // Send the request. And return a timeout if it takes too long
             //
-            // Check if it contains any errors or if its `latest` and insert it if it isn't
-            match timeout(
-                Duration::from_millis($ttl.try_into().unwrap()),
-                rpc.send_request($parts,$bytes)
-            )
-            .await
-            {
+            // If hedging is on, a slow RPC might get raced against a second one,
+            // in which case the winner is the one we attribute the response to.
+            let attempt = async {
+                match $params.hedge_percentile {
+                    Some(percentile) => send_hedged(
+                        &$rpc_list_rwlock,
+                        rpc.clone(),
+                        $rpc_position.unwrap(),
+                        $parts,
+                        $bytes,
+                        percentile,
+                        &$connection_params.hedge_budget,
+                        |rpc: &Rpc| $params.eligible(rpc) && $params.caught_up(rpc),
+                        $send,
+                    )
+                    .await,
+                    None => (
+                        $send(rpc.clone(), $parts, $bytes).await,
+                        rpc.name.clone(),
+                        $rpc_position.unwrap(),
+                    ),
+                }
+            };
+
+            match timeout(attempt_ttl, attempt).await {
+                Ok((Ok(rxa), winner_name, winner_position)) => {
+                    // Behind what the client has already seen, give it a moment to catch up
+                    if let (Some(min), Some(version)) = ($params.min_ledger_version, rxa.ledger_version()) {
+                        if version < min {
+                            let wait_until = $params.ledger_wait_until.unwrap_or_else(Instant::now);
+                            if Instant::now() + LEDGER_POLL_INTERVAL >= wait_until {
+                                return (Ok(TridentError::LedgerBehind(min).into()), Some(winner_position));
+                            }
+                            tokio::time::sleep(LEDGER_POLL_INTERVAL).await;
+                            continue;
+                        }
+                    }
 
-                Ok(rxa) => {
-                    let res = rxa.unwrap();
-                    (rx,status) = res;
+                    // It answered, so whatever went wrong before is over
+                    let recovered = $rpc_list_rwlock
+                        .read()
+                        .unwrap()
+                        .get(winner_position)
+                        .is_some_and(|rpc| rpc.name == winner_name && rpc.status.is_erroring);
+                    if recovered {
+                        if let Some(rpc) = $rpc_list_rwlock.write().unwrap().get_mut(winner_position) {
+                            rpc.status.is_erroring = false;
+                        }
+                    }
+
+                    rx = rxa;
+                    rpc_name = winner_name;
+                    $rpc_position = Some(winner_position);
+                    $connection_params.retry_budget.deposit();
                     break;
                 },
+                // Other RPCs would most likely send the same oversized response
+                Ok((Err(e), _, winner_position)) if e.downcast_ref::<LengthLimitError>().is_some() => {
+                    return (Ok(TridentError::ResponseTooLarge($params.max_response_size).into()), Some(winner_position));
+                },
+                // Couldn't reach the RPC at all. Give it a moment before it gets
+                // another request, and try the next one.
+                Ok((Err(e), winner_name, winner_position)) => {
+                    log_wrn!(UPSTREAM = winner_name; "Error while calling RPC: {}", e);
+                    if let Some(rpc) = $rpc_list_rwlock
+                        .write()
+                        .unwrap()
+                        .get_mut(winner_position)
+                        .filter(|rpc| rpc.name == winner_name)
+                    {
+                        rpc.status.mark_erroring();
+                    }
+                    last_error = TridentError::Upstream;
+                    retries += 1;
+                },
                 Err(_) => {
-                    rpc.update_latency($ttl as f64);
+                    rpc.update_latency($params.ttl as f64);
+                    last_error = TridentError::TimedOut;
                     retries += 1;
                 },
             };
 
-            if retries == $max_retries {
-                return (timed_out!(), $rpc_position,);
+            if retries == $params.max_retries {
+                return (Ok(last_error.into()), $rpc_position,);
+            }
+
+            // Retries are capped globally, so an outage doesn't turn into a retry storm
+            if !$connection_params.retry_budget.try_withdraw() {
+                return (Ok(TridentError::RetryBudgetExhausted.into()), $rpc_position,);
             }
         }
 
 
-        (rx,status,rpc_name)
+        (rx,rpc_name)
     }};
 }
 
+/// Build the response we send back to the client. It's JSON unless the RPC
+/// said otherwise, like for BCS.
+///
+/// The ledger info headers of the RPC's response get passed along, as SDKs rely on them.
+fn build_response(
+    status: u16,
+    rpc_name: String,
+    content_type: Option<&str>,
+    rpc_headers: Option<&HeaderMap>,
+    body: ResponseBody,
+) -> hyper::Response<ResponseBody> {
+    let mut response = hyper::Response::builder()
+        .status(status)
+        .header("Content-Type", content_type.unwrap_or("application/json"))
+        .header("Access-Control-Allow-Origin", "*")
+        .header("rpc-used", rpc_name);
+
+    for (name, value) in rpc_headers.into_iter().flatten() {
+        if name.as_str().starts_with(APTOS_HEADER_PREFIX) {
+            response = response.header(name, value);
+        }
+    }
+
+    response.body(body).unwrap()
+}
+
 /// Pick RPC and send request to it. In case the result is cached,
 /// read and return from the cache.
 async fn forward_body(
-    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    connection_params: &ConnectionParams,
+    network: &Network,
     params: RequestParams,
     parts: Parts,
     bytes: Bytes,
 ) -> (
-    Result<hyper::Response<Full<Bytes>>, Infallible>,
+    Result<hyper::Response<ResponseBody>, Infallible>,
+    Option<usize>,
+) {
+    // Not found errors get cached even for requests we don't cache otherwise
+    let cache_key = (params.cache || params.negative_ttl.is_some())
+        .then(|| cache_key(network.cache_namespace(), &parts, &bytes));
+
+    // Expired response we can still fall back on if no RPC answers
+    let mut stale = None;
+    if let Some(cache_key) = &cache_key {
+        match connection_params.cache.get(cache_key, params.max_stale()).await {
+            // Cached before a version the client has already seen, so it could go back in time
+            Lookup::Fresh(cached) | Lookup::Stale(cached, _)
+                if !cached.satisfies(params.min_ledger_version) => {}
+            Lookup::Fresh(cached) => {
+                return (
+                    Ok(build_response(
+                        cached.status,
+                        "cache".to_string(),
+                        cached.content_type.as_deref(),
+                        None,
+                        full(cached.body),
+                    )),
+                    None,
+                )
+            }
+            // Recent enough to serve right away, but refresh it in the background
+            Lookup::Stale(cached, age) if age < params.stale_while_revalidate => {
+                revalidate(connection_params, network, params, parts, bytes, cache_key.clone());
+                return (
+                    Ok(build_response(
+                        cached.status,
+                        "cache".to_string(),
+                        cached.content_type.as_deref(),
+                        None,
+                        full(cached.body),
+                    )),
+                    None,
+                );
+            }
+            Lookup::Stale(cached, _) => stale = Some(cached),
+            Lookup::Miss => {}
+        }
+    }
+
+    let (response, rpc_position) =
+        fetch_upstream(connection_params, network, params, parts, bytes, cache_key).await;
+
+    if let (Some(stale), Ok(fresh)) = (stale, &response) {
+        if fresh.status().is_server_error() {
+            return (
+                Ok(build_response(
+                    stale.status,
+                    "cache".to_string(),
+                    stale.content_type.as_deref(),
+                    None,
+                    full(stale.body),
+                )),
+                None,
+            );
+        }
+    }
+
+    (response, rpc_position)
+}
+
+/// Refresh a stale cache entry without making the client wait for it
+fn revalidate(
+    connection_params: &ConnectionParams,
+    network: &Network,
+    params: RequestParams,
+    parts: Parts,
+    bytes: Bytes,
+    cache_key: String,
+) {
+    let connection_params = connection_params.clone();
+    let network = network.clone();
+    tokio::task::spawn(async move {
+        let time = Instant::now();
+        let (_, rpc_position) =
+            fetch_upstream(&connection_params, &network, params, parts, bytes, Some(cache_key))
+                .await;
+        if let Some(rpc_position) = rpc_position {
+            update_rpc_latency(&network.rpc_list, rpc_position, time.elapsed());
+        }
+    });
+}
+
+/// Get the response from an RPC and cache it under `cache_key` if we should.
+async fn fetch_upstream(
+    connection_params: &ConnectionParams,
+    network: &Network,
+    params: RequestParams,
+    parts: Parts,
+    bytes: Bytes,
+    cache_key: Option<String>,
+) -> (
+    Result<hyper::Response<ResponseBody>, Infallible>,
     Option<usize>,
 ) {
     // RPC used to get the response, we use it to update the latency for it later.
     let mut rpc_position;
 
-    // Get the response from either the DB or from a RPC. If it timeouts, retry.
-    let (rax, status, rpc_name) = get_response!(
-        cache,
-        rpc_position,
-        rpc_list_rwlock,
-        finalized_rx.clone(),
-        named_numbers.clone(),
-        head_cache.clone(),
-        params.ttl,
-        params.max_retries,
-        parts.clone(),
-        bytes.clone()
-    );
-
-    let res;
-    // Convert rx to bytes and but it in a Buf
-    let body = hyper::body::Bytes::from(rax);
-    // Put it in a http_body_util::Full
-    let body = Full::new(body);
-
-    if status == 200 {
-        // Build the response
-        res = hyper::Response::builder()
-            .status(200)
-            .header("Content-Type", "application/json")
-            .header("Access-Control-Allow-Origin", "*")
-            .header("rpc-used", rpc_name)
-            .body(body)
-            .unwrap();
-    } else {
-        res = hyper::Response::builder()
-            .status(status)
-            .header("Content-Type", "application/json")
-            .header("Access-Control-Allow-Origin", "*")
-            .header("rpc-used", rpc_name)
-            .body(body)
-            .unwrap();
+    // Nothing needs to look at the body, so pass it through frame by frame
+    // as it comes in instead of holding all of it in memory.
+    //
+    // Responses we want to cache or compare have to be buffered anyway.
+    if params.stream && !params.cache && params.quorum.is_none() {
+        let (response, rpc_name) = get_response!(
+            cache,
+            rpc_position,
+            network.rpc_list,
+            finalized_rx.clone(),
+            named_numbers.clone(),
+            head_cache.clone(),
+            params,
+            connection_params,
+            parts.clone(),
+            bytes.clone(),
+            |rpc: Rpc, parts, bytes| async move { rpc.request(parts, bytes).await }
+        );
+
+        let content_length = response
+            .headers()
+            .get(hyper::header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<usize>().ok());
+        if content_length.is_some_and(|length| length > params.max_response_size) {
+            return (
+                Ok(TridentError::ResponseTooLarge(params.max_response_size).into()),
+                rpc_position,
+            );
+        }
+
+        // Chunked responses don't tell us their size upfront, so the stream
+        // gets cut off if it goes over the limit, or if it's still going at the deadline.
+        let status = response.status().as_u16();
+        let (response_parts, body) = response.into_parts();
+        let mut body = Limited::new(body, params.max_response_size).boxed();
+        if let Some(deadline) = params.deadline {
+            body = Deadline::new(body, deadline).boxed();
+        }
+
+        return (
+            Ok(build_response(
+                status,
+                rpc_name,
+                response_parts
+                    .headers
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|content_type| content_type.to_str().ok()),
+                Some(&response_parts.headers),
+                body,
+            )),
+            rpc_position,
+        );
+    }
+
+    // Identical reads share one upstream request. Which responses are acceptable also
+    // depends on the route and the group and ledger version a request is pinned to, so
+    // those are part of the key. The cache key already has the Accept header.
+    let flight = match params.coalesce {
+        true => {
+            let key = cache_key.clone().unwrap_or_else(|| {
+                crate::core::cache::cache_key(network.cache_namespace(), &parts, &bytes)
+            });
+            let key = format!(
+                "{}|{:?}|{:?}|{:?}",
+                key, params.route, params.group, params.min_ledger_version
+            );
+            let flight = connection_params.in_flight.join(key);
+            let flight = match params.deadline {
+                Some(deadline) => {
+                    match timeout(deadline.saturating_duration_since(Instant::now()), flight).await
+                    {
+                        Ok(flight) => flight,
+                        Err(_) => return (Ok(TridentError::TimedOut.into()), None),
+                    }
+                }
+                None => flight.await,
+            };
+
+            match flight {
+                Flight::Shared(shared) => {
+                    let (rax, rpc_name) = &*shared;
+                    return (
+                        Ok(build_response(
+                            rax.status,
+                            rpc_name.clone(),
+                            rax.content_type(),
+                            Some(&rax.headers),
+                            full(rax.body.clone()),
+                        )),
+                        None,
+                    );
+                }
+                Flight::Leader(guard) => Some(guard),
+            }
+        }
+        false => None,
+    };
+
+    // Decide before `parts` gets moved, the copy is sent once we have something to compare to
+    let mirror = connection_params
+        .shadow
+        .should_mirror(network, &parts)
+        .then(|| (parts.clone(), bytes.clone()));
+    let started = Instant::now();
+
+    let (rax, rpc_name) = match params.quorum {
+        // Nobody in particular answered, so there's no latency to attribute
+        Some(quorum) => {
+            rpc_position = None;
+
+            let mut ttl = Duration::from_millis(params.ttl.try_into().unwrap());
+            if let Some(deadline) = params.deadline {
+                ttl = ttl.min(deadline.saturating_duration_since(Instant::now()));
+            }
+
+            match quorum_read(
+                &network.rpc_list,
+                quorum,
+                |rpc| params.eligible(rpc) && params.caught_up(rpc),
+                params.min_ledger_version,
+                parts,
+                bytes,
+                ttl,
+                &connection_params.quorum_stats,
+            )
+            .await
+            {
+                Ok(response) => response,
+                Err(e) => return (Ok(e.into()), None),
+            }
+        }
+        // Get the response from either the DB or from a RPC. If it timeouts, retry.
+        None => get_response!(
+            cache,
+            rpc_position,
+            network.rpc_list,
+            finalized_rx.clone(),
+            named_numbers.clone(),
+            head_cache.clone(),
+            params,
+            connection_params,
+            parts.clone(),
+            bytes.clone(),
+            |rpc: Rpc, parts, bytes| async move { rpc.send_request(parts, bytes).await }
+        ),
+    };
+
+    if let Some(flight) = flight {
+        flight.finish(&rax, &rpc_name);
+    }
+
+    if let Some((parts, bytes)) = mirror {
+        connection_params
+            .shadow
+            .mirror(parts, bytes, &rax, started.elapsed());
     }
 
-    (Ok(res), rpc_position)
+    if let Some(cache_key) = cache_key {
+        let ttl = if params.cache && rax.status == 200 && !is_pending_transaction(&rax.body) {
+            Some(params.cache_ttl)
+        } else if is_not_found(rax.status, &rax.body) {
+            params.negative_ttl.map(Some)
+        } else {
+            None
+        };
+
+        if let Some(ttl) = ttl {
+            // Invalidation waits while we hold this, so it can't run between the check and the insert
+            let head_cache = network.invalidator.read().await;
+            // Don't cache what a transaction we've already seen has made stale
+            if !head_cache.is_outdated(&cache_key, rax.ledger_version()) {
+                connection_params
+                    .cache
+                    .insert(
+                        &cache_key,
+                        rax.status,
+                        rax.content_type(),
+                        &rax.body,
+                        rax.ledger_version(),
+                        ttl,
+                    )
+                    .await;
+            }
+        }
+    }
+
+    (
+        Ok(build_response(
+            rax.status,
+            rpc_name,
+            rax.content_type(),
+            Some(&rax.headers),
+            full(rax.body.clone()),
+        )),
+        rpc_position,
+    )
 }
 
 pub async fn accept_request(
     tx: Request<hyper::body::Incoming>,
     connection_params: ConnectionParams,
-) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+) -> Result<hyper::Response<ResponseBody>, Infallible> {
     // Send request and measure time
-    let response: Result<hyper::Response<Full<Bytes>>, Infallible>;
+    let response: Result<hyper::Response<ResponseBody>, Infallible>;
     let rpc_position: Option<usize>;
 
+    // get body and parts from incoming request
+    let (mut parts, body) = tx.into_parts();
+
+    let time = Instant::now();
+
+    // Strips the network's path prefix if it has one, so do this before anything reads the path
+    let network = &connection_params.networks[select_network(
+        &connection_params.networks,
+        connection_params.network,
+        &mut parts,
+    )];
+
+    // Clients can ask for a tighter deadline than the configured one, but not a longer one
+    let client_deadline = parts
+        .headers
+        .get("X-Trident-Timeout-Ms")
+        .and_then(|timeout| timeout.to_str().ok())
+        .and_then(|timeout| timeout.parse::<u64>().ok());
+
+    // Clients that just submitted a transaction can ask to only be answered at or past it
+    let client_min_version = match parts.headers.get("X-Trident-Min-Ledger-Version") {
+        Some(version) => match version.to_str().ok().and_then(|v| v.parse::<u64>().ok()) {
+            Some(version) => Some(version),
+            None => {
+                return Ok(TridentError::BadRequest(
+                    "X-Trident-Min-Ledger-Version must be a ledger version".to_string(),
+                )
+                .into())
+            }
+        },
+        None => None,
+    };
+
+    let class = RequestClass::from_parts(&parts);
+
     // RequestParams from config
-    let params = {
+    let (mut params, rate_limit, api_key, require_key, session, ledger_wait) = {
         let config_guard = connection_params.config.read().unwrap();
-        RequestParams {
-            ttl: config_guard.ttl,
-            max_retries: config_guard.max_retries,
-        }
+
+        // Also strips a `/key/<key>` prefix from the path, so do this before anything reads it
+        let api_key = config_guard.api_keys.extract(&mut parts);
+        let known_key = api_key.as_ref().and_then(|key| {
+            let settings = connection_params.api_keys.get(key)?;
+            Some((key.clone(), settings))
+        });
+
+        let ratelimit = &config_guard.ratelimit;
+        let rate_limit = match ratelimit.limit_for(class) {
+            Some(limit) if ratelimit.enabled => Some((
+                ratelimit.client_id(
+                    &parts,
+                    connection_params.remote_addr,
+                    known_key.as_ref().map(|(key, _)| key.as_str()),
+                ),
+                limit,
+            )),
+            _ => None,
+        };
+
+        let matched = match_route(&config_guard.routes, &parts);
+        let route = matched.map(|(_, route)| route);
+
+        let params = RequestParams {
+            route: matched.map(|(index, _)| index),
+            ttl: route.and_then(|route| route.ttl).unwrap_or(config_guard.ttl),
+            max_retries: route
+                .and_then(|route| route.max_retries)
+                .unwrap_or(config_guard.max_retries),
+            hedge_percentile: if config_guard.hedge && is_hedgeable(&parts) {
+                Some(config_guard.hedge_percentile)
+            } else {
+                None
+            },
+            deadline: match (config_guard.deadline, client_deadline) {
+                (Some(deadline), Some(client)) => Some(deadline.min(client)),
+                (deadline, client) => deadline.or(client),
+            }
+            .map(|deadline| time + Duration::from_millis(deadline)),
+            max_request_size: config_guard.max_request_size,
+            stream: config_guard.stream_responses,
+            max_response_size: config_guard.max_response_size,
+            group: route.and_then(|route| route.group.clone()),
+            strategy: route
+                .and_then(|route| route.strategy)
+                .unwrap_or_default(),
+            // Unless a route says otherwise, only cache what can't change
+            cache: cfg!(not(feature = "no-cache"))
+                && route
+                    .and_then(|route| route.cache)
+                    .unwrap_or_else(|| is_immutable(&parts)),
+            cache_ttl: route.and_then(|route| route.cache_ttl),
+            min_ledger_version: None,
+            ledger_wait_until: None,
+            max_block_lag: config_guard.max_block_lag,
+            quorum: route.and_then(|route| route.quorum),
+            coalesce: config_guard.coalesce && is_hedgeable(&parts),
+            stale_while_revalidate: route
+                .and_then(|route| route.stale_while_revalidate)
+                .unwrap_or(0),
+            stale_if_error: route.and_then(|route| route.stale_if_error).unwrap_or(0),
+            negative_ttl: route
+                .and_then(|route| route.negative_ttl)
+                .or(config_guard.negative_ttl)
+                .filter(|ttl| cfg!(not(feature = "no-cache")) && *ttl > 0 && is_hedgeable(&parts)),
+        };
+
+        let sessions = &config_guard.sessions;
+        let session = match sessions.enabled {
+            true => sessions.session_id(&parts, api_key.as_deref()),
+            false => None,
+        };
+
+        (
+            params,
+            rate_limit,
+            known_key,
+            config_guard.api_keys.enabled,
+            session,
+            config_guard.ledger_wait,
+        )
     };
 
-    let time = Instant::now();
+    // Versions differ between networks, so sessions are tracked per network
+    let session = session.map(|session| {
+        let key = format!("{}/{}", network.name.as_deref().unwrap_or_default(), session.id);
+        params.min_ledger_version = connection_params.sessions.min_version(&key);
+        (key, session.set_cookie)
+    });
 
-    // get body and parts from incoming request
-    let (parts, body) = tx.into_parts();
-    // let body_bytes = body.collect();
-    let body_bytes = block_on(async {
-        body.collect()
-            .await
-            .map(|collected| collected.to_bytes())
-            .unwrap_or_else(|_| Bytes::new())
+    if let Some(min) = client_min_version {
+        params.min_ledger_version = Some(params.min_ledger_version.map_or(min, |v| v.max(min)));
+    }
+
+    // Don't wait for an RPC to catch up past `ledger_wait` or the request deadline
+    if params.min_ledger_version.is_some() {
+        let wait_until = time + Duration::from_millis(ledger_wait);
+        params.ledger_wait_until = Some(match params.deadline {
+            Some(deadline) => deadline.min(wait_until),
+            None => wait_until,
+        });
+    }
+
+    // Check the key and what it's allowed to call
+    let api_key = if require_key {
+        match api_key {
+            Some((key, settings)) if settings.allows(parts.uri.path()) => Some((key, settings)),
+            Some(_) => {
+                return Ok(TridentError::RouteNotAllowed(parts.uri.path().to_string()).into())
+            }
+            None => return Ok(TridentError::Unauthorized.into()),
+        }
+    } else {
+        None
+    };
+
+    // Turn away clients over their limit before doing any work for them
+    let rate_limit_status = rate_limit.map(|(client, limit)| {
+        connection_params
+            .rate_limiter
+            .check(&client, class, limit)
     });
+    if let Some(status) = &rate_limit_status {
+        if let Some(retry_after) = status.retry_after {
+            let mut response: hyper::Response<ResponseBody> =
+                TridentError::RateLimited(retry_after).into();
+            status.apply_headers(response.headers_mut());
+            return Ok(response);
+        }
+    }
+
+    // Rate limited requests don't count against the quota
+    if let Some((key, settings)) = api_key {
+        if let Err(retry_after) = connection_params.api_keys.record_usage(&key, &settings) {
+            return Ok(TridentError::QuotaExceeded(retry_after).into());
+        }
+        // A key pinned to a group overrides the route's group
+        if settings.group.is_some() {
+            params.group = settings.group;
+        }
+    }
+
+    // Don't bother reading the body if the client already told us it's too big
+    let content_length = parts
+        .headers
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > params.max_request_size) {
+        return Ok(TridentError::PayloadTooLarge(params.max_request_size).into());
+    }
+
+    let body_bytes = match Limited::new(body, params.max_request_size).collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(e) if e.downcast_ref::<LengthLimitError>().is_some() => {
+            return Ok(TridentError::PayloadTooLarge(params.max_request_size).into());
+        }
+        Err(e) => return Ok(TridentError::BadRequest(e.to_string()).into()),
+    };
 
     (response, rpc_position) = forward_body(
-        &connection_params.rpc_list_rwlock,
+        &connection_params,
+        network,
         params,
         parts.clone(),
         body_bytes.clone(),
@@ -278,8 +1035,113 @@ pub async fn accept_request(
     // Here, we update the latency of the RPC that was used to process the request
     // if `rpc_position` is Some.
     if let Some(rpc_position) = rpc_position {
-        update_rpc_latency(&connection_params.rpc_list_rwlock, rpc_position, time);
+        update_rpc_latency(&network.rpc_list, rpc_position, time);
     }
 
-    response
+    response.map(|mut response| {
+        if let Some(status) = &rate_limit_status {
+            status.apply_headers(response.headers_mut());
+        }
+
+        if let Some((key, set_cookie)) = session {
+            if let Some(version) = ledger_version(response.headers()) {
+                connection_params.sessions.observe(&key, version);
+            }
+            if let Some(cookie) = set_cookie.and_then(|cookie| cookie.parse().ok()) {
+                response.headers_mut().insert(hyper::header::SET_COOKIE, cookie);
+            }
+        }
+
+        response
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> RequestParams {
+        RequestParams {
+            ttl: 1000,
+            max_retries: 3,
+            hedge_percentile: None,
+            deadline: None,
+            max_request_size: 1024,
+            stream: false,
+            max_response_size: 1024,
+            route: None,
+            group: None,
+            strategy: Strategy::Default,
+            cache: false,
+            cache_ttl: None,
+            min_ledger_version: None,
+            ledger_wait_until: None,
+            max_block_lag: None,
+            quorum: None,
+            coalesce: false,
+            stale_while_revalidate: 0,
+            stale_if_error: 0,
+            negative_ttl: None,
+        }
+    }
+
+    fn rpc(group: Option<&str>) -> Rpc {
+        let mut rpc = Rpc::new("http://127.0.0.1:9101".to_string(), None, 15, 0, 3.0);
+        rpc.group = group.map(|group| group.to_string());
+        rpc
+    }
+
+    #[test]
+    fn eligibility() {
+        let pinned = RequestParams {
+            group: Some("archive".to_string()),
+            ..params()
+        };
+        assert!(pinned.eligible(&rpc(Some("archive"))));
+        assert!(!pinned.eligible(&rpc(Some("fast"))));
+        assert!(!pinned.eligible(&rpc(None)));
+        assert!(params().eligible(&rpc(Some("fast"))));
+
+        // Sits out for a bit after failing to respond
+        let mut down = rpc(None);
+        down.status.mark_erroring();
+        assert!(!params().eligible(&down));
+    }
+
+    #[test]
+    fn ledger_position() {
+        let rpc = rpc(None);
+        let behind = RequestParams {
+            min_ledger_version: Some(100),
+            max_block_lag: Some(5),
+            ..params()
+        };
+        // We don't know where it's at yet
+        assert!(!behind.caught_up(&rpc));
+        assert!(params().caught_up(&rpc));
+
+        rpc.ledger.set(100, 50);
+        assert!(behind.caught_up(&rpc));
+        assert!(!behind.lagging(&rpc, Some(55)));
+        assert!(behind.lagging(&rpc, Some(56)));
+        assert!(!params().lagging(&rpc, Some(1000)));
+    }
+
+    #[test]
+    fn responses_pass_ledger_headers_along() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-aptos-ledger-version", "42".parse().unwrap());
+        headers.insert("x-aptos-chain-id", "1".parse().unwrap());
+        headers.insert("set-cookie", "upstream=1".parse().unwrap());
+
+        let response = build_response(200, "rpc".to_string(), None, Some(&headers), full("{}"));
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(response.headers()["rpc-used"], "rpc");
+        assert_eq!(response.headers()["x-aptos-ledger-version"], "42");
+        assert_eq!(response.headers()["x-aptos-chain-id"], "1");
+        assert!(response.headers().get("set-cookie").is_none());
+
+        let bcs = build_response(200, "rpc".to_string(), Some("application/x-bcs"), None, full(""));
+        assert_eq!(bcs.headers()[header::CONTENT_TYPE], "application/x-bcs");
+    }
 }
@@ -1,17 +1,88 @@
-use crate::Rpc;
+use crate::{
+    config::types::Strategy,
+    Rpc,
+};
+use rand::Rng;
 use std::time::SystemTime;
 
 
-// Generic entry point fn to select the next rpc and return its position
-pub fn pick(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
+// Generic entry point fn to select the next rpc and return its position.
+// Only RPCs for which `eligible` returns true are considered.
+pub fn pick_where<F>(list: &mut [Rpc], eligible: F) -> (Rpc, Option<usize>)
+where
+    F: Fn(&Rpc) -> bool,
+{
+    pick_with(list, eligible, Strategy::Default)
+}
+
+// Same as `pick_where`, but lets routes choose how the RPC is selected
+pub fn pick_with<F>(list: &mut [Rpc], eligible: F, strategy: Strategy) -> (Rpc, Option<usize>)
+where
+    F: Fn(&Rpc) -> bool,
+{
+    let candidates: Vec<usize> = (0..list.len()).filter(|&i| eligible(&list[i])).collect();
+    let candidates = preferred_tier(list, candidates);
+
     // If len is 1, return the only element
-    if list.len() == 1 {
-        return (list[0].clone(), Some(0));
-    } else if list.is_empty() {
+    if candidates.len() == 1 {
+        list[candidates[0]].last_used = now_micros();
+        return (list[candidates[0]].clone(), Some(candidates[0]));
+    } else if candidates.is_empty() {
         return (Rpc::default(), None);
     }
 
-    algo(list)
+    match strategy {
+        Strategy::Default => algo(list, &candidates),
+        Strategy::Fastest => fastest(list, &candidates),
+        Strategy::Random => random(list, &candidates),
+    }
+}
+
+// Always the lowest latency candidate
+fn fastest(list: &mut [Rpc], candidates: &[usize]) -> (Rpc, Option<usize>) {
+    let choice = argsort(list)
+        .into_iter()
+        .find(|i| candidates.contains(i))
+        .unwrap();
+
+    list[choice].last_used = now_micros();
+    (list[choice].clone(), Some(choice))
+}
+
+// Any candidate, uniformly at random
+fn random(list: &mut [Rpc], candidates: &[usize]) -> (Rpc, Option<usize>) {
+    let choice = candidates[rand::thread_rng().gen_range(0..candidates.len())];
+
+    list[choice].last_used = now_micros();
+    (list[choice].clone(), Some(choice))
+}
+
+// Narrow `candidates` down to the most preferred tier that has an RPC available.
+//
+// A lower tier only gets traffic once every RPC above it is backing off from an
+// error or over its max_per_second. If nothing is available at all, we stay on the top tier.
+fn preferred_tier(list: &[Rpc], mut candidates: Vec<usize>) -> Vec<usize> {
+    let time = now_micros();
+    let is_available = |rpc: &Rpc| {
+        !rpc.status.is_backing_off() && time.saturating_sub(rpc.last_used) > rpc.min_time_delta
+    };
+
+    candidates.sort_by_key(|&i| list[i].priority);
+    let priority = candidates
+        .iter()
+        .find(|&&i| is_available(&list[i]))
+        .or(candidates.first())
+        .map(|&i| list[i].priority);
+
+    candidates.retain(|&i| Some(list[i].priority) == priority);
+    candidates
+}
+
+fn now_micros() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Failed to get current time")
+        .as_micros()
 }
 
 // Sorting algo
@@ -36,14 +107,14 @@ pub fn argsort(data: &[Rpc]) -> Vec<usize> {
     not(feature = "selection-random"),
     not(feature = "old-weighted-round-robin"),
 ))]
-fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
+fn algo(list: &mut [Rpc], candidates: &[usize]) -> (Rpc, Option<usize>) {
     // Sort by latency
-    let indices = argsort(list);
+    let indices: Vec<usize> = argsort(list)
+        .into_iter()
+        .filter(|i| candidates.contains(i))
+        .collect();
 
-    let time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .expect("Failed to get current time")
-        .as_micros();
+    let time = now_micros();
 
     // Picks the second fastest one rpc that meets our requirements
     // Also take into account min_delta_time
@@ -68,3 +139,65 @@ fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
     list[choice].last_used = time;
     (list[choice].clone(), Some(choice))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc(port: u16, priority: u32) -> Rpc {
+        let mut rpc = Rpc::new(format!("http://127.0.0.1:{}", port), None, 15, 0, 3.0);
+        rpc.priority = priority;
+        rpc
+    }
+
+    #[test]
+    fn lower_tiers_only_take_spillover() {
+        let mut list = vec![rpc(9103, 1), rpc(9101, 0), rpc(9102, 0)];
+        for _ in 0..10 {
+            let (_, position) = pick_where(&mut list, |_| true);
+            assert_ne!(position, Some(0));
+        }
+
+        list[1].status.mark_erroring();
+        list[2].status.mark_erroring();
+        let (picked, position) = pick_where(&mut list, |_| true);
+        assert_eq!(position, Some(0));
+        assert_eq!(picked.url, "http://127.0.0.1:9103");
+    }
+
+    #[test]
+    fn old_errors_dont_keep_a_tier_out() {
+        let mut list = vec![rpc(9103, 1), rpc(9101, 0)];
+        // Failed a while ago and hasn't won a request since
+        list[1].status.is_erroring = true;
+        list[1].status.last_error = 0;
+        for _ in 0..10 {
+            let (_, position) = pick_where(&mut list, |_| true);
+            assert_eq!(position, Some(1));
+            list[1].last_used = 0;
+        }
+    }
+
+    #[test]
+    fn only_eligible_rpcs_get_picked() {
+        let mut list = vec![rpc(9101, 0), rpc(9102, 1)];
+        let (_, position) = pick_with(&mut list, |rpc| rpc.priority == 1, Strategy::Random);
+        assert_eq!(position, Some(1));
+        assert!(list[1].last_used > 0);
+
+        let (_, position) = pick_where(&mut list, |_| false);
+        assert_eq!(position, None);
+    }
+
+    #[test]
+    fn fastest() {
+        let mut list = vec![rpc(9101, 0), rpc(9102, 0), rpc(9103, 0)];
+        list[0].status.latency = 30.0;
+        list[1].status.latency = 10.0;
+        list[2].status.latency = 20.0;
+        assert_eq!(argsort(&list), vec![1, 2, 0]);
+
+        let (_, position) = pick_with(&mut list, |_| true, Strategy::Fastest);
+        assert_eq!(position, Some(1));
+    }
+}
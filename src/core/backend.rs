@@ -0,0 +1,493 @@
+use crate::utils::resp::{
+    Reply,
+    RespClient,
+    RespError,
+};
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use sled::{
+    Db,
+    Tree,
+};
+use std::{
+    error::Error,
+    time::Duration,
+};
+
+#[derive(Debug)]
+pub enum CacheError {
+    Sled(sled::Error),
+    // The store was corrupt and got cleared, everything in it is gone
+    Rebuilt,
+    Redis(RespError),
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CacheError::Sled(e) => write!(f, "{}", e),
+            CacheError::Rebuilt => write!(f, "Cache was corrupt and had to be cleared"),
+            CacheError::Redis(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for CacheError {}
+
+impl From<RespError> for CacheError {
+    fn from(error: RespError) -> Self {
+        CacheError::Redis(error)
+    }
+}
+
+/// What eviction passes need to know about an entry
+#[derive(Debug)]
+pub struct EntryInfo {
+    pub key: String,
+    // Size of the key and value
+    pub size: u64,
+    // In ms since the epoch, 0 for never
+    pub expires: u64,
+}
+
+/// Where cached responses end up. Values are opaque to the backend.
+pub trait CacheBackend: Send + Sync + std::fmt::Debug {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Bytes>, CacheError>>;
+
+    /// Store `value` under `key`. The backend has to keep it for `keep_for` ms,
+    /// or until it's removed if that's `None`. Returns the size of the value it replaced, if known.
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Bytes,
+        keep_for: Option<u64>,
+    ) -> BoxFuture<'a, Result<Option<u64>, CacheError>>;
+
+    /// Returns the size of the removed value, `None` if there was nothing to remove
+    fn remove<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<u64>, CacheError>>;
+
+    /// Every key starting with any of `prefixes`, in one pass over the store
+    fn keys<'a>(&'a self, prefixes: &'a [String])
+        -> BoxFuture<'a, Result<Vec<String>, CacheError>>;
+
+    /// Every entry, for budgets and eviction. Only called if `evicts_itself` is false.
+    fn entries(&self) -> BoxFuture<'_, Result<Vec<EntryInfo>, CacheError>>;
+
+    /// Whether the backend expires entries and enforces size limits on its own
+    fn evicts_itself(&self) -> bool {
+        false
+    }
+
+    /// Write out whatever hasn't reached disk yet. Nothing to do if the store
+    /// takes care of that itself.
+    fn flush(&self) -> BoxFuture<'_, Result<(), CacheError>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Stores responses in the `responses` tree of the separate `<db_path>-responses` sled DB
+#[derive(Debug)]
+pub struct SledBackend {
+    tree: Tree,
+}
+
+impl SledBackend {
+    pub fn open(db: &Db) -> Result<Self, sled::Error> {
+        Ok(Self {
+            tree: db.open_tree("responses")?,
+        })
+    }
+
+    /// If the tree turns out to be corrupt, it gets cleared so the cache can start over
+    fn check<T>(&self, result: Result<T, sled::Error>) -> Result<T, CacheError> {
+        match result {
+            Ok(value) => Ok(value),
+            Err(sled::Error::Corruption { .. }) => {
+                self.tree.clear().map_err(CacheError::Sled)?;
+                Err(CacheError::Rebuilt)
+            }
+            Err(e) => Err(CacheError::Sled(e)),
+        }
+    }
+}
+
+impl CacheBackend for SledBackend {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Bytes>, CacheError>> {
+        Box::pin(async move {
+            let value = self.check(self.tree.get(key))?;
+            Ok(value.map(|value| Bytes::copy_from_slice(&value)))
+        })
+    }
+
+    // sled doesn't expire anything, eviction passes take care of that
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Bytes,
+        _keep_for: Option<u64>,
+    ) -> BoxFuture<'a, Result<Option<u64>, CacheError>> {
+        Box::pin(async move {
+            let old = self.check(self.tree.insert(key, value.as_ref()))?;
+            Ok(old.map(|old| old.len() as u64))
+        })
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<u64>, CacheError>> {
+        Box::pin(async move {
+            let old = self.check(self.tree.remove(key))?;
+            Ok(old.map(|old| old.len() as u64))
+        })
+    }
+
+    fn keys<'a>(
+        &'a self,
+        prefixes: &'a [String],
+    ) -> BoxFuture<'a, Result<Vec<String>, CacheError>> {
+        Box::pin(async move {
+            let mut keys = Vec::new();
+            for prefix in prefixes {
+                for key in self.tree.scan_prefix(prefix).keys() {
+                    let key = self.check(key)?;
+                    keys.push(String::from_utf8_lossy(&key).to_string());
+                }
+            }
+            // Prefixes can overlap
+            keys.sort();
+            keys.dedup();
+            Ok(keys)
+        })
+    }
+
+    fn entries(&self) -> BoxFuture<'_, Result<Vec<EntryInfo>, CacheError>> {
+        Box::pin(async move {
+            self.tree
+                .iter()
+                .map(|entry| {
+                    let (key, value) = self.check(entry)?;
+                    let expires = match value.get(..8) {
+                        Some(expires) => u64::from_be_bytes(expires.try_into().unwrap()),
+                        None => 0,
+                    };
+                    Ok(EntryInfo {
+                        key: String::from_utf8_lossy(&key).to_string(),
+                        size: (key.len() + value.len()) as u64,
+                        expires,
+                    })
+                })
+                .collect()
+        })
+    }
+
+    fn flush(&self) -> BoxFuture<'_, Result<(), CacheError>> {
+        Box::pin(async move {
+            self.check(self.tree.flush_async().await)?;
+            Ok(())
+        })
+    }
+}
+
+/// Stores responses in Redis, or anything else that speaks its protocol, so
+/// several trident instances can share them.
+///
+/// Entries expire through Redis, and `maxmemory` takes the place of our budgets.
+#[derive(Debug)]
+pub struct RedisBackend {
+    client: RespClient,
+    // Put in front of every key, so we don't step on anything else in the same db
+    prefix: String,
+}
+
+impl RedisBackend {
+    pub fn new(url: &str, prefix: &str, timeout: Duration) -> Result<Self, RespError> {
+        Ok(Self {
+            client: RespClient::new(url, timeout)?,
+            prefix: prefix.to_string(),
+        })
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+impl CacheBackend for RedisBackend {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Bytes>, CacheError>> {
+        Box::pin(async move {
+            match self.client.command(&[b"GET", self.key(key).as_bytes()]).await? {
+                Reply::Bulk(value) => Ok(value),
+                reply => Err(unexpected(reply)),
+            }
+        })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Bytes,
+        keep_for: Option<u64>,
+    ) -> BoxFuture<'a, Result<Option<u64>, CacheError>> {
+        Box::pin(async move {
+            let key = self.key(key);
+            let reply = match keep_for {
+                Some(keep_for) => {
+                    let keep_for = keep_for.max(1).to_string();
+                    self.client
+                        .command(&[b"SET", key.as_bytes(), &value, b"PX", keep_for.as_bytes()])
+                        .await?
+                }
+                None => self.client.command(&[b"SET", key.as_bytes(), &value]).await?,
+            };
+
+            match reply {
+                Reply::Status(status) if status == "OK" => Ok(None),
+                reply => Err(unexpected(reply)),
+            }
+        })
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<u64>, CacheError>> {
+        Box::pin(async move {
+            match self.client.command(&[b"DEL", self.key(key).as_bytes()]).await? {
+                Reply::Integer(0) => Ok(None),
+                // Redis doesn't tell us how big it was
+                Reply::Integer(_) => Ok(Some(0)),
+                reply => Err(unexpected(reply)),
+            }
+        })
+    }
+
+    // SCAN walks the whole keyspace no matter the pattern, so all prefixes go in one
+    // pass matching what they have in common and get told apart here
+    fn keys<'a>(
+        &'a self,
+        prefixes: &'a [String],
+    ) -> BoxFuture<'a, Result<Vec<String>, CacheError>> {
+        Box::pin(async move {
+            if prefixes.is_empty() {
+                return Ok(Vec::new());
+            }
+            let pattern = format!("{}*", escape_glob(&self.key(common_prefix(prefixes))));
+            let mut keys = Vec::new();
+            let mut cursor = "0".to_string();
+
+            loop {
+                let reply = self
+                    .client
+                    .command(&[
+                        b"SCAN",
+                        cursor.as_bytes(),
+                        b"MATCH",
+                        pattern.as_bytes(),
+                        b"COUNT",
+                        b"1000",
+                    ])
+                    .await?;
+
+                let (next, batch) = match reply {
+                    Reply::Array(mut reply) if reply.len() == 2 => {
+                        match (reply.remove(0), reply.remove(0)) {
+                            (Reply::Bulk(Some(next)), Reply::Array(batch)) => (next, batch),
+                            _ => return Err(CacheError::Redis(RespError::Protocol("unexpected SCAN reply".to_string()))),
+                        }
+                    }
+                    reply => return Err(unexpected(reply)),
+                };
+
+                for key in batch {
+                    if let Reply::Bulk(Some(key)) = key {
+                        let key = String::from_utf8_lossy(&key);
+                        if let Some(key) = key.strip_prefix(&self.prefix) {
+                            if prefixes.iter().any(|prefix| key.starts_with(prefix.as_str())) {
+                                keys.push(key.to_string());
+                            }
+                        }
+                    }
+                }
+
+                cursor = String::from_utf8_lossy(&next).to_string();
+                if cursor == "0" {
+                    // SCAN can return the same key more than once
+                    keys.sort();
+                    keys.dedup();
+                    return Ok(keys);
+                }
+            }
+        })
+    }
+
+    fn entries(&self) -> BoxFuture<'_, Result<Vec<EntryInfo>, CacheError>> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+
+    fn evicts_itself(&self) -> bool {
+        true
+    }
+}
+
+fn unexpected(reply: Reply) -> CacheError {
+    CacheError::Redis(RespError::Protocol(format!("unexpected reply {:?}", reply)))
+}
+
+/// Longest prefix all of `prefixes` start with
+fn common_prefix(prefixes: &[String]) -> &str {
+    let first = prefixes[0].as_str();
+    let len = prefixes[1..].iter().fold(first.len(), |len, prefix| {
+        first[..len]
+            .char_indices()
+            .zip(prefix.chars())
+            .find(|((_, a), b)| a != b)
+            .map(|((i, _), _)| i)
+            .unwrap_or(len.min(prefix.len()))
+    });
+    &first[..len]
+}
+
+/// Cache keys have `?` in them, which SCAN would treat as a wildcard
+fn escape_glob(key: &str) -> String {
+    let mut escaped = String::with_capacity(key.len());
+    for c in key.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::redis;
+
+    /// Same checks for every backend
+    async fn round_trip(backend: &dyn CacheBackend) {
+        assert_eq!(backend.get("GET /v1").await.unwrap(), None);
+        backend.set("GET /v1", Bytes::from_static(b"one"), None).await.unwrap();
+        backend.set("GET /v1", Bytes::from_static(b"two"), Some(1000)).await.unwrap();
+        assert_eq!(backend.get("GET /v1").await.unwrap().as_deref(), Some(&b"two"[..]));
+
+        assert!(backend.remove("GET /v1").await.unwrap().is_some());
+        assert!(backend.remove("GET /v1").await.unwrap().is_none());
+        assert_eq!(backend.get("GET /v1").await.unwrap(), None);
+    }
+
+    /// Keys with glob characters in them, more than one SCAN page of them
+    async fn prefixes(backend: &dyn CacheBackend) {
+        let keys = [
+            "GET /v1/accounts/0x1?ledger_version=1",
+            "GET /v1/accounts/0x1/resources",
+            "GET /v1/accounts/0x1[x]*",
+            "GET /v1/accounts/0x10/resources",
+            "GET /v1/accounts/0x2/resources",
+            "GET /v1/blocks/by_height/1",
+        ];
+        for key in keys {
+            backend.set(key, Bytes::new(), None).await.unwrap();
+        }
+        let prefixes = |prefixes: &[&str]| -> Vec<String> {
+            prefixes.iter().map(|prefix| prefix.to_string()).collect()
+        };
+
+        // `?` is a literal, not any one character
+        assert_eq!(
+            backend.keys(&prefixes(&["GET /v1/accounts/0x1?"])).await.unwrap(),
+            vec!["GET /v1/accounts/0x1?ledger_version=1"]
+        );
+        assert_eq!(
+            backend.keys(&prefixes(&["GET /v1/accounts/0x1["])).await.unwrap(),
+            vec!["GET /v1/accounts/0x1[x]*"]
+        );
+        assert_eq!(
+            backend
+                .keys(&prefixes(&[
+                    "GET /v1/accounts/0x1/",
+                    "GET /v1/accounts/0x2/",
+                    "GET /v1/accounts/0x2/resources",
+                ]))
+                .await
+                .unwrap(),
+            vec!["GET /v1/accounts/0x1/resources", "GET /v1/accounts/0x2/resources"]
+        );
+        assert_eq!(backend.keys(&prefixes(&["GET /v1/"])).await.unwrap().len(), keys.len());
+        assert!(backend.keys(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn sled() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let backend = SledBackend::open(&db).unwrap();
+        round_trip(&backend).await;
+        prefixes(&backend).await;
+
+        backend
+            .set("GET /v1", Bytes::from(1234u64.to_be_bytes().to_vec()), None)
+            .await
+            .unwrap();
+        let entries = backend.entries().await.unwrap();
+        let entry = entries.iter().find(|entry| entry.key == "GET /v1").unwrap();
+        assert_eq!(entry.expires, 1234);
+        assert_eq!(entry.size, 7 + 8);
+        backend.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn redis() {
+        let (server, url) = redis::spawn().await;
+        let backend = RedisBackend::new(&url, "trident:", Duration::from_secs(1)).unwrap();
+        round_trip(&backend).await;
+        prefixes(&backend).await;
+
+        // Everything is stored under the prefix, and keys outside of it aren't ours
+        server
+            .data
+            .lock()
+            .unwrap()
+            .insert(b"other:GET /v1/blocks/by_height/2".to_vec(), Vec::new());
+        assert!(server
+            .data
+            .lock()
+            .unwrap()
+            .contains_key(&b"trident:GET /v1/blocks/by_height/1"[..]));
+        assert_eq!(
+            backend.keys(&["GET /v1/blocks/".to_string()]).await.unwrap(),
+            vec!["GET /v1/blocks/by_height/1"]
+        );
+        // Several prefixes still take a single pass
+        let scans = |server: &redis::Server| {
+            server.commands.lock().unwrap().iter().filter(|command| *command == "SCAN").count()
+        };
+        let before = scans(&server);
+        backend
+            .keys(&["GET /v1/accounts/0x1/".to_string(), "GET /v1/accounts/0x2/".to_string()])
+            .await
+            .unwrap();
+        let pages = (server.data.lock().unwrap().len() + 1) / 2;
+        assert_eq!(scans(&server) - before, pages);
+
+        // Redis persists on its own, so there's nothing to send
+        let sent = server.commands.lock().unwrap().len();
+        backend.flush().await.unwrap();
+        assert_eq!(server.commands.lock().unwrap().len(), sent);
+    }
+
+    #[test]
+    fn globs() {
+        let pattern = format!("{}*", escape_glob("GET /v1/a?b=[1]*\\"));
+        assert!(redis::glob_matches(pattern.as_bytes(), b"GET /v1/a?b=[1]*\\ tail"));
+        assert!(!redis::glob_matches(pattern.as_bytes(), b"GET /v1/aXb=[1]*\\"));
+        assert!(!redis::glob_matches(pattern.as_bytes(), b"GET /v1/a?b=1*\\"));
+    }
+
+    #[test]
+    fn common_prefixes() {
+        let prefixes = |prefixes: &[&str]| -> Vec<String> {
+            prefixes.iter().map(|prefix| prefix.to_string()).collect()
+        };
+        assert_eq!(common_prefix(&prefixes(&["abc"])), "abc");
+        assert_eq!(common_prefix(&prefixes(&["abcd", "abxy", "abc"])), "ab");
+        assert_eq!(common_prefix(&prefixes(&["abc", "a"])), "a");
+        assert_eq!(common_prefix(&prefixes(&["xé1", "xé2"])), "xé");
+        assert_eq!(common_prefix(&prefixes(&["abc", "xyz"])), "");
+    }
+}
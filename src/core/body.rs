@@ -0,0 +1,127 @@
+use crate::core::errors::TridentError;
+
+use http_body_util::{
+    combinators::BoxBody,
+    BodyExt,
+    Full,
+};
+use hyper::body::{
+    Body,
+    Bytes,
+    Frame,
+    SizeHint,
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+    time::Instant,
+};
+use tokio::time::Sleep;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Body of every response we send back to clients. Either fully buffered, or
+/// streamed frame by frame straight from an RPC.
+pub type ResponseBody = BoxBody<Bytes, BoxError>;
+
+/// Wraps a buffered chunk into a `ResponseBody`.
+pub fn full<T: Into<Bytes>>(chunk: T) -> ResponseBody {
+    Full::new(chunk.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
+
+/// Cuts a streamed body off with `TridentError::TimedOut` once the deadline
+/// passes, so a slow RPC can't keep a request alive after its headers arrived.
+pub struct Deadline<B> {
+    body: B,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl<B> Deadline<B> {
+    pub fn new(body: B, deadline: Instant) -> Self {
+        Self {
+            body,
+            sleep: Box::pin(tokio::time::sleep_until(deadline.into())),
+        }
+    }
+}
+
+impl<B> Body for Deadline<B>
+where
+    B: Body<Data = Bytes, Error = BoxError> + Unpin,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Poll::Ready(frame) = Pin::new(&mut self.body).poll_frame(cx) {
+            return Poll::Ready(frame);
+        }
+
+        match self.sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Some(Err(TridentError::TimedOut.into()))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use http_body_util::StreamBody;
+    use std::time::Duration;
+
+    fn chunks(chunks: Vec<&'static str>) -> ResponseBody {
+        let frames = chunks
+            .into_iter()
+            .map(|chunk| Ok::<_, BoxError>(Frame::data(Bytes::from(chunk))));
+        StreamBody::new(stream::iter(frames)).boxed()
+    }
+
+    #[tokio::test]
+    async fn deadline_lets_fast_bodies_through() {
+        let body = Deadline::new(
+            chunks(vec!["he", "llo"]),
+            Instant::now() + Duration::from_secs(5),
+        );
+
+        let body = body.collect().await.unwrap().to_bytes();
+        assert_eq!(body, Bytes::from("hello"));
+    }
+
+    #[tokio::test]
+    async fn deadline_cuts_off_slow_bodies() {
+        let slow = StreamBody::new(stream::pending::<Result<Frame<Bytes>, BoxError>>()).boxed();
+        let body = Deadline::new(slow, Instant::now() + Duration::from_millis(50));
+
+        let error = timeout_collect(body).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<TridentError>(),
+            Some(TridentError::TimedOut)
+        ));
+    }
+
+    async fn timeout_collect(body: Deadline<ResponseBody>) -> Result<Bytes, BoxError> {
+        tokio::time::timeout(Duration::from_secs(5), body.collect())
+            .await
+            .expect("the deadline should have ended the body")
+            .map(|collected| collected.to_bytes())
+    }
+}
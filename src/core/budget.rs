@@ -0,0 +1,73 @@
+use std::sync::Mutex;
+
+/// Token bucket used to cap extra upstream load (hedges, retries) to a percentage
+/// of regular traffic.
+///
+/// Every `deposit` adds `ratio` tokens to the bucket, and every `try_withdraw`
+/// takes a whole one out if available. With a ratio of 0.1, we can spend one
+/// extra request for every 10 regular ones.
+#[derive(Debug)]
+pub struct Budget {
+    ratio: f64,
+    max_tokens: f64,
+    tokens: Mutex<f64>,
+}
+
+impl Budget {
+    /// `percent` is the share of traffic we allow as extra load. `max_tokens` caps
+    /// how much of it can be saved up and spent in a burst.
+    pub fn new(percent: f64, max_tokens: f64) -> Self {
+        Self {
+            ratio: percent / 100.0,
+            max_tokens,
+            tokens: Mutex::new(max_tokens),
+        }
+    }
+
+    pub fn deposit(&self) {
+        let mut tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+        *tokens = (*tokens + self.ratio).min(self.max_tokens);
+    }
+
+    /// Returns true if there was budget left and one token was taken.
+    pub fn try_withdraw(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_full_and_refills_by_ratio() {
+        let budget = Budget::new(50.0, 2.0);
+        assert!(budget.try_withdraw());
+        assert!(budget.try_withdraw());
+        assert!(!budget.try_withdraw());
+
+        // Two regular requests at 50% buy one extra
+        budget.deposit();
+        assert!(!budget.try_withdraw());
+        budget.deposit();
+        assert!(budget.try_withdraw());
+        assert!(!budget.try_withdraw());
+    }
+
+    #[test]
+    fn savings_are_capped() {
+        let budget = Budget::new(100.0, 2.0);
+        for _ in 0..10 {
+            budget.deposit();
+        }
+
+        assert!(budget.try_withdraw());
+        assert!(budget.try_withdraw());
+        assert!(!budget.try_withdraw());
+    }
+}
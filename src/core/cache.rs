@@ -0,0 +1,782 @@
+use crate::{
+    config::types::{
+        CacheSettings,
+        Eviction,
+    },
+    core::backend::{
+        CacheBackend,
+        CacheError,
+    },
+    log_err,
+    log_wrn,
+};
+
+use bytes::Bytes;
+use http::request::Parts;
+use serde_json::{
+    json,
+    Value,
+};
+use sled::Db;
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Mutex,
+    },
+    time::{
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+/// A response read back from the cache
+#[derive(Debug)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub body: Bytes,
+    // In ms since the epoch, 0 for never
+    pub expires: u64,
+    // What the RPC that answered was at, if it said
+    pub ledger_version: Option<u64>,
+    // Content-Type the RPC answered with, if any
+    pub content_type: Option<String>,
+}
+
+impl CachedResponse {
+    /// Whether the response is as recent as a client that has seen `min_version`
+    /// needs. Entries that never expire can't have changed since.
+    pub fn satisfies(&self, min_version: Option<u64>) -> bool {
+        match min_version {
+            Some(min) => self.expires == 0 || self.ledger_version.is_some_and(|v| v >= min),
+            None => true,
+        }
+    }
+}
+
+/// What we found in the cache for a key
+#[derive(Debug)]
+pub enum Lookup {
+    Fresh(CachedResponse),
+    // Expired this many ms ago, but still within the stale window we asked for
+    Stale(CachedResponse, u64),
+    Miss,
+}
+
+/// Keeps track of which keys were used last
+#[derive(Debug, Default)]
+pub struct Recency {
+    ticks: HashMap<String, u64>,
+    order: BTreeMap<u64, String>,
+    next: u64,
+}
+
+impl Recency {
+    pub fn touch(&mut self, key: &str) {
+        match self.ticks.get_mut(key) {
+            Some(tick) => {
+                self.order.remove(tick);
+                *tick = self.next;
+            }
+            None => {
+                self.ticks.insert(key.to_string(), self.next);
+            }
+        }
+        self.order.insert(self.next, key.to_string());
+        self.next += 1;
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.ticks.contains_key(key)
+    }
+
+    pub fn oldest(&self) -> Option<String> {
+        self.order.values().next().cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.ticks.clear();
+        self.order.clear();
+    }
+}
+
+/// The most recently used entries, kept in memory so hot reads skip the backend
+#[derive(Debug, Default)]
+struct HotTier {
+    values: HashMap<String, Bytes>,
+    recency: Recency,
+    capacity: usize,
+}
+
+impl HotTier {
+    fn get(&mut self, key: &str) -> Option<Bytes> {
+        let value = self.values.get(key)?.clone();
+        self.recency.touch(key);
+        Some(value)
+    }
+
+    fn put(&mut self, key: &str, value: Bytes) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.values.insert(key.to_string(), value);
+        self.recency.touch(key);
+        while self.recency.len() > self.capacity {
+            match self.recency.oldest() {
+                Some(oldest) => self.remove(&oldest),
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.values.remove(key);
+        self.recency.remove(key);
+    }
+
+    fn clear(&mut self) {
+        self.values.clear();
+        self.recency.clear();
+    }
+}
+
+#[derive(Debug, Default)]
+struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    // Size of the keys and values in the backend
+    bytes: AtomicU64,
+    entries: AtomicU64,
+}
+
+impl CacheStats {
+    fn add(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.entries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn sub(&self, bytes: u64) {
+        let _ = self
+            .bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+                Some(total.saturating_sub(bytes))
+            });
+        let _ = self
+            .entries
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+                Some(total.saturating_sub(1))
+            });
+    }
+}
+
+/// Cached RPC responses, stored in a `CacheBackend` with an in-memory hot tier
+/// in front of it.
+///
+/// Keys look like `GET /v1/blocks/by_height/5?with_transactions=true` or
+/// `POST /v1/view#<body hash>`, so entries are easy to find by hand. Values are
+/// the expiry time in ms since the epoch (0 for never), the status code, the
+/// ledger version the response is from (0 if unknown) and then the body.
+///
+/// If the backend turns out to be corrupt, it gets cleared and the cache starts over.
+#[derive(Debug)]
+pub struct ResponseCache {
+    backend: Box<dyn CacheBackend>,
+    hot: Mutex<HotTier>,
+    // Only kept up to date with LRU eviction
+    recency: Mutex<Recency>,
+    settings: CacheSettings,
+    // How long past their expiry entries can still be served stale, in ms
+    grace: u64,
+    stats: CacheStats,
+}
+
+impl ResponseCache {
+    pub async fn open(
+        backend: Box<dyn CacheBackend>,
+        settings: &CacheSettings,
+        grace: u64,
+    ) -> Result<Self, CacheError> {
+        let cache = Self {
+            backend,
+            hot: Mutex::new(HotTier {
+                capacity: settings.hot_entries,
+                ..Default::default()
+            }),
+            recency: Mutex::new(Recency::default()),
+            settings: settings.clone(),
+            grace,
+            stats: CacheStats::default(),
+        };
+
+        // Count what's already there, so budgets hold across restarts
+        if !cache.backend.evicts_itself() {
+            if let Some(entries) = cache.check(cache.backend.entries().await) {
+                for entry in entries {
+                    cache.stats.add(entry.size);
+                }
+            }
+        }
+
+        Ok(cache)
+    }
+
+    /// Look up `key`. Entries that expired less than `max_stale` ms ago are still
+    /// returned, but as stale. Anything older gets dropped.
+    pub async fn get(&self, key: &str, max_stale: u64) -> Lookup {
+        let hot = self.hot.lock().unwrap_or_else(|e| e.into_inner()).get(key);
+        let value = match hot {
+            Some(value) => Some(value),
+            None => {
+                let value = self.check(self.backend.get(key).await).flatten();
+                if let Some(value) = &value {
+                    self.hot
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .put(key, value.clone());
+                }
+                value
+            }
+        };
+
+        let response = match value.as_deref().and_then(decode) {
+            Some(response) => response,
+            None => {
+                self.stats.misses.fetch_add(1, Ordering::Relaxed);
+                return Lookup::Miss;
+            }
+        };
+
+        let now = now_millis();
+        let expires = response.expires;
+        if expires == 0 || now < expires {
+            self.stats.hits.fetch_add(1, Ordering::Relaxed);
+            self.touch(key);
+            return Lookup::Fresh(response);
+        }
+        if now - expires < max_stale {
+            self.stats.hits.fetch_add(1, Ordering::Relaxed);
+            self.touch(key);
+            return Lookup::Stale(response, now - expires);
+        }
+
+        self.remove(key).await;
+        self.stats.misses.fetch_add(1, Ordering::Relaxed);
+        Lookup::Miss
+    }
+
+    /// Cache the response under `key`. It never expires if `ttl` (in ms) is `None`.
+    pub async fn insert(
+        &self,
+        key: &str,
+        status: u16,
+        content_type: Option<&str>,
+        body: &[u8],
+        ledger_version: Option<u64>,
+        ttl: Option<u64>,
+    ) {
+        let expires = ttl.map(|ttl| now_millis() + ttl).unwrap_or(0);
+        // Anything too long to store goes without, it isn't a real Content-Type anyway
+        let content_type = content_type
+            .filter(|content_type| content_type.len() <= u16::MAX as usize)
+            .unwrap_or("");
+
+        let mut value = Vec::with_capacity(HEADER_LEN + content_type.len() + body.len());
+        value.extend_from_slice(&expires.to_be_bytes());
+        value.extend_from_slice(&status.to_be_bytes());
+        value.extend_from_slice(&ledger_version.unwrap_or(0).to_be_bytes());
+        value.extend_from_slice(&(content_type.len() as u16).to_be_bytes());
+        value.extend_from_slice(content_type.as_bytes());
+        value.extend_from_slice(body);
+        let value = Bytes::from(value);
+
+        // Has to outlive its expiry for as long as it could be served stale
+        let keep_for = ttl.map(|ttl| ttl + self.grace);
+        if let Some(old) = self.check(self.backend.set(key, value.clone(), keep_for).await) {
+            if !self.backend.evicts_itself() {
+                if let Some(old) = old {
+                    self.stats.sub(key.len() as u64 + old);
+                }
+                self.stats.add((key.len() + value.len()) as u64);
+            }
+
+            self.hot
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .put(key, value);
+            self.touch(key);
+        }
+    }
+
+    /// Drop `key` from the cache. Returns false if it wasn't cached.
+    pub async fn remove(&self, key: &str) -> bool {
+        self.hot.lock().unwrap_or_else(|e| e.into_inner()).remove(key);
+        self.recency
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(key);
+
+        match self.check(self.backend.remove(key).await).flatten() {
+            Some(old) => {
+                if !self.backend.evicts_itself() {
+                    self.stats.sub(key.len() as u64 + old);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop every entry whose key starts with `prefix`, returns how many there were
+    pub async fn purge(&self, prefix: &str) -> usize {
+        self.purge_all(&[prefix.to_string()]).await
+    }
+
+    /// Same as `purge`, for every key starting with any of `prefixes`
+    pub async fn purge_all(&self, prefixes: &[String]) -> usize {
+        let keys = self.check(self.backend.keys(prefixes).await).unwrap_or_default();
+
+        let mut purged = 0;
+        for key in keys {
+            if self.remove(&key).await {
+                purged += 1;
+            }
+        }
+        purged
+    }
+
+    /// The entry under `key` as JSON, without counting it as a hit or use
+    pub async fn lookup(&self, key: &str) -> Value {
+        let value = self.check(self.backend.get(key).await).flatten();
+        match value.as_deref().and_then(decode) {
+            Some(response) => json!({
+                "status": response.status,
+                "expires": (response.expires != 0).then_some(response.expires),
+                "ledger_version": response.ledger_version,
+                "content_type": response.content_type,
+                "body": String::from_utf8_lossy(&response.body),
+            }),
+            None => Value::Null,
+        }
+    }
+
+    /// Make sure what's cached so far is still there after a restart
+    pub async fn flush(&self) {
+        self.check(self.backend.flush().await);
+    }
+
+    /// Sizes are left out if the backend keeps track of them itself
+    pub fn stats(&self) -> Value {
+        let tracked = !self.backend.evicts_itself();
+        json!({
+            "hits": self.stats.hits.load(Ordering::Relaxed),
+            "misses": self.stats.misses.load(Ordering::Relaxed),
+            "evictions": self.stats.evictions.load(Ordering::Relaxed),
+            "bytes": tracked.then(|| self.stats.bytes.load(Ordering::Relaxed)),
+            "entries": tracked.then(|| self.stats.entries.load(Ordering::Relaxed)),
+            "hot_entries": self.hot.lock().unwrap_or_else(|e| e.into_inner()).values.len(),
+        })
+    }
+
+    /// Drop entries that are past their expiry and any stale window, then evict
+    /// more until the cache is back within its budgets.
+    ///
+    /// Walks every entry, so it's meant to run in the background. Backends that
+    /// evict on their own are left alone.
+    pub async fn evict(&self) {
+        if self.backend.evicts_itself() {
+            return;
+        }
+        let entries = match self.check(self.backend.entries().await) {
+            Some(entries) => entries,
+            None => return,
+        };
+
+        let now = now_millis();
+        // What could go if we're over budget, in the order it should go in
+        let mut candidates: Vec<(u64, String)> = Vec::new();
+        // Entries from before the last restart, which we don't know the last use of
+        let mut untracked: Vec<String> = Vec::new();
+
+        for entry in entries {
+            if entry.expires != 0 && entry.expires.saturating_add(self.grace) <= now {
+                if self.remove(&entry.key).await {
+                    self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+                }
+                continue;
+            }
+
+            match self.settings.eviction {
+                Eviction::Ttl if entry.expires == 0 => candidates.push((u64::MAX, entry.key)),
+                Eviction::Ttl => candidates.push((entry.expires, entry.key)),
+                Eviction::Lru => {
+                    if !self
+                        .recency
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .contains(&entry.key)
+                    {
+                        untracked.push(entry.key);
+                    }
+                }
+            }
+        }
+        candidates.sort();
+
+        let mut candidates = untracked
+            .into_iter()
+            .chain(candidates.into_iter().map(|(_, key)| key));
+
+        while self.over_budget() {
+            let victim = match self.settings.eviction {
+                Eviction::Ttl => candidates.next(),
+                Eviction::Lru => candidates.next().or_else(|| {
+                    self.recency
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .oldest()
+                }),
+            };
+            let victim = match victim {
+                Some(victim) => victim,
+                None => break,
+            };
+
+            // Whatever the oldest entry is gets dropped from the recency list either way,
+            // so this can't loop forever
+            self.remove(&victim).await;
+            self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn over_budget(&self) -> bool {
+        self.settings
+            .max_bytes
+            .is_some_and(|max| self.stats.bytes.load(Ordering::Relaxed) > max)
+            || self
+                .settings
+                .max_entries
+                .is_some_and(|max| self.stats.entries.load(Ordering::Relaxed) > max)
+    }
+
+    fn touch(&self, key: &str) {
+        if self.settings.eviction == Eviction::Lru {
+            self.recency
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .touch(key);
+        }
+    }
+
+    /// Unwrap the result of a backend operation. If the backend had to be cleared,
+    /// so does everything we know about its entries.
+    fn check<T>(&self, result: Result<T, CacheError>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(CacheError::Rebuilt) => {
+                log_wrn!("Response cache is corrupt, rebuilding it");
+                self.rebuild();
+                None
+            }
+            Err(e) => {
+                log_err!("Cache error: {}", e);
+                None
+            }
+        }
+    }
+
+    fn rebuild(&self) {
+        self.hot.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        self.recency
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.stats.bytes.store(0, Ordering::Relaxed);
+        self.stats.entries.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Open the sled DB for cached responses, next to the main one at `<db_path>-responses`.
+/// It only holds what can be fetched again, so if it's corrupt it gets moved out of
+/// the way and we start over with an empty one. API keys and stats stay untouched.
+pub fn open_cache_db(config: &sled::Config) -> Result<Db, sled::Error> {
+    let mut path = config.get_path().into_os_string();
+    path.push("-responses");
+    // A config can't change once it's been cloned, so copy over what the `[sled]` table sets
+    let config = sled::Config::new()
+        .path(&path)
+        .mode(config.mode)
+        .cache_capacity(config.cache_capacity)
+        .use_compression(config.use_compression)
+        .print_profile_on_drop(config.print_profile_on_drop)
+        .flush_every_ms(config.flush_every_ms);
+
+    match config.open() {
+        // A mangled config file shows up as unsupported rather than corrupt
+        Err(sled::Error::Corruption { .. }) | Err(sled::Error::Unsupported(_)) => {
+            let mut aside = path.clone();
+            aside.push(format!(".corrupt-{}", now_millis()));
+
+            log_err!(
+                "Response cache at {} is corrupt, moving it to {} and starting over",
+                path.to_string_lossy(),
+                aside.to_string_lossy()
+            );
+            std::fs::rename(&path, &aside)?;
+            config.open()
+        }
+        result => result,
+    }
+}
+
+/// Expiry, status, ledger version and the Content-Type's length in front of
+/// every stored Content-Type and body
+const HEADER_LEN: usize = 20;
+
+/// Split a stored value back into the response
+fn decode(value: &[u8]) -> Option<CachedResponse> {
+    if value.len() < HEADER_LEN {
+        return None;
+    }
+
+    let ledger_version = u64::from_be_bytes(value[10..18].try_into().unwrap());
+    let content_type_len = u16::from_be_bytes(value[18..20].try_into().unwrap()) as usize;
+    let content_type = value.get(HEADER_LEN..HEADER_LEN + content_type_len)?;
+    Some(CachedResponse {
+        status: u16::from_be_bytes(value[8..10].try_into().unwrap()),
+        body: Bytes::copy_from_slice(&value[HEADER_LEN + content_type_len..]),
+        expires: u64::from_be_bytes(value[..8].try_into().unwrap()),
+        ledger_version: (ledger_version != 0).then_some(ledger_version),
+        content_type: match std::str::from_utf8(content_type) {
+            Ok("") | Err(_) => None,
+            Ok(content_type) => Some(content_type.to_string()),
+        },
+    })
+}
+
+/// Build the cache key for a request. Networks other than the default one
+/// get their name in front, like `testnet:GET /v1/...`. Requests with an `Accept`
+/// header, like BCS ones, get it at the end, after a space.
+pub fn cache_key(namespace: Option<&str>, parts: &Parts, body: &[u8]) -> String {
+    let mut key = match namespace {
+        Some(namespace) => format!("{}:", namespace),
+        None => String::new(),
+    };
+    key.push_str(&format!("{} {}", parts.method, parts.uri.path()));
+    if let Some(query) = parts.uri.query() {
+        key.push('?');
+        key.push_str(query);
+    }
+    if !body.is_empty() {
+        key.push('#');
+        key.push_str(&hash(body));
+    }
+    if let Some(accept) = parts.headers.get(http::header::ACCEPT) {
+        key.push(' ');
+        key.push_str(&String::from_utf8_lossy(accept.as_bytes()));
+    }
+    key
+}
+
+#[cfg(not(feature = "xxhash"))]
+fn hash(body: &[u8]) -> String {
+    blake3::hash(body).to_hex().to_string()
+}
+
+#[cfg(feature = "xxhash")]
+fn hash(body: &[u8]) -> String {
+    format!("{:016x}", xxhash_rust::xxh3::xxh3_64(body))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Failed to get current time")
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::backend::SledBackend;
+    use crate::utils::testing::parts;
+
+    async fn cache(settings: CacheSettings) -> ResponseCache {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        ResponseCache::open(Box::new(SledBackend::open(&db).unwrap()), &settings, 0)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn entries_round_trip() {
+        let cache = cache(CacheSettings::default()).await;
+        cache.insert("GET /v1", 404, Some("application/x-bcs"), b"{}", Some(7), None).await;
+
+        match cache.get("GET /v1", 0).await {
+            Lookup::Fresh(cached) => {
+                assert_eq!(cached.status, 404);
+                assert_eq!(&cached.body[..], b"{}");
+                assert_eq!(cached.expires, 0);
+                assert_eq!(cached.ledger_version, Some(7));
+                assert_eq!(cached.content_type.as_deref(), Some("application/x-bcs"));
+            }
+            lookup => panic!("expected a fresh entry, got {:?}", lookup),
+        }
+        assert!(matches!(cache.get("GET /v2", 0).await, Lookup::Miss));
+
+        assert!(cache.remove("GET /v1").await);
+        assert!(!cache.remove("GET /v1").await);
+        assert!(matches!(cache.get("GET /v1", 0).await, Lookup::Miss));
+    }
+
+    #[tokio::test]
+    async fn expired_entries_go_stale_then_missing() {
+        let cache = cache(CacheSettings::default()).await;
+        cache.insert("GET /v1", 200, None, b"{}", None, Some(0)).await;
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        assert!(matches!(cache.get("GET /v1", 60_000).await, Lookup::Stale(_, _)));
+        assert!(matches!(cache.get("GET /v1", 0).await, Lookup::Miss));
+        // Misses drop it for good
+        assert!(matches!(cache.get("GET /v1", 60_000).await, Lookup::Miss));
+    }
+
+    #[test]
+    fn decode_rejects_short_values() {
+        assert!(decode(&[0; HEADER_LEN - 1]).is_none());
+
+        // Says there's a Content-Type longer than what's left
+        let mut value = vec![0; HEADER_LEN];
+        value[18..20].copy_from_slice(&5u16.to_be_bytes());
+        value.extend_from_slice(b"json");
+        assert!(decode(&value).is_none());
+        value.push(b'!');
+        assert_eq!(decode(&value).unwrap().content_type.as_deref(), Some("json!"));
+    }
+
+    #[test]
+    fn min_version() {
+        let response = |expires, ledger_version| CachedResponse {
+            status: 200,
+            body: Bytes::new(),
+            expires,
+            ledger_version,
+            content_type: None,
+        };
+
+        assert!(response(5, None).satisfies(None));
+        assert!(response(0, None).satisfies(Some(10)));
+        assert!(response(5, Some(10)).satisfies(Some(10)));
+        assert!(!response(5, Some(9)).satisfies(Some(10)));
+        assert!(!response(5, None).satisfies(Some(10)));
+    }
+
+    #[tokio::test]
+    async fn purge_by_prefix() {
+        let cache = cache(CacheSettings::default()).await;
+        for key in ["GET /v1/accounts/0x1", "GET /v1/accounts/0x1/resources", "GET /v1/blocks"] {
+            cache.insert(key, 200, None, b"{}", None, None).await;
+        }
+
+        assert_eq!(cache.purge("GET /v1/accounts/").await, 2);
+        assert!(matches!(cache.get("GET /v1/blocks", 0).await, Lookup::Fresh(_)));
+        assert_eq!(cache.stats()["entries"], 1);
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() {
+        let cache = cache(CacheSettings {
+            max_entries: Some(2),
+            hot_entries: 0,
+            ..Default::default()
+        })
+        .await;
+        cache.insert("a", 200, None, b"", None, None).await;
+        cache.insert("b", 200, None, b"", None, None).await;
+        cache.insert("c", 200, None, b"", None, None).await;
+        // `a` was used last, so `b` goes first
+        cache.get("a", 0).await;
+
+        cache.evict().await;
+        assert!(matches!(cache.get("a", 0).await, Lookup::Fresh(_)));
+        assert!(matches!(cache.get("b", 0).await, Lookup::Miss));
+        assert!(matches!(cache.get("c", 0).await, Lookup::Fresh(_)));
+        assert_eq!(cache.stats()["evictions"], 1);
+    }
+
+    #[tokio::test]
+    async fn evicts_closest_to_expiring() {
+        let cache = cache(CacheSettings {
+            max_entries: Some(2),
+            eviction: Eviction::Ttl,
+            ..Default::default()
+        })
+        .await;
+        cache.insert("forever", 200, None, b"", None, None).await;
+        cache.insert("soon", 200, None, b"", None, Some(60_000)).await;
+        cache.insert("later", 200, None, b"", None, Some(120_000)).await;
+
+        cache.evict().await;
+        assert!(matches!(cache.get("soon", 0).await, Lookup::Miss));
+        assert!(matches!(cache.get("later", 0).await, Lookup::Fresh(_)));
+        assert!(matches!(cache.get("forever", 0).await, Lookup::Fresh(_)));
+    }
+
+    #[test]
+    fn keys() {
+        let get = |uri: &str| parts("GET", uri, &[]);
+
+        assert_eq!(cache_key(None, &get("/v1/blocks/by_height/5"), b""), "GET /v1/blocks/by_height/5");
+        assert_eq!(
+            cache_key(Some("testnet"), &get("/v1/blocks?x=1"), b""),
+            "testnet:GET /v1/blocks?x=1"
+        );
+        let with_body = cache_key(None, &get("/v1/view"), b"{}");
+        assert!(with_body.starts_with("GET /v1/view#"));
+        assert_ne!(with_body, cache_key(None, &get("/v1/view"), b"[]"));
+
+        let bcs = parts("GET", "/v1/blocks/by_height/5", &[("Accept", "application/x-bcs")]);
+        assert_eq!(cache_key(None, &bcs, b""), "GET /v1/blocks/by_height/5 application/x-bcs");
+    }
+
+    #[test]
+    fn corrupt_cache_db_is_replaced_alone() {
+        let dir = std::env::temp_dir().join(format!("trident-test-{}", now_millis()));
+        let config = sled::Config::new().path(dir.join("db"));
+        config.open().unwrap().insert("key", "kept").unwrap();
+
+        std::fs::create_dir_all(dir.join("db-responses")).unwrap();
+        std::fs::write(dir.join("db-responses").join("conf"), b"not a sled config").unwrap();
+
+        open_cache_db(&config).unwrap().insert("entry", "fresh").unwrap();
+        let moved_aside = std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with("db-responses.corrupt-")
+            })
+            .count();
+        assert_eq!(moved_aside, 1);
+        assert_eq!(&config.open().unwrap().get("key").unwrap().unwrap()[..], b"kept");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
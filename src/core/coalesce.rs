@@ -0,0 +1,181 @@
+use crate::utils::rpc::RpcResponse;
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+use tokio::sync::watch;
+
+/// Response of a coalesced request along with the name of the RPC that sent it
+pub type Shared = Arc<(RpcResponse, String)>;
+
+/// Identical reads that are upstream right now, so the ones arriving while they're
+/// in flight can wait for that response instead of sending their own.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    requests: Mutex<HashMap<String, watch::Receiver<Option<Shared>>>>,
+}
+
+/// What a request should do about its upstream call
+pub enum Flight<'a> {
+    // Nothing identical is in flight, so this request goes upstream and shares the result
+    Leader(FlightGuard<'a>),
+    // An identical request already went upstream, this is what it got
+    Shared(Shared),
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lead the request for `key`, or wait for whoever does. If the leader gives up
+    /// without a response, one of the requests waiting on it takes over, so they
+    /// don't all go upstream at once.
+    pub async fn join(&self, key: String) -> Flight<'_> {
+        loop {
+            let rx = {
+                let mut requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+                match requests.get(&key) {
+                    Some(rx) => rx.clone(),
+                    None => {
+                        let (tx, rx) = watch::channel(None);
+                        requests.insert(key.clone(), rx);
+                        return Flight::Leader(FlightGuard {
+                            in_flight: self,
+                            key,
+                            tx,
+                        });
+                    }
+                }
+            };
+
+            if let Some(shared) = wait(rx).await {
+                return Flight::Shared(shared);
+            }
+        }
+    }
+}
+
+/// Held by the request that went upstream. Followers get whatever gets passed to
+/// `finish`. If it's dropped without finishing, the next one in line takes over.
+pub struct FlightGuard<'a> {
+    in_flight: &'a InFlight,
+    key: String,
+    tx: watch::Sender<Option<Shared>>,
+}
+
+impl FlightGuard<'_> {
+    pub fn finish(self, response: &RpcResponse, rpc_name: &str) {
+        self.tx
+            .send_replace(Some(Arc::new((response.clone(), rpc_name.to_string()))));
+    }
+}
+
+impl Drop for FlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut requests = self
+            .in_flight
+            .requests
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        requests.remove(&self.key);
+    }
+}
+
+/// Wait for the leader's response. `None` if it gave up without one.
+async fn wait(mut rx: watch::Receiver<Option<Shared>>) -> Option<Shared> {
+    loop {
+        if let Some(shared) = rx.borrow_and_update().clone() {
+            return Some(shared);
+        }
+        // The leader is gone, but it might have finished right before
+        if rx.changed().await.is_err() {
+            return rx.borrow().clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderMap;
+    use std::time::Duration;
+
+    fn response(body: &'static str) -> RpcResponse {
+        RpcResponse {
+            body: body.into(),
+            status: 200,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn followers_share_the_leaders_response() {
+        let in_flight = InFlight::new();
+        let leader = match in_flight.join("GET /v1".to_string()).await {
+            Flight::Leader(guard) => guard,
+            Flight::Shared(_) => panic!("nothing was in flight"),
+        };
+
+        let follower = in_flight.join("GET /v1".to_string());
+        let finish = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            leader.finish(&response("shared"), "rpc1");
+        };
+        let (flight, _) = tokio::join!(follower, finish);
+
+        match flight {
+            Flight::Shared(shared) => {
+                assert_eq!(&shared.0.body[..], b"shared");
+                assert_eq!(shared.1, "rpc1");
+            }
+            Flight::Leader(_) => panic!("should have followed"),
+        }
+        assert!(in_flight.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn different_keys_dont_wait_on_each_other() {
+        let in_flight = InFlight::new();
+        let _first = in_flight.join("GET /v1|Some(0)".to_string()).await;
+        assert!(matches!(
+            in_flight.join("GET /v1|Some(1)".to_string()).await,
+            Flight::Leader(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn one_follower_takes_over_from_a_failed_leader() {
+        let in_flight = InFlight::new();
+        let leader = match in_flight.join("GET /v1".to_string()).await {
+            Flight::Leader(guard) => guard,
+            Flight::Shared(_) => panic!("nothing was in flight"),
+        };
+
+        let followers = futures::future::join_all((0..3).map(|_| async {
+            match in_flight.join("GET /v1".to_string()).await {
+                // Only one of them should get here, the others wait on it
+                Flight::Leader(guard) => {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    guard.finish(&response("retried"), "rpc2");
+                    true
+                }
+                Flight::Shared(shared) => {
+                    assert_eq!(&shared.0.body[..], b"retried");
+                    false
+                }
+            }
+        }));
+        let fail = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(leader);
+        };
+        let (led, _) = tokio::join!(followers, fail);
+
+        assert_eq!(led.iter().filter(|led| **led).count(), 1);
+    }
+}
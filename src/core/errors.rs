@@ -1,49 +1,138 @@
+use crate::core::body::{
+    full,
+    ResponseBody,
+};
 
-#[macro_export]
-macro_rules! no_rpc_available {
-    () => {
-        Ok(hyper::Response::builder()
-            .status(500)
-            .body(Full::new(Bytes::from(
-                "{code:-32002, message:\"error: No working RPC available! Try again later...\"}"
-                    .to_string(),
-            )))
-            .unwrap())
-    };
+use hyper::{
+    header,
+    StatusCode,
+};
+use serde_json::json;
+
+
+/// Errors trident answers with by itself, as opposed to errors relayed from an RPC.
+///
+/// These get turned into the same shape Aptos nodes use for their REST errors,
+/// so SDKs can parse them like any other API error.
+#[derive(Debug)]
+pub enum TridentError {
+    NoRpcAvailable,
+    TimedOut,
+    RetryBudgetExhausted,
+    Upstream,
+    PayloadTooLarge(usize),
+    BadRequest(String),
+    ResponseTooLarge(usize),
+    RateLimited(u64),
+    Unauthorized,
+    RouteNotAllowed(String),
+    QuotaExceeded(u64),
+    LedgerBehind(u64),
+    QuorumUnavailable(usize),
+    QuorumMismatch,
 }
 
-#[macro_export]
-macro_rules! timed_out {
-    () => {
-        Ok(hyper::Response::builder()
-            .status(408)
-            .body(Full::new(Bytes::from(
-                "{code:-32001, message:\"error: Request timed out! Try again later...\"}"
-                    .to_string(),
-            )))
-            .unwrap())
-    };
+impl TridentError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            TridentError::NoRpcAvailable => StatusCode::SERVICE_UNAVAILABLE,
+            TridentError::TimedOut => StatusCode::GATEWAY_TIMEOUT,
+            TridentError::RetryBudgetExhausted => StatusCode::SERVICE_UNAVAILABLE,
+            TridentError::Upstream => StatusCode::BAD_GATEWAY,
+            TridentError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            TridentError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            TridentError::ResponseTooLarge(_) => StatusCode::BAD_GATEWAY,
+            TridentError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            TridentError::Unauthorized => StatusCode::UNAUTHORIZED,
+            TridentError::RouteNotAllowed(_) => StatusCode::FORBIDDEN,
+            TridentError::QuotaExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+            TridentError::LedgerBehind(_) => StatusCode::SERVICE_UNAVAILABLE,
+            TridentError::QuorumUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            TridentError::QuorumMismatch => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// The `AptosErrorCode` closest to what went wrong
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            TridentError::PayloadTooLarge(_)
+            | TridentError::RateLimited(_)
+            | TridentError::Unauthorized
+            | TridentError::RouteNotAllowed(_)
+            | TridentError::QuotaExceeded(_) => "web_framework_error",
+            TridentError::BadRequest(_) => "invalid_input",
+            _ => "internal_error",
+        }
+    }
+
+    /// Seconds the client should wait before trying again, if it makes sense to retry at all
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            TridentError::NoRpcAvailable
+            | TridentError::RetryBudgetExhausted
+            | TridentError::LedgerBehind(_)
+            | TridentError::QuorumUnavailable(_) => Some(1),
+            TridentError::RateLimited(retry_after) | TridentError::QuotaExceeded(retry_after) => {
+                Some(*retry_after)
+            }
+            _ => None,
+        }
+    }
 }
 
-#[macro_export]
-macro_rules! print_cache_error {
-    () => {
-        println!("\x1b[31m!!! Cache error! Check the DB !!!\x1b[0m");
-        println!("To recover, please stop trident, delete your cache folder, and start trident again.");
-        println!("If the error perists, please open up an issue: https://github.com/kanalabs/trident");
-    };
+impl std::fmt::Display for TridentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TridentError::NoRpcAvailable => write!(f, "No working RPC available! Try again later..."),
+            TridentError::TimedOut => write!(f, "Request timed out! Try again later..."),
+            TridentError::RetryBudgetExhausted => {
+                write!(f, "Too many failing requests, retries are paused! Try again later...")
+            }
+            TridentError::Upstream => write!(f, "Error while calling RPC! Try again later..."),
+            TridentError::PayloadTooLarge(limit) => {
+                write!(f, "Request body is larger than the limit of {} bytes", limit)
+            }
+            TridentError::BadRequest(reason) => write!(f, "Could not read request: {}", reason),
+            TridentError::ResponseTooLarge(limit) => {
+                write!(f, "RPC response is larger than the limit of {} bytes", limit)
+            }
+            TridentError::RateLimited(_) => write!(f, "Rate limit exceeded! Slow down..."),
+            TridentError::Unauthorized => write!(f, "Missing or invalid API key"),
+            TridentError::RouteNotAllowed(path) => {
+                write!(f, "API key is not allowed to access {}", path)
+            }
+            TridentError::QuotaExceeded(_) => write!(f, "API key request quota exceeded"),
+            TridentError::LedgerBehind(version) => {
+                write!(f, "No RPC has caught up to ledger version {} yet", version)
+            }
+            TridentError::QuorumUnavailable(quorum) => {
+                write!(f, "Not enough RPCs available for a quorum of {}", quorum)
+            }
+            TridentError::QuorumMismatch => write!(f, "RPCs disagree on the response"),
+        }
+    }
 }
 
-#[macro_export]
-macro_rules! cache_error {
-    () => {
-        Ok(hyper::Response::builder()
-            .status(500)
-            .body(Full::new(Bytes::from(
-                "{code:-32003, message:\"error: Cache error! Try again later...\"}".to_string(),
-            )))
-            .unwrap())
-    };
+impl std::error::Error for TridentError {}
+
+impl From<TridentError> for hyper::Response<ResponseBody> {
+    fn from(error: TridentError) -> Self {
+        let body = json!({
+            "message": error.to_string(),
+            "error_code": error.error_code(),
+            "vm_error_code": null,
+        });
+
+        let mut response = hyper::Response::builder()
+            .status(error.status())
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*");
+        if let Some(retry_after) = error.retry_after() {
+            response = response.header(header::RETRY_AFTER, retry_after);
+        }
+
+        response.body(full(body.to_string())).unwrap()
+    }
 }
 
 #[macro_export]
@@ -58,3 +147,28 @@ macro_rules! rpc_response {
             .unwrap())
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn upstream_errors_stay_generic() {
+        let response: hyper::Response<ResponseBody> = TridentError::Upstream.into();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["message"], "Error while calling RPC! Try again later...");
+        assert_eq!(body["error_code"], "internal_error");
+    }
+
+    #[test]
+    fn retry_after() {
+        assert_eq!(TridentError::NoRpcAvailable.retry_after(), Some(1));
+        assert_eq!(TridentError::RateLimited(7).retry_after(), Some(7));
+        assert_eq!(TridentError::Upstream.retry_after(), None);
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{
+    core::{
+        algo::pick_where,
+        body::BoxError,
+        budget::Budget,
+    },
+    utils::{
+        aptos::endpoints::RequestClass,
+        rpc::Rpc,
+    },
+};
+
+use http::request::Parts;
+use hyper::body::Bytes;
+use std::{
+    future::Future,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+/// Returns true if the request can safely be sent to more than one RPC.
+///
+/// Only reads and view function calls qualify, submissions must never be duplicated.
+pub fn is_hedgeable(parts: &Parts) -> bool {
+    RequestClass::from_parts(parts) != RequestClass::Submit
+}
+
+/// Sends the request to `rpc` with `send`. If it hasn't answered by the time `percentile`
+/// of its own latency history has passed, the same request is sent to a second RPC and
+/// whichever answers first wins. The losing request gets dropped, cancelling it.
+///
+/// Only RPCs for which `eligible` returns true are considered for the second request.
+///
+/// Returns the result along with the name and position of the RPC that produced it.
+#[allow(clippy::too_many_arguments)]
+pub async fn send_hedged<T, F, Fut, E>(
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    rpc: Rpc,
+    rpc_position: usize,
+    parts: Parts,
+    bytes: Bytes,
+    percentile: f64,
+    budget: &Budget,
+    eligible: E,
+    send: F,
+) -> (Result<T, BoxError>, String, usize)
+where
+    F: Fn(Rpc, Parts, Bytes) -> Fut,
+    Fut: Future<Output = Result<T, BoxError>>,
+    E: Fn(&Rpc) -> bool,
+{
+    budget.deposit();
+
+    let primary = send(rpc.clone(), parts.clone(), bytes.clone());
+    tokio::pin!(primary);
+
+    // No latency history yet, so we can't tell what slow means for this RPC
+    let delay = match rpc.latency_percentile(percentile) {
+        Some(delay) => Duration::from_nanos(delay as u64),
+        None => return (primary.await, rpc.name.clone(), rpc_position),
+    };
+
+    tokio::select! {
+        res = &mut primary => return (res, rpc.name.clone(), rpc_position),
+        _ = tokio::time::sleep(delay) => {}
+    }
+
+    if !budget.try_withdraw() {
+        return (primary.await, rpc.name.clone(), rpc_position);
+    }
+
+    let (hedge, hedge_position) = {
+        let mut rpc_list = rpc_list_rwlock.write().unwrap();
+        pick_where(&mut rpc_list, |candidate| {
+            candidate.url != rpc.url && eligible(candidate)
+        })
+    };
+    let hedge_position = match hedge_position {
+        Some(hedge_position) => hedge_position,
+        None => return (primary.await, rpc.name.clone(), rpc_position),
+    };
+
+    let secondary = send(hedge.clone(), parts, bytes);
+    tokio::pin!(secondary);
+
+    // If one of them errors out, we still give the other one a chance to answer
+    tokio::select! {
+        res = &mut primary => match res {
+            Ok(_) => (res, rpc.name.clone(), rpc_position),
+            Err(_) => (secondary.await, hedge.name.clone(), hedge_position),
+        },
+        res = &mut secondary => match res {
+            Ok(_) => (res, hedge.name.clone(), hedge_position),
+            Err(_) => (primary.await, rpc.name.clone(), rpc_position),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::parts;
+
+    const SLOW: &str = "http://127.0.0.1:9102";
+    const FAST: &str = "http://127.0.0.1:9101";
+
+    fn rpc(url: &str, latency: Option<f64>) -> Rpc {
+        let mut rpc = Rpc::new(url.to_string(), None, 15, 0, 3.0);
+        if let Some(latency) = latency {
+            rpc.restore_latency(vec![latency]);
+        }
+        rpc
+    }
+
+    // The slow RPC takes a while to answer, the fast one answers right away
+    async fn send(rpc: Rpc, _: Parts, _: Bytes) -> Result<String, BoxError> {
+        if rpc.url == SLOW {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        Ok(rpc.url)
+    }
+
+    async fn hedged(primary: Rpc, budget: &Budget) -> (String, String, usize) {
+        let rpc_list = Arc::new(RwLock::new(vec![primary.clone(), rpc(FAST, None)]));
+        let (result, name, position) = send_hedged(
+            &rpc_list,
+            primary,
+            0,
+            parts("GET", "/v1", &[]),
+            Bytes::new(),
+            95.0,
+            budget,
+            |_: &Rpc| true,
+            send,
+        )
+        .await;
+        (result.unwrap(), name, position)
+    }
+
+    #[test]
+    fn only_reads_are_hedged() {
+        assert!(is_hedgeable(&parts("GET", "/v1/accounts/0x1", &[])));
+        assert!(is_hedgeable(&parts("POST", "/v1/view", &[])));
+        assert!(!is_hedgeable(&parts("POST", "/v1/transactions", &[])));
+    }
+
+    #[tokio::test]
+    async fn slow_rpcs_get_raced() {
+        // It usually answers in 1ms, so 500ms is slow
+        let budget = Budget::new(10.0, 1.0);
+        let (url, name, position) = hedged(rpc(SLOW, Some(1_000_000.0)), &budget).await;
+        assert_eq!(url, FAST);
+        assert_eq!(name, rpc(FAST, None).name);
+        assert_eq!(position, 1);
+    }
+
+    #[tokio::test]
+    async fn no_hedge_without_history_or_budget() {
+        let budget = Budget::new(10.0, 1.0);
+        let (url, _, position) = hedged(rpc(SLOW, None), &budget).await;
+        assert_eq!((url.as_str(), position), (SLOW, 0));
+
+        let budget = Budget::new(0.0, 0.0);
+        let (url, _, position) = hedged(rpc(SLOW, Some(1_000_000.0)), &budget).await;
+        assert_eq!((url.as_str(), position), (SLOW, 0));
+    }
+}
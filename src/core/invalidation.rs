@@ -0,0 +1,430 @@
+use crate::{
+    config::types::CacheSettings,
+    core::{
+        algo::pick_where,
+        cache::ResponseCache,
+        networks::Network,
+    },
+    log_info,
+    log_wrn,
+};
+
+use bytes::Bytes;
+use http::Request;
+use serde_json::Value;
+use std::{
+    collections::{
+        BTreeMap,
+        HashSet,
+    },
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{
+    RwLock,
+    RwLockReadGuard,
+};
+
+/// Change types that write to an account's state
+const WRITE_CHANGES: [&str; 4] = [
+    "write_resource",
+    "delete_resource",
+    "write_module",
+    "delete_module",
+];
+
+/// Follows the ledger of a network and remembers which accounts recent
+/// transactions wrote to, so their cached resources and modules can be dropped.
+#[derive(Debug, Default)]
+pub struct Invalidator {
+    head_cache: RwLock<HeadCache>,
+    // Last version whose changes made it into the cache, 0 until we start watching
+    processed: AtomicU64,
+}
+
+/// Recent writes, to tell which responses are out of date before they get cached
+#[derive(Debug, Default)]
+pub struct HeadCache {
+    // Ledger version -> addresses written at it, in long form
+    writes: BTreeMap<u64, Vec<String>>,
+    // Account data served before this version was dropped wholesale
+    purged_at: u64,
+}
+
+impl Invalidator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hold on to this while checking `is_outdated` and caching the response.
+    /// Invalidation waits for it, so it can't run in between.
+    pub async fn read(&self) -> RwLockReadGuard<'_, HeadCache> {
+        self.head_cache.read().await
+    }
+}
+
+impl HeadCache {
+    /// Whether a response served at `version` for `cache_key` is already out of date,
+    /// because a later transaction we've seen wrote to that account.
+    ///
+    /// Those mustn't get cached, or they'd undo the invalidation that ran while
+    /// the request was upstream.
+    pub fn is_outdated(&self, cache_key: &str, version: Option<u64>) -> bool {
+        let (address, version) = match (account_address(cache_key), version) {
+            (Some(address), Some(version)) => (address, version),
+            _ => return false,
+        };
+
+        version < self.purged_at
+            || self
+                .writes
+                .range(version.saturating_add(1)..)
+                .any(|(_, addresses)| addresses.contains(&address))
+    }
+
+    fn record(&mut self, version: u64, addresses: Vec<String>, window: u64) {
+        self.writes.insert(version, addresses);
+
+        // Nothing still in flight was served from that far back
+        let oldest = version.saturating_sub(window);
+        self.writes = self.writes.split_off(&oldest);
+    }
+}
+
+/// Whatever got cached before we started watching `network` could be stale by now.
+/// Has to run before anything gets cached for it.
+pub async fn purge_accounts(network: &Network, cache: &ResponseCache) {
+    let namespace = network.cache_namespace().map(|namespace| format!("{}:", namespace));
+    let purged = cache
+        .purge(&format!("{}GET /v1/accounts/", namespace.unwrap_or_default()))
+        .await;
+    if purged > 0 {
+        log_info!("Purged {} cached account responses on startup", purged);
+    }
+}
+
+/// Watch `network` for new ledger versions and evict cached account data
+/// touched by the transactions that got there.
+pub async fn watch_ledger(network: Network, cache: Arc<ResponseCache>, settings: CacheSettings) {
+    let namespace = network.cache_namespace().map(|namespace| format!("{}:", namespace));
+    let namespace = namespace.unwrap_or_default();
+    let invalidator = &network.invalidator;
+
+    loop {
+        tokio::time::sleep(Duration::from_millis(settings.invalidate_interval)).await;
+
+        // Served from the cache, requests don't tell us where the chain is at anymore
+        let processed = invalidator.processed.load(Ordering::Relaxed);
+        if network.head_version().unwrap_or(0) <= processed {
+            if let Err(e) = refresh_head(&network).await {
+                log_wrn!("Could not get the ledger version for cache invalidation: {}", e);
+            }
+        }
+        let head = match network.head_version() {
+            Some(head) => head,
+            None => continue,
+        };
+
+        // Start from wherever the chain is at when we first hear about it
+        if processed == 0 {
+            invalidator.processed.store(head, Ordering::Relaxed);
+            continue;
+        }
+        if head <= processed {
+            continue;
+        }
+
+        // Too far behind to catch up on, dropping everything is cheaper
+        if head - processed > settings.max_backlog {
+            let mut head_cache = invalidator.head_cache.write().await;
+            head_cache.purged_at = head;
+            let purged = cache.purge(&format!("{}GET /v1/accounts/", namespace)).await;
+            drop(head_cache);
+            log_wrn!(
+                "Invalidation fell {} versions behind, purged {} cached account responses",
+                head - processed,
+                purged
+            );
+            invalidator.processed.store(head, Ordering::Relaxed);
+            continue;
+        }
+
+        if let Err(e) = catch_up(&network, &cache, &settings, &namespace, head).await {
+            log_wrn!("Could not fetch transactions for cache invalidation: {}", e);
+        }
+    }
+}
+
+/// Ask an RPC for the ledger info. It isn't used directly, the headers of
+/// the response update where that RPC is at.
+async fn refresh_head(network: &Network) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (rpc, rpc_position) = {
+        let mut rpc_list = network.rpc_list.write().unwrap();
+        pick_where(&mut rpc_list, |rpc| !rpc.status.is_backing_off())
+    };
+    if rpc_position.is_none() {
+        return Err("no RPC available".into());
+    }
+
+    let (parts, _) = Request::get("/v1").body(())?.into_parts();
+    rpc.send_request(parts, Bytes::new()).await?;
+    Ok(())
+}
+
+/// Process transactions in batches until we've seen all of them up to `head`
+async fn catch_up(
+    network: &Network,
+    cache: &ResponseCache,
+    settings: &CacheSettings,
+    namespace: &str,
+    head: u64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let invalidator = &network.invalidator;
+
+    loop {
+        let start = invalidator.processed.load(Ordering::Relaxed) + 1;
+        if start > head {
+            return Ok(());
+        }
+
+        // Only RPCs that have the versions we're asking for
+        let (rpc, rpc_position) = {
+            let mut rpc_list = network.rpc_list.write().unwrap();
+            pick_where(&mut rpc_list, |rpc| {
+                !rpc.status.is_backing_off()
+                    && rpc.ledger.version().is_some_and(|version| version >= start)
+            })
+        };
+        if rpc_position.is_none() {
+            return Err("no RPC has reached the next version".into());
+        }
+
+        let (parts, _) = Request::get(format!(
+            "/v1/transactions?start={}&limit={}",
+            start, settings.invalidate_batch
+        ))
+        .body(())?
+        .into_parts();
+        let response = rpc.send_request(parts, Bytes::new()).await?;
+        if response.status != 200 {
+            return Err(format!("{} answered with {}", rpc.name, response.status).into());
+        }
+
+        let transactions: Vec<Value> = serde_json::from_slice(&response.body)?;
+        if transactions.is_empty() {
+            return Ok(());
+        }
+
+        let writes: Vec<(u64, Vec<String>)> = transactions
+            .iter()
+            .filter_map(|transaction| {
+                let version = transaction
+                    .get("version")?
+                    .as_str()?
+                    .parse::<u64>()
+                    .ok()?;
+                Some((version, written_addresses(transaction)))
+            })
+            .collect();
+
+        // Recorded before purging and under the same lock, so a response from before
+        // one of these versions can't get cached again once it's been purged
+        let mut head_cache = invalidator.head_cache.write().await;
+        let mut addresses = Vec::new();
+        for (version, written) in &writes {
+            addresses.extend(written.iter().cloned());
+            head_cache.record(*version, written.clone(), settings.max_backlog);
+        }
+        invalidate(cache, namespace, addresses).await;
+        drop(head_cache);
+
+        if let Some(version) = writes.iter().map(|(version, _)| *version).max() {
+            invalidator.processed.fetch_max(version, Ordering::Relaxed);
+        }
+
+        // Would ask for the same batch again otherwise
+        if invalidator.processed.load(Ordering::Relaxed) < start {
+            return Err("transactions came back without usable versions".into());
+        }
+    }
+}
+
+/// Long form addresses of every account the transaction wrote to
+fn written_addresses(transaction: &Value) -> Vec<String> {
+    let changes = match transaction.get("changes").and_then(|changes| changes.as_array()) {
+        Some(changes) => changes,
+        None => return Vec::new(),
+    };
+
+    let addresses: HashSet<String> = changes
+        .iter()
+        .filter(|change| {
+            change
+                .get("type")
+                .and_then(|kind| kind.as_str())
+                .is_some_and(|kind| WRITE_CHANGES.contains(&kind))
+        })
+        .filter_map(|change| change.get("address")?.as_str())
+        .filter_map(long_address)
+        .collect();
+    addresses.into_iter().collect()
+}
+
+/// Drop everything cached about the accounts, under both ways of writing their
+/// addresses. All of them go in one purge, which is one pass over the cache.
+async fn invalidate(cache: &ResponseCache, namespace: &str, mut addresses: Vec<String>) {
+    addresses.sort();
+    addresses.dedup();
+
+    let mut accounts = Vec::with_capacity(addresses.len() * 2);
+    for address in &addresses {
+        accounts.push(format!("{}GET /v1/accounts/{}", namespace, address));
+        accounts.push(format!("{}GET /v1/accounts/{}", namespace, short_address(address)));
+    }
+
+    let prefixes: Vec<String> = accounts
+        .iter()
+        .flat_map(|account| {
+            [
+                // Covers both `/resource/...` and `/resources`, same for modules
+                format!("{}/resource", account),
+                format!("{}/module", account),
+                format!("{}?", account),
+                // The account itself with an Accept header in the key
+                format!("{} ", account),
+            ]
+        })
+        .collect();
+    if !prefixes.is_empty() {
+        cache.purge_all(&prefixes).await;
+    }
+    for account in &accounts {
+        cache.remove(account).await;
+    }
+}
+
+/// `0x1` and `0x00..01` are the same account, compare them as the latter
+fn long_address(address: &str) -> Option<String> {
+    let hex = address.strip_prefix("0x").unwrap_or(address);
+    if hex.is_empty() || hex.len() > 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(format!("0x{:0>64}", hex.to_ascii_lowercase()))
+}
+
+fn short_address(address: &str) -> String {
+    let hex = address.trim_start_matches("0x").trim_start_matches('0');
+    match hex {
+        "" => "0x0".to_string(),
+        hex => format!("0x{}", hex),
+    }
+}
+
+/// Address of the account a cache key is about, if it's about one
+fn account_address(cache_key: &str) -> Option<String> {
+    let (_, rest) = cache_key.split_once("GET /v1/accounts/")?;
+    let end = rest.find(['/', '?', '#', ' ']).unwrap_or(rest.len());
+    long_address(&rest[..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::backend::SledBackend;
+    use crate::core::cache::Lookup;
+    use serde_json::json;
+
+    const ONE: &str = "0x0000000000000000000000000000000000000000000000000000000000000001";
+
+    #[test]
+    fn addresses() {
+        assert_eq!(long_address("0x1").as_deref(), Some(ONE));
+        assert_eq!(long_address("0xAB").unwrap(), format!("0x{:0>64}", "ab"));
+        assert_eq!(long_address("0x"), None);
+        assert_eq!(long_address("0xzz"), None);
+        assert_eq!(short_address(ONE), "0x1");
+        assert_eq!(short_address("0x0"), "0x0");
+
+        assert_eq!(account_address("GET /v1/accounts/0x1/resource/0x1::a::B").as_deref(), Some(ONE));
+        assert_eq!(account_address("testnet:GET /v1/accounts/0x1?ledger_version=5").as_deref(), Some(ONE));
+        assert_eq!(account_address("GET /v1/accounts/0x1 application/x-bcs").as_deref(), Some(ONE));
+        assert_eq!(account_address("GET /v1/blocks/by_height/1"), None);
+    }
+
+    #[test]
+    fn writes_of_a_transaction() {
+        let transaction = json!({
+            "version": "10",
+            "changes": [
+                {"type": "write_resource", "address": "0x1"},
+                {"type": "write_module", "address": ONE},
+                {"type": "write_table_item", "address": "0x2"},
+            ],
+        });
+        assert_eq!(written_addresses(&transaction), vec![ONE.to_string()]);
+        assert!(written_addresses(&json!({"version": "11"})).is_empty());
+    }
+
+    #[test]
+    fn outdated_responses() {
+        let mut head_cache = HeadCache::default();
+        head_cache.record(10, vec![ONE.to_string()], 100);
+
+        let key = "GET /v1/accounts/0x1/resources";
+        assert!(head_cache.is_outdated(key, Some(9)));
+        assert!(!head_cache.is_outdated(key, Some(10)));
+        // Can't tell without a version, and other accounts weren't written to
+        assert!(!head_cache.is_outdated(key, None));
+        assert!(!head_cache.is_outdated("GET /v1/accounts/0x2/resources", Some(9)));
+
+        // Old writes fall out of the window
+        head_cache.record(200, Vec::new(), 100);
+        assert!(!head_cache.is_outdated(key, Some(9)));
+
+        head_cache.purged_at = 150;
+        assert!(head_cache.is_outdated(key, Some(149)));
+        assert!(!head_cache.is_outdated(key, Some(150)));
+    }
+
+    #[tokio::test]
+    async fn invalidating_drops_everything_about_the_account() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cache = ResponseCache::open(
+            Box::new(SledBackend::open(&db).unwrap()),
+            &CacheSettings::default(),
+            0,
+        )
+        .await
+        .unwrap();
+
+        let dropped = [
+            "GET /v1/accounts/0x1",
+            "GET /v1/accounts/0x1 application/x-bcs",
+            "GET /v1/accounts/0x1?ledger_version=5",
+            "GET /v1/accounts/0x1/resources",
+            "GET /v1/accounts/0x1/module/coin",
+        ];
+        let kept = [
+            "GET /v1/accounts/0x10",
+            "GET /v1/accounts/0x1/transactions",
+            "testnet:GET /v1/accounts/0x1",
+        ];
+        for key in dropped.iter().chain(&kept) {
+            cache.insert(key, 200, None, b"{}", None, None).await;
+        }
+
+        invalidate(&cache, "", vec![ONE.to_string()]).await;
+        for key in dropped {
+            assert!(matches!(cache.get(key, 0).await, Lookup::Miss), "{} is still cached", key);
+        }
+        for key in kept {
+            assert!(matches!(cache.get(key, 0).await, Lookup::Fresh(_)), "{} got dropped", key);
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use crate::{
+    config::types::ApiKeySettings,
+    log_err,
+    utils::aptos::endpoints::path_matches,
+};
+
+use chrono::{
+    DateTime,
+    Datelike,
+    NaiveDate,
+    Utc,
+};
+use http::{
+    request::Parts,
+    Uri,
+};
+use rand::Rng;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use serde_json::{
+    json,
+    Map,
+    Value,
+};
+use sled::{
+    transaction::{
+        ConflictableTransactionError,
+        TransactionError,
+        UnabortableTransactionError,
+    },
+    Db,
+    Tree,
+};
+
+/// What a client API key is allowed to do.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ApiKey {
+    // Label for the key, shows up in usage exports
+    #[serde(default)]
+    pub name: String,
+    // Max requests per UTC day, `None` for unlimited
+    #[serde(default)]
+    pub daily_quota: Option<u64>,
+    // Max requests per UTC month, `None` for unlimited
+    #[serde(default)]
+    pub monthly_quota: Option<u64>,
+    // Path patterns the key may call, see `path_matches`. Empty allows everything.
+    #[serde(default)]
+    pub routes: Vec<String>,
+    // Only send this key's requests to RPCs in this group
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+impl ApiKey {
+    pub fn allows(&self, path: &str) -> bool {
+        self.routes.is_empty() || self.routes.iter().any(|route| path_matches(route, path))
+    }
+}
+
+/// API keys and their usage counters, stored in sled.
+///
+/// Usage is kept under `<key>/d/<YYYY-MM-DD>` and `<key>/m/<YYYY-MM>` as big endian
+/// counters, so old periods stay around for exports.
+#[derive(Debug)]
+pub struct ApiKeys {
+    keys: Tree,
+    usage: Tree,
+}
+
+impl ApiKeys {
+    pub fn open(db: &Db) -> Result<Self, sled::Error> {
+        Ok(Self {
+            keys: db.open_tree("api_keys")?,
+            usage: db.open_tree("api_key_usage")?,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<ApiKey> {
+        let raw = self.keys.get(key).ok()??;
+        serde_json::from_slice(&raw).ok()
+    }
+
+    /// Store `api_key` under `key`, or under a newly generated key if `None`.
+    /// Returns the key it was stored under.
+    pub fn insert(&self, key: Option<String>, api_key: &ApiKey) -> Result<String, sled::Error> {
+        let key = key.unwrap_or_else(generate_key);
+        self.keys
+            .insert(key.as_bytes(), serde_json::to_vec(api_key).unwrap())?;
+        Ok(key)
+    }
+
+    /// Remove a key along with its usage history. Returns false if it didn't exist.
+    pub fn remove(&self, key: &str) -> Result<bool, sled::Error> {
+        let existed = self.keys.remove(key)?.is_some();
+        for entry in self.usage.scan_prefix(format!("{}/", key)).keys() {
+            self.usage.remove(entry?)?;
+        }
+        Ok(existed)
+    }
+
+    pub fn list(&self) -> Vec<(String, ApiKey)> {
+        self.keys
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter_map(|(key, value)| {
+                Some((
+                    String::from_utf8(key.to_vec()).ok()?,
+                    serde_json::from_slice(&value).ok()?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Count a request against the key's quotas.
+    ///
+    /// If a quota is used up, nothing gets counted and we return the number of
+    /// seconds until it resets instead. The check and the count happen in one
+    /// transaction, so concurrent requests can't push usage past a quota.
+    pub fn record_usage(&self, key: &str, api_key: &ApiKey) -> Result<(), u64> {
+        let now = Utc::now();
+        let day = format!("{}/d/{}", key, now.format("%Y-%m-%d"));
+        let month = format!("{}/m/{}", key, now.format("%Y-%m"));
+
+        let result = self.usage.transaction(|usage| {
+            let count = |counter: &str| -> Result<u64, UnabortableTransactionError> {
+                Ok(usage.get(counter)?.map(|value| decode_count(&value)).unwrap_or(0))
+            };
+            let daily = count(&day)? + 1;
+            let monthly = count(&month)? + 1;
+
+            if api_key.daily_quota.is_some_and(|quota| daily > quota) {
+                let tomorrow = now.date_naive().succ_opt().unwrap();
+                return Err(ConflictableTransactionError::Abort(seconds_until(now, tomorrow)));
+            }
+            if api_key.monthly_quota.is_some_and(|quota| monthly > quota) {
+                let next_month = if now.month() == 12 {
+                    NaiveDate::from_ymd_opt(now.year() + 1, 1, 1)
+                } else {
+                    NaiveDate::from_ymd_opt(now.year(), now.month() + 1, 1)
+                }
+                .unwrap();
+                return Err(ConflictableTransactionError::Abort(seconds_until(now, next_month)));
+            }
+
+            usage.insert(day.as_bytes(), &daily.to_be_bytes())?;
+            usage.insert(month.as_bytes(), &monthly.to_be_bytes())?;
+            Ok(())
+        });
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(TransactionError::Abort(retry_after)) => Err(retry_after),
+            // Don't turn clients away because we couldn't count them
+            Err(TransactionError::Storage(e)) => {
+                log_err!("Could not update API key usage: {}", e);
+                Ok(())
+            }
+        }
+    }
+
+    /// Export usage counters for every key, or just `key` if set.
+    ///
+    /// Looks like `{"<key>": {"name": ..., "daily": {"2024-01-31": 12}, "monthly": {"2024-01": 340}}}`.
+    pub fn usage(&self, key: Option<&str>) -> Value {
+        let mut export = Map::new();
+
+        let keys = match key {
+            Some(key) => self
+                .get(key)
+                .map(|api_key| vec![(key.to_string(), api_key)])
+                .unwrap_or_default(),
+            None => self.list(),
+        };
+
+        for (key, api_key) in keys {
+            let mut daily = Map::new();
+            let mut monthly = Map::new();
+
+            for entry in self.usage.scan_prefix(format!("{}/", key)) {
+                let (entry_key, value) = match entry {
+                    Ok(entry) => entry,
+                    Err(_) => continue,
+                };
+                let entry_key = String::from_utf8_lossy(&entry_key[key.len() + 1..]).to_string();
+                let count = json!(decode_count(&value));
+
+                match entry_key.split_once('/') {
+                    Some(("d", period)) => daily.insert(period.to_string(), count),
+                    Some(("m", period)) => monthly.insert(period.to_string(), count),
+                    _ => continue,
+                };
+            }
+
+            export.insert(
+                key,
+                json!({
+                    "name": api_key.name,
+                    "daily": daily,
+                    "monthly": monthly,
+                }),
+            );
+        }
+
+        Value::Object(export)
+    }
+}
+
+impl ApiKeySettings {
+    /// Get the API key the client sent, if any.
+    ///
+    /// Keys sent as a `/key/<key>/...` path prefix get stripped from the URI so the
+    /// request can be forwarded as is. The header wins if both are present.
+    pub fn extract(&self, parts: &mut Parts) -> Option<String> {
+        let mut from_path = None;
+
+        if self.path_prefix {
+            if let Some(rest) = parts.uri.path().strip_prefix("/key/") {
+                let (key, path) = match rest.find('/') {
+                    Some(split) => rest.split_at(split),
+                    None => (rest, "/"),
+                };
+                let path_and_query = match parts.uri.query() {
+                    Some(query) => format!("{}?{}", path, query),
+                    None => path.to_string(),
+                };
+
+                if let Ok(uri) = path_and_query.parse::<Uri>() {
+                    from_path = Some(key.to_string());
+                    parts.uri = uri;
+                }
+            }
+        }
+
+        parts
+            .headers
+            .get(self.header.as_str())
+            .and_then(|key| key.to_str().ok())
+            .map(|key| key.to_string())
+            .or(from_path)
+    }
+}
+
+fn generate_key() -> String {
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_count(value: &[u8]) -> u64 {
+    value.try_into().map(u64::from_be_bytes).unwrap_or(0)
+}
+
+fn seconds_until(now: DateTime<Utc>, date: NaiveDate) -> u64 {
+    let reset = date.and_hms_opt(0, 0, 0).unwrap().and_utc();
+    (reset - now).num_seconds().max(1) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::parts;
+    use std::sync::Arc;
+
+    fn api_keys() -> ApiKeys {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        ApiKeys::open(&db).unwrap()
+    }
+
+    #[test]
+    fn daily_quota_is_enforced() {
+        let keys = api_keys();
+        let api_key = ApiKey {
+            daily_quota: Some(2),
+            ..Default::default()
+        };
+
+        assert!(keys.record_usage("abc", &api_key).is_ok());
+        assert!(keys.record_usage("abc", &api_key).is_ok());
+        assert!(keys.record_usage("abc", &api_key).is_err());
+        // Other keys have their own counters
+        assert!(keys.record_usage("def", &api_key).is_ok());
+
+        let usage = keys.usage(None);
+        assert_eq!(usage, json!({}));
+        keys.insert(Some("abc".to_string()), &api_key).unwrap();
+        let usage = keys.usage(Some("abc"));
+        let daily = usage["abc"]["daily"].as_object().unwrap();
+        assert_eq!(daily.values().next(), Some(&json!(2)));
+    }
+
+    #[test]
+    fn concurrent_requests_stay_within_quota() {
+        let keys = Arc::new(api_keys());
+        let api_key = ApiKey {
+            monthly_quota: Some(50),
+            ..Default::default()
+        };
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let keys = Arc::clone(&keys);
+                let api_key = api_key.clone();
+                std::thread::spawn(move || {
+                    (0..20)
+                        .filter(|_| keys.record_usage("abc", &api_key).is_ok())
+                        .count()
+                })
+            })
+            .collect();
+        let allowed: usize = threads.into_iter().map(|thread| thread.join().unwrap()).sum();
+        assert_eq!(allowed, 50);
+    }
+
+    #[test]
+    fn remove_drops_usage() {
+        let keys = api_keys();
+        let key = keys.insert(None, &ApiKey::default()).unwrap();
+        assert_eq!(key.len(), 32);
+        keys.record_usage(&key, &ApiKey::default()).unwrap();
+
+        assert!(keys.remove(&key).unwrap());
+        assert!(!keys.remove(&key).unwrap());
+        assert!(keys.get(&key).is_none());
+        assert_eq!(keys.usage.scan_prefix(&key).count(), 0);
+    }
+
+    #[test]
+    fn allowed_routes() {
+        let api_key = ApiKey {
+            routes: vec!["/v1/accounts/**".to_string()],
+            ..Default::default()
+        };
+        assert!(api_key.allows("/v1/accounts/0x1/resources"));
+        assert!(!api_key.allows("/v1/transactions"));
+        assert!(ApiKey::default().allows("/v1/transactions"));
+    }
+
+    #[test]
+    fn extract_from_header_or_path() {
+        let settings = ApiKeySettings {
+            path_prefix: true,
+            ..Default::default()
+        };
+
+        let mut in_path = parts("GET", "/key/abc/v1/accounts/0x1?ledger_version=5", &[]);
+        assert_eq!(settings.extract(&mut in_path), Some("abc".to_string()));
+        assert_eq!(in_path.uri, "/v1/accounts/0x1?ledger_version=5");
+
+        let mut in_header = parts("GET", "/key/abc/v1", &[("X-API-Key", "def")]);
+        assert_eq!(settings.extract(&mut in_header), Some("def".to_string()));
+        assert_eq!(in_header.uri, "/v1");
+    }
+}
@@ -1,4 +1,21 @@
 pub mod accept_incoming;
 pub mod processing;
-mod errors;
+pub mod errors;
 pub mod algo;
+pub mod body;
+pub mod budget;
+pub mod hedge;
+pub mod ratelimit;
+pub mod keys;
+pub mod routes;
+pub mod cache;
+pub mod networks;
+pub mod sessions;
+pub mod quorum;
+pub mod shadow;
+pub mod coalesce;
+pub mod invalidation;
+pub mod backend;
+pub mod warm;
+pub mod upstream_stats;
+pub mod shutdown;
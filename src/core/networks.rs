@@ -0,0 +1,153 @@
+use crate::{
+    core::invalidation::Invalidator,
+    Rpc,
+};
+
+use http::{
+    header,
+    request::Parts,
+    Uri,
+};
+use std::sync::{Arc, RwLock};
+
+/// An Aptos network and the RPCs serving it.
+///
+/// Every network keeps its own lists, so health checks and latency stats
+/// of one never affect another.
+#[derive(Debug, Clone)]
+pub struct Network {
+    // `None` for the default network
+    pub name: Option<String>,
+    pub chain_id: Option<u32>,
+    pub path_prefix: Option<String>,
+    pub hosts: Vec<String>,
+    pub rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    pub poverty_list: Arc<RwLock<Vec<Rpc>>>,
+    // Accounts recent transactions wrote to, for cache invalidation
+    pub invalidator: Arc<Invalidator>,
+}
+
+impl Network {
+    /// Prefix that keeps this network's cache entries apart from the others
+    pub fn cache_namespace(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Highest ledger version any of our RPCs is known to be at
+    pub fn head_version(&self) -> Option<u64> {
+        let rpc_list = self.rpc_list.read().unwrap();
+        rpc_list.iter().filter_map(|rpc| rpc.ledger.version()).max()
+    }
+
+    /// If the path starts with our prefix, strip it and return true
+    fn strip_prefix(&self, parts: &mut Parts) -> bool {
+        let prefix = match &self.path_prefix {
+            Some(prefix) => prefix,
+            None => return false,
+        };
+
+        let rest = match parts.uri.path().strip_prefix(prefix.as_str()) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+            _ => return false,
+        };
+
+        let path_and_query = match (rest, parts.uri.query()) {
+            ("", Some(query)) => format!("/?{}", query),
+            ("", None) => "/".to_string(),
+            (rest, Some(query)) => format!("{}?{}", rest, query),
+            (rest, None) => rest.to_string(),
+        };
+        match path_and_query.parse::<Uri>() {
+            Ok(uri) => {
+                parts.uri = uri;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn serves_host(&self, parts: &Parts) -> bool {
+        let host = parts
+            .headers
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .or(parts.uri.host());
+        // Ignore the port, if any
+        let host = match host {
+            Some(host) => host.rsplit_once(':').map_or(host, |(host, _)| host),
+            None => return false,
+        };
+
+        self.hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host))
+    }
+}
+
+/// Figure out which network a request is for and return its index.
+///
+/// Connections on a network's own listener always go to it. Otherwise we
+/// go by path prefix, which gets stripped, then by `Host` header. Anything
+/// left over goes to the default network at index 0.
+pub fn select_network(networks: &[Network], listener: Option<usize>, parts: &mut Parts) -> usize {
+    if let Some(listener) = listener {
+        return listener;
+    }
+
+    if let Some(index) = networks.iter().position(|network| network.strip_prefix(parts)) {
+        return index;
+    }
+
+    networks
+        .iter()
+        .position(|network| network.serves_host(parts))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::{
+        network,
+        parts,
+    };
+
+    fn networks() -> Vec<Network> {
+        vec![
+            network(Some("mainnet")),
+            Network {
+                path_prefix: Some("/testnet".to_string()),
+                hosts: vec!["testnet.example.com".to_string()],
+                ..network(Some("testnet"))
+            },
+        ]
+    }
+
+    #[test]
+    fn prefixes_get_stripped() {
+        let mut request = parts("GET", "/testnet/v1/accounts/0x1?ledger_version=5", &[]);
+        assert_eq!(select_network(&networks(), None, &mut request), 1);
+        assert_eq!(request.uri, "/v1/accounts/0x1?ledger_version=5");
+
+        let mut request = parts("GET", "/testnet", &[]);
+        assert_eq!(select_network(&networks(), None, &mut request), 1);
+        assert_eq!(request.uri, "/");
+
+        // Only whole segments count
+        let mut request = parts("GET", "/testnet2/v1", &[]);
+        assert_eq!(select_network(&networks(), None, &mut request), 0);
+        assert_eq!(request.uri, "/testnet2/v1");
+    }
+
+    #[test]
+    fn hosts_and_listeners() {
+        let mut request = parts("GET", "/v1", &[("Host", "TESTNET.example.com:8080")]);
+        assert_eq!(select_network(&networks(), None, &mut request), 1);
+
+        let mut request = parts("GET", "/v1", &[("Host", "mainnet.example.com")]);
+        assert_eq!(select_network(&networks(), None, &mut request), 0);
+
+        // A network's own listener beats everything else
+        let mut request = parts("GET", "/testnet/v1", &[]);
+        assert_eq!(select_network(&networks(), Some(0), &mut request), 0);
+        assert_eq!(request.uri, "/testnet/v1");
+    }
+}
@@ -2,7 +2,6 @@ use crate::Rpc;
 
 use std::{
     collections::BTreeMap,
-    println,
     sync::{Arc, RwLock},
     time::Duration,
 };
@@ -51,3 +50,23 @@ pub fn update_rpc_latency(rpc_list: &Arc<RwLock<Vec<Rpc>>>, rpc_position: usize,
         // println!("LA {}", rpc_list_guard[index].status.latency);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_updates() {
+        let rpc = Rpc::new("http://127.0.0.1:9101".to_string(), None, 15, 0, 3.0);
+        let rpc_list = Arc::new(RwLock::new(vec![rpc.clone(), rpc]));
+
+        update_rpc_latency(&rpc_list, 1, Duration::from_micros(5));
+        // Positions past the end land on the last RPC
+        update_rpc_latency(&rpc_list, 7, Duration::from_micros(7));
+
+        let rpc_list = rpc_list.read().unwrap();
+        assert_eq!(rpc_list[0].status.latency_data, Vec::<f64>::new());
+        assert_eq!(rpc_list[1].status.latency_data, vec![5_000.0, 7_000.0]);
+        assert_eq!(rpc_list[1].last_used, 7);
+    }
+}
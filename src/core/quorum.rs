@@ -0,0 +1,336 @@
+use crate::{
+    core::{
+        algo::argsort,
+        errors::TridentError,
+    },
+    log_wrn,
+    utils::{
+        aptos::ledger::pin_to_version,
+        rpc::{
+            Rpc,
+            RpcResponse,
+        },
+    },
+};
+
+use bytes::Bytes;
+use futures::future::join_all;
+use http::request::Parts;
+use serde_json::{
+    json,
+    Map,
+    Value,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        Mutex,
+        RwLock,
+    },
+    time::Duration,
+};
+use tokio::time::timeout;
+
+/// How often each RPC disagreed with the rest of a quorum or didn't answer in time, by name
+#[derive(Debug, Default)]
+pub struct QuorumStats {
+    counts: Mutex<HashMap<String, RpcCounts>>,
+}
+
+#[derive(Debug, Default)]
+struct RpcCounts {
+    divergences: u64,
+    timeouts: u64,
+}
+
+impl QuorumStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record(&self, rpc_name: &str, update: impl FnOnce(&mut RpcCounts)) {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        update(counts.entry(rpc_name.to_string()).or_default());
+    }
+
+    /// Looks like `{"<rpc name>": {"divergences": 3, "timeouts": 1}}`
+    pub fn export(&self) -> Value {
+        let counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let export: Map<String, Value> = counts
+            .iter()
+            .map(|(name, counts)| {
+                (
+                    name.clone(),
+                    json!({
+                        "divergences": counts.divergences,
+                        "timeouts": counts.timeouts,
+                    }),
+                )
+            })
+            .collect();
+        Value::Object(export)
+    }
+}
+
+/// Send the request to `quorum` RPCs pinned to the same ledger version, at least
+/// `min_version`, and only return the response if all of them agree on it.
+///
+/// RPCs that disagree with the majority get recorded in `stats` as diverged, the ones
+/// that don't answer in `ttl` as timed out. Returns the response along with the names
+/// of the RPCs used.
+#[allow(clippy::too_many_arguments)]
+pub async fn quorum_read<E>(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    quorum: usize,
+    eligible: E,
+    min_version: Option<u64>,
+    mut parts: Parts,
+    bytes: Bytes,
+    ttl: Duration,
+    stats: &QuorumStats,
+) -> Result<(RpcResponse, String), TridentError>
+where
+    E: Fn(&Rpc) -> bool,
+{
+    // The fastest RPCs we're allowed to use
+    let rpcs: Vec<Rpc> = {
+        let list = rpc_list.read().unwrap();
+        argsort(&list)
+            .into_iter()
+            .map(|i| &list[i])
+            .filter(|rpc| eligible(rpc) && !rpc.status.is_backing_off())
+            .take(quorum)
+            .cloned()
+            .collect()
+    };
+    if rpcs.len() < quorum {
+        return Err(TridentError::QuorumUnavailable(quorum));
+    }
+
+    // Everyone has to answer from the same point in time, otherwise they'd disagree
+    // on anything that changed in between. The lowest version we know of is one
+    // all of them have reached, but it can't be older than what the client has seen.
+    let version = rpcs.iter().filter_map(|rpc| rpc.ledger.version()).min();
+    if let Some(version) = version.max(min_version) {
+        pin_to_version(&mut parts, version);
+    }
+
+    let responses = join_all(rpcs.iter().map(|rpc| {
+        let request = rpc.send_request(parts.clone(), bytes.clone());
+        async move {
+            match timeout(ttl, request).await {
+                Ok(Ok(response)) => Answer::Response(response),
+                Ok(Err(_)) => Answer::Failed,
+                Err(_) => Answer::TimedOut,
+            }
+        }
+    }))
+    .await;
+
+    let mut unanswered = Vec::new();
+    for (rpc, answer) in rpcs.iter().zip(&responses) {
+        match answer {
+            Answer::Response(_) => continue,
+            Answer::TimedOut => stats.record(&rpc.name, |counts| counts.timeouts += 1),
+            Answer::Failed => {}
+        }
+        unanswered.push(rpc.name.as_str());
+    }
+
+    let answers: Vec<Option<(u16, Vec<u8>)>> = responses
+        .iter()
+        .map(|answer| match answer {
+            Answer::Response(response) => Some((response.status, normalize(&response.body))),
+            _ => None,
+        })
+        .collect();
+
+    match vote(&answers) {
+        Vote::Agreed if unanswered.is_empty() => {}
+        Vote::Agreed => {
+            log_wrn!(
+                "Quorum read of {} got no answer from: {}",
+                parts.uri,
+                unanswered.join(", ")
+            );
+            return Err(TridentError::QuorumUnavailable(quorum));
+        }
+        Vote::Diverged(diverged) => {
+            let diverged: Vec<&str> = diverged.into_iter().map(|i| rpcs[i].name.as_str()).collect();
+            for rpc_name in &diverged {
+                stats.record(rpc_name, |counts| counts.divergences += 1);
+            }
+            log_wrn!("Quorum read of {} diverged on: {}", parts.uri, diverged.join(", "));
+            return Err(TridentError::QuorumMismatch);
+        }
+    }
+
+    let names = rpcs
+        .iter()
+        .map(|rpc| rpc.name.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+    let response = responses
+        .into_iter()
+        .find_map(|answer| match answer {
+            Answer::Response(response) => Some(response),
+            _ => None,
+        })
+        .ok_or(TridentError::QuorumUnavailable(quorum))?;
+    Ok((response, names))
+}
+
+enum Answer {
+    Response(RpcResponse),
+    Failed,
+    TimedOut,
+}
+
+#[derive(Debug, PartialEq)]
+enum Vote {
+    // Everyone who answered said the same thing
+    Agreed,
+    // Indexes of the answers that differ from the majority
+    Diverged(Vec<usize>),
+}
+
+/// Whatever most RPCs that answered said is what the odd ones out diverged from.
+/// Without a majority we can't tell who's wrong, so all of them are. Missing
+/// answers don't count either way.
+fn vote(answers: &[Option<(u16, Vec<u8>)>]) -> Vote {
+    let answered = answers.iter().flatten().count();
+    let mut counts: Vec<(&(u16, Vec<u8>), usize)> = Vec::new();
+    for answer in answers.iter().flatten() {
+        match counts.iter_mut().find(|(seen, _)| *seen == answer) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((answer, 1)),
+        }
+    }
+    let majority = counts
+        .iter()
+        .find(|(_, count)| count * 2 > answered)
+        .map(|(majority, _)| *majority);
+
+    let diverged: Vec<usize> = answers
+        .iter()
+        .enumerate()
+        .filter(|(_, answer)| answer.is_some() && (majority.is_none() || answer.as_ref() != majority))
+        .map(|(i, _)| i)
+        .collect();
+    match diverged.is_empty() {
+        true => Vote::Agreed,
+        false => Vote::Diverged(diverged),
+    }
+}
+
+/// JSON bodies get reserialized, which sorts object keys and drops whitespace,
+/// so formatting differences between node versions don't count as disagreement.
+/// Anything else is compared byte for byte.
+pub fn normalize(body: &Bytes) -> Vec<u8> {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(value) => serde_json::to_vec(&value).unwrap_or_else(|_| body.to_vec()),
+        Err(_) => body.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::{
+        parts,
+        upstream,
+    };
+
+    fn answer(body: &str) -> Option<(u16, Vec<u8>)> {
+        Some((200, normalize(&Bytes::copy_from_slice(body.as_bytes()))))
+    }
+
+    #[test]
+    fn formatting_doesnt_count_as_disagreement() {
+        assert_eq!(
+            normalize(&Bytes::from_static(b"{\"b\": 1, \"a\": [1, 2]}")),
+            normalize(&Bytes::from_static(b"{\"a\":[1,2],\"b\":1}"))
+        );
+        assert_eq!(normalize(&Bytes::from_static(b"not json ")), b"not json ");
+    }
+
+    #[test]
+    fn votes() {
+        assert_eq!(vote(&[answer("1"), answer("1"), answer("1")]), Vote::Agreed);
+        assert_eq!(vote(&[answer("1"), answer("2"), answer("1")]), Vote::Diverged(vec![1]));
+        // No majority, so nobody can be trusted
+        assert_eq!(vote(&[answer("1"), answer("2")]), Vote::Diverged(vec![0, 1]));
+        // Same body but another status is a different answer
+        assert_eq!(
+            vote(&[answer("1"), Some((500, b"1".to_vec())), answer("1")]),
+            Vote::Diverged(vec![1])
+        );
+    }
+
+    #[test]
+    fn missing_answers_arent_divergences() {
+        assert_eq!(vote(&[answer("1"), None, None]), Vote::Agreed);
+        assert_eq!(vote(&[None, answer("1"), answer("2"), answer("2")]), Vote::Diverged(vec![1]));
+    }
+
+    #[tokio::test]
+    async fn nobody_answering_isnt_a_response() {
+        let result = quorum_read(
+            &Arc::new(RwLock::new(Vec::new())),
+            0,
+            |_: &Rpc| true,
+            None,
+            parts("GET", "/v1", &[]),
+            Bytes::new(),
+            Duration::from_secs(1),
+            &QuorumStats::new(),
+        )
+        .await;
+        assert!(matches!(result, Err(TridentError::QuorumUnavailable(0))));
+    }
+
+    #[tokio::test]
+    async fn old_errors_dont_keep_rpcs_out() {
+        let mut rpc = Rpc::new(upstream("{\"a\":1}").await, None, 15, 0, 3.0);
+        // Failed a while ago and hasn't won a request since
+        rpc.status.is_erroring = true;
+        rpc.status.last_error = 0;
+        let rpc_list = Arc::new(RwLock::new(vec![rpc]));
+        let stats = QuorumStats::new();
+
+        let read = |rpc_list| {
+            quorum_read(
+                rpc_list,
+                1,
+                |_: &Rpc| true,
+                None,
+                parts("GET", "/v1", &[]),
+                Bytes::new(),
+                Duration::from_secs(5),
+                &stats,
+            )
+        };
+        let (response, _) = read(&rpc_list).await.unwrap();
+        assert_eq!(response.body, Bytes::from("{\"a\":1}"));
+
+        rpc_list.write().unwrap()[0].status.mark_erroring();
+        assert!(matches!(read(&rpc_list).await, Err(TridentError::QuorumUnavailable(1))));
+    }
+
+    #[test]
+    fn stats() {
+        let stats = QuorumStats::new();
+        stats.record("a", |counts| counts.divergences += 1);
+        stats.record("a", |counts| counts.timeouts += 1);
+        stats.record("b", |counts| counts.timeouts += 1);
+
+        assert_eq!(
+            stats.export(),
+            json!({
+                "a": {"divergences": 1, "timeouts": 1},
+                "b": {"divergences": 0, "timeouts": 1},
+            })
+        );
+    }
+}
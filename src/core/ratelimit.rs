@@ -0,0 +1,315 @@
+use crate::{
+    config::types::{
+        ClientKey,
+        Limit,
+        RateLimitSettings,
+    },
+    utils::aptos::endpoints::RequestClass,
+};
+
+use http::{
+    request::Parts,
+    HeaderMap,
+    HeaderValue,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::Instant,
+};
+
+/// Result of a rate limit check, used to fill in the `X-RateLimit-*` headers.
+#[derive(Debug, Clone)]
+pub struct RateLimitStatus {
+    pub limit: u64,
+    pub remaining: u64,
+    // Seconds until the bucket is full again
+    pub reset: u64,
+    // Seconds until the next request is allowed, if this one wasn't
+    pub retry_after: Option<u64>,
+}
+
+impl RateLimitStatus {
+    pub fn apply_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("X-RateLimit-Limit", HeaderValue::from(self.limit));
+        headers.insert("X-RateLimit-Remaining", HeaderValue::from(self.remaining));
+        headers.insert("X-RateLimit-Reset", HeaderValue::from(self.reset));
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    limit: Limit,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst);
+        self.last_refill = now;
+    }
+}
+
+/// Most clients we keep buckets for. Past this, new clients share one bucket
+/// until pruning makes room, so spoofed identities can't grow the map.
+const MAX_BUCKETS: usize = 100_000;
+
+/// Bucket shared by clients that didn't get one of their own
+const OVERFLOW: &str = "";
+
+/// Token bucket rate limiter keyed by client and request class.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<(String, RequestClass), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take a token from the client's bucket for `class`.
+    pub fn check(&self, client: &str, class: RequestClass, limit: Limit) -> RateLimitStatus {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut key = (client.to_string(), class);
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&key) {
+            prune_full(&mut buckets, now);
+            if buckets.len() >= MAX_BUCKETS {
+                key.0 = OVERFLOW.to_string();
+            }
+        }
+
+        let bucket = buckets
+            .entry(key)
+            .or_insert(Bucket {
+                tokens: limit.burst,
+                last_refill: now,
+                limit,
+            });
+        // Limits might have changed since the bucket was created
+        bucket.limit = limit;
+        bucket.refill(now);
+
+        let retry_after = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(((1.0 - bucket.tokens) / limit.per_second).ceil() as u64)
+        };
+
+        RateLimitStatus {
+            limit: limit.burst as u64,
+            remaining: bucket.tokens.floor() as u64,
+            reset: ((limit.burst - bucket.tokens) / limit.per_second).ceil() as u64,
+            retry_after,
+        }
+    }
+
+    /// Drop buckets that have filled back up, as they're the same as a fresh one.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        prune_full(&mut buckets, now);
+    }
+}
+
+fn prune_full(buckets: &mut HashMap<(String, RequestClass), Bucket>, now: Instant) {
+    buckets.retain(|_, bucket| {
+        bucket.refill(now);
+        bucket.tokens < bucket.limit.burst
+    });
+}
+
+impl RateLimitSettings {
+    pub fn limit_for(&self, class: RequestClass) -> Option<Limit> {
+        match class {
+            RequestClass::Read => self.read,
+            RequestClass::View => self.view,
+            RequestClass::Submit => self.submit,
+        }
+    }
+
+    /// Figure out who is sending the request. Falls back to the IP address
+    /// if the configured key isn't present or can't be trusted.
+    ///
+    /// `api_key` has to be a key we know about, otherwise clients could pick
+    /// a new bucket for every request.
+    pub fn client_id(
+        &self,
+        parts: &Parts,
+        remote_addr: Option<SocketAddr>,
+        api_key: Option<&str>,
+    ) -> String {
+        let key = match &self.key {
+            ClientKey::Ip => None,
+            ClientKey::ApiKey => api_key.map(|key| format!("key/{}", key)),
+            // Only our proxy can be trusted to set it
+            ClientKey::Header(name) if self.trust_forwarded_for => parts
+                .headers
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(|value| format!("header/{}", value)),
+            ClientKey::Header(_) => None,
+        };
+        if let Some(key) = key {
+            return key;
+        }
+
+        // Behind a load balancer, the socket address is the balancer's. Each proxy
+        // appends the address it got the request from, so only the last
+        // `trusted_proxies` entries are real, the rest can be anything.
+        if self.trust_forwarded_for {
+            let forwarded: Vec<&str> = parts
+                .headers
+                .get_all("X-Forwarded-For")
+                .iter()
+                .filter_map(|forwarded| forwarded.to_str().ok())
+                .flat_map(|forwarded| forwarded.split(','))
+                .map(|forwarded| forwarded.trim())
+                .collect();
+            if let Some(forwarded) = forwarded
+                .len()
+                .checked_sub(self.trusted_proxies)
+                .and_then(|index| forwarded.get(index))
+                .filter(|forwarded| !forwarded.is_empty())
+            {
+                return forwarded.to_string();
+            }
+        }
+
+        remote_addr
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::parts;
+
+    fn settings(key: ClientKey, trust_forwarded_for: bool) -> RateLimitSettings {
+        RateLimitSettings {
+            enabled: true,
+            key,
+            trust_forwarded_for,
+            ..Default::default()
+        }
+    }
+
+    fn remote() -> Option<SocketAddr> {
+        Some("10.0.0.1:1234".parse().unwrap())
+    }
+
+    #[test]
+    fn bucket_runs_out_after_burst() {
+        let limiter = RateLimiter::new();
+        let limit = Limit {
+            per_second: 1.0,
+            burst: 3.0,
+        };
+
+        for remaining in [2, 1, 0] {
+            let status = limiter.check("client", RequestClass::Read, limit);
+            assert_eq!(status.retry_after, None);
+            assert_eq!(status.remaining, remaining);
+        }
+        let status = limiter.check("client", RequestClass::Read, limit);
+        assert_eq!(status.retry_after, Some(1));
+
+        // Other clients and classes have their own buckets
+        assert!(limiter.check("other", RequestClass::Read, limit).retry_after.is_none());
+        assert!(limiter.check("client", RequestClass::View, limit).retry_after.is_none());
+    }
+
+    #[test]
+    fn prune_drops_full_buckets() {
+        let limiter = RateLimiter::new();
+        let limit = Limit {
+            per_second: 1.0,
+            burst: 1.0,
+        };
+        limiter.check("client", RequestClass::Read, limit);
+        limiter.buckets.lock().unwrap().insert(
+            ("idle".to_string(), RequestClass::Read),
+            Bucket {
+                tokens: 1.0,
+                last_refill: Instant::now(),
+                limit,
+            },
+        );
+
+        limiter.prune();
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.contains_key(&("client".to_string(), RequestClass::Read)));
+        assert!(!buckets.contains_key(&("idle".to_string(), RequestClass::Read)));
+    }
+
+    #[test]
+    fn new_clients_share_a_bucket_once_full() {
+        let limiter = RateLimiter::new();
+        let limit = Limit {
+            per_second: 0.001,
+            burst: 2.0,
+        };
+        {
+            let mut buckets = limiter.buckets.lock().unwrap();
+            for i in 0..MAX_BUCKETS {
+                buckets.insert(
+                    (i.to_string(), RequestClass::Read),
+                    Bucket {
+                        tokens: 0.0,
+                        last_refill: Instant::now(),
+                        limit,
+                    },
+                );
+            }
+        }
+
+        assert!(limiter.check("a", RequestClass::Read, limit).retry_after.is_none());
+        assert!(limiter.check("b", RequestClass::Read, limit).retry_after.is_none());
+        assert!(limiter.check("c", RequestClass::Read, limit).retry_after.is_some());
+        assert_eq!(limiter.buckets.lock().unwrap().len(), MAX_BUCKETS + 1);
+    }
+
+    #[test]
+    fn forwarded_for_uses_trusted_hops() {
+        let parts = parts("GET", "/v1", &[("X-Forwarded-For", "1.1.1.1, 2.2.2.2, 3.3.3.3")]);
+
+        let untrusted = settings(ClientKey::Ip, false);
+        assert_eq!(untrusted.client_id(&parts, remote(), None), "10.0.0.1");
+
+        let one_proxy = settings(ClientKey::Ip, true);
+        assert_eq!(one_proxy.client_id(&parts, remote(), None), "3.3.3.3");
+
+        let two_proxies = RateLimitSettings {
+            trusted_proxies: 2,
+            ..settings(ClientKey::Ip, true)
+        };
+        assert_eq!(two_proxies.client_id(&parts, remote(), None), "2.2.2.2");
+
+        let too_many = RateLimitSettings {
+            trusted_proxies: 4,
+            ..settings(ClientKey::Ip, true)
+        };
+        assert_eq!(too_many.client_id(&parts, remote(), None), "10.0.0.1");
+    }
+
+    #[test]
+    fn client_keys_need_to_be_trusted() {
+        let parts = parts("GET", "/v1", &[("X-Client-Id", "me")]);
+
+        let api_key = settings(ClientKey::ApiKey, false);
+        assert_eq!(api_key.client_id(&parts, remote(), Some("abc")), "key/abc");
+        assert_eq!(api_key.client_id(&parts, remote(), None), "10.0.0.1");
+
+        let header = ClientKey::Header("X-Client-Id".to_string());
+        assert_eq!(settings(header.clone(), false).client_id(&parts, remote(), None), "10.0.0.1");
+        assert_eq!(settings(header, true).client_id(&parts, remote(), None), "header/me");
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{
+    config::types::RouteRule,
+    utils::aptos::endpoints::path_matches,
+};
+
+use http::request::Parts;
+
+impl RouteRule {
+    /// True if every condition set on the rule holds for the request
+    pub fn matches(&self, parts: &Parts) -> bool {
+        if let Some(method) = &self.method {
+            if parts.method.as_str() != method {
+                return false;
+            }
+        }
+
+        if let Some(path) = &self.path {
+            if !path_matches(path, parts.uri.path()) {
+                return false;
+            }
+        }
+
+        self.headers.iter().all(|(name, value)| {
+            parts
+                .headers
+                .get(name.as_str())
+                .is_some_and(|header| header.as_bytes() == value.as_bytes())
+        })
+    }
+}
+
+/// Find the first `[[route]]` rule that matches the request, along with its index
+pub fn match_route<'a>(routes: &'a [RouteRule], parts: &Parts) -> Option<(usize, &'a RouteRule)> {
+    routes.iter().enumerate().find(|(_, route)| route.matches(parts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::parts;
+
+    #[test]
+    fn every_condition_has_to_hold() {
+        let rule = RouteRule {
+            method: Some("POST".to_string()),
+            path: Some("/v1/view".to_string()),
+            headers: vec![("X-Team".to_string(), "indexer".to_string())],
+            ..Default::default()
+        };
+
+        assert!(rule.matches(&parts("POST", "/v1/view", &[("X-Team", "indexer")])));
+        assert!(!rule.matches(&parts("GET", "/v1/view", &[("X-Team", "indexer")])));
+        assert!(!rule.matches(&parts("POST", "/v1/views", &[("X-Team", "indexer")])));
+        assert!(!rule.matches(&parts("POST", "/v1/view", &[("X-Team", "wallet")])));
+        assert!(!rule.matches(&parts("POST", "/v1/view", &[])));
+
+        // No conditions match everything
+        assert!(RouteRule::default().matches(&parts("DELETE", "/anything", &[])));
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let routes = vec![
+            RouteRule {
+                path: Some("/v1/accounts/*".to_string()),
+                max_retries: Some(1),
+                ..Default::default()
+            },
+            RouteRule {
+                path: Some("/v1/**".to_string()),
+                max_retries: Some(2),
+                ..Default::default()
+            },
+        ];
+
+        let (index, route) = match_route(&routes, &parts("GET", "/v1/accounts/0x1", &[])).unwrap();
+        assert_eq!((index, route.max_retries), (0, Some(1)));
+
+        let (index, _) = match_route(&routes, &parts("GET", "/v1/blocks/by_height/1", &[])).unwrap();
+        assert_eq!(index, 1);
+
+        assert!(match_route(&routes, &parts("GET", "/health", &[])).is_none());
+    }
+}
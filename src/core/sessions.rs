@@ -0,0 +1,226 @@
+use crate::{
+    config::types::{
+        SessionKey,
+        SessionSettings,
+    },
+    core::cache::Recency,
+};
+
+use http::{
+    header,
+    request::Parts,
+};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+struct Session {
+    // Highest ledger version we've answered this session with
+    version: u64,
+    last_seen: Instant,
+}
+
+/// Most sessions we keep track of. Cookie sessions get created by any client that
+/// shows up without one, so past this the least recently seen one makes room.
+const MAX_SESSIONS: usize = 100_000;
+
+#[derive(Debug, Default)]
+struct Sessions {
+    by_id: HashMap<String, Session>,
+    // Least recently seen first, so the oldest can go without looking at all of them
+    recency: Recency,
+}
+
+impl Sessions {
+    fn remove(&mut self, id: &str) {
+        self.by_id.remove(id);
+        self.recency.remove(id);
+    }
+}
+
+/// Remembers the highest ledger version each client session has been served,
+/// so later reads don't go back in time.
+#[derive(Debug, Default)]
+pub struct SessionTracker {
+    sessions: Mutex<Sessions>,
+}
+
+impl SessionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lowest ledger version we can serve the session at
+    pub fn min_version(&self, session: &str) -> Option<u64> {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.by_id.get(session).map(|session| session.version)
+    }
+
+    pub fn observe(&self, session: &str, version: u64) {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        if sessions.recency.len() >= MAX_SESSIONS && !sessions.recency.contains(session) {
+            if let Some(oldest) = sessions.recency.oldest() {
+                sessions.remove(&oldest);
+            }
+        }
+
+        let entry = sessions.by_id.entry(session.to_string()).or_insert(Session {
+            version,
+            last_seen: Instant::now(),
+        });
+        entry.version = entry.version.max(version);
+        entry.last_seen = Instant::now();
+        sessions.recency.touch(session);
+    }
+
+    /// Forget sessions we haven't seen in `idle`
+    pub fn prune(&self, idle: Duration) {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        while let Some(oldest) = sessions.recency.oldest() {
+            if sessions.by_id[&oldest].last_seen.elapsed() < idle {
+                break;
+            }
+            sessions.remove(&oldest);
+        }
+    }
+}
+
+/// The session a request belongs to
+#[derive(Debug)]
+pub struct SessionId {
+    pub id: String,
+    // `Set-Cookie` value to send back if we just started the session
+    pub set_cookie: Option<String>,
+}
+
+impl SessionSettings {
+    /// Figure out which session the request belongs to.
+    ///
+    /// With cookie sessions, clients without one get a new session and a cookie for it.
+    /// Other requests without the configured key don't belong to any session.
+    pub fn session_id(&self, parts: &Parts, api_key: Option<&str>) -> Option<SessionId> {
+        let id = match &self.key {
+            SessionKey::ApiKey => api_key.map(|key| key.to_string()),
+            SessionKey::Header(name) => parts
+                .headers
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string()),
+            SessionKey::Cookie(name) => {
+                let cookie = parts
+                    .headers
+                    .get_all(header::COOKIE)
+                    .iter()
+                    .filter_map(|cookies| cookies.to_str().ok())
+                    .flat_map(|cookies| cookies.split(';'))
+                    .filter_map(|cookie| cookie.trim().split_once('='))
+                    .find(|(cookie, _)| cookie == name)
+                    .map(|(_, value)| value.to_string());
+
+                return Some(match cookie {
+                    Some(id) => SessionId {
+                        id,
+                        set_cookie: None,
+                    },
+                    None => {
+                        let bytes: [u8; 16] = rand::thread_rng().gen();
+                        let id: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+                        SessionId {
+                            set_cookie: Some(format!(
+                                "{}={}; Path=/; HttpOnly; Secure; SameSite=Lax",
+                                name, id
+                            )),
+                            id,
+                        }
+                    }
+                });
+            }
+        };
+
+        id.map(|id| SessionId {
+            id,
+            set_cookie: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::parts;
+
+    #[test]
+    fn versions_only_go_up() {
+        let tracker = SessionTracker::new();
+        assert_eq!(tracker.min_version("a"), None);
+
+        tracker.observe("a", 10);
+        tracker.observe("a", 5);
+        assert_eq!(tracker.min_version("a"), Some(10));
+
+        tracker.prune(Duration::ZERO);
+        assert_eq!(tracker.min_version("a"), None);
+    }
+
+    #[test]
+    fn oldest_session_makes_room() {
+        let tracker = SessionTracker::new();
+        for i in 0..MAX_SESSIONS {
+            tracker.observe(&i.to_string(), 1);
+        }
+
+        // Known sessions don't push anyone out, but count as seen again
+        tracker.observe("0", 2);
+        assert_eq!(tracker.sessions.lock().unwrap().by_id.len(), MAX_SESSIONS);
+
+        tracker.observe("new", 3);
+        let sessions = tracker.sessions.lock().unwrap();
+        assert_eq!(sessions.by_id.len(), MAX_SESSIONS);
+        assert_eq!(sessions.recency.len(), MAX_SESSIONS);
+        drop(sessions);
+        assert_eq!(tracker.min_version("new"), Some(3));
+        assert_eq!(tracker.min_version("0"), Some(2));
+        assert_eq!(tracker.min_version("1"), None);
+    }
+
+    #[test]
+    fn prune_stops_at_recent_sessions() {
+        let tracker = SessionTracker::new();
+        tracker.observe("old", 1);
+        std::thread::sleep(Duration::from_millis(100));
+        tracker.observe("new", 1);
+
+        tracker.prune(Duration::from_millis(50));
+        assert_eq!(tracker.min_version("old"), None);
+        assert_eq!(tracker.min_version("new"), Some(1));
+        assert_eq!(tracker.sessions.lock().unwrap().recency.len(), 1);
+    }
+
+    #[test]
+    fn cookie_sessions() {
+        let settings = SessionSettings {
+            enabled: true,
+            key: SessionKey::Cookie("trident_session".to_string()),
+            idle_timeout: 600,
+        };
+
+        let cookie = parts("GET", "/v1/accounts/0x1", &[("Cookie", "other=1; trident_session=abc")]);
+        let existing = settings.session_id(&cookie, None).unwrap();
+        assert_eq!(existing.id, "abc");
+        assert!(existing.set_cookie.is_none());
+
+        let new = settings.session_id(&parts("GET", "/v1/accounts/0x1", &[]), None).unwrap();
+        assert_eq!(new.id.len(), 32);
+        assert_eq!(
+            new.set_cookie.unwrap(),
+            format!(
+                "trident_session={}; Path=/; HttpOnly; Secure; SameSite=Lax",
+                new.id
+            )
+        );
+    }
+}
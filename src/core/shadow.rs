@@ -0,0 +1,239 @@
+use crate::{
+    config::types::ShadowSettings,
+    core::{
+        hedge::is_hedgeable,
+        networks::Network,
+        quorum::normalize,
+    },
+    log_wrn,
+    utils::{
+        aptos::ledger::{
+            pin_to_version,
+            LedgerVersioned,
+        },
+        rpc::{
+            Rpc,
+            RpcResponse,
+        },
+    },
+};
+
+use bytes::Bytes;
+use http::request::Parts;
+use rand::Rng;
+use serde_json::{
+    json,
+    Value,
+};
+use std::{
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::time::timeout;
+
+/// How the shadow's answers compared to the ones clients actually got
+#[derive(Debug, Default)]
+struct ShadowStats {
+    samples: u64,
+    // Requests that failed or timed out on the shadow
+    errors: u64,
+    status_mismatches: u64,
+    body_mismatches: u64,
+    // Summed up in microseconds, for averages
+    shadow_latency: u128,
+    primary_latency: u128,
+}
+
+/// Candidate node that gets a sample of live reads mirrored to it, so it can be
+/// compared against the RPCs we use before promoting it to the `rpc_list`.
+///
+/// Its responses never make it back to clients.
+#[derive(Debug)]
+pub struct Shadow {
+    // `None` if shadowing is off
+    rpc: Option<Rpc>,
+    sample: f64,
+    ttl: Duration,
+    network: Option<String>,
+    stats: Mutex<ShadowStats>,
+}
+
+impl Shadow {
+    pub fn new(settings: &ShadowSettings) -> Self {
+        Self {
+            // Never goes through the selection algos, so those settings don't matter
+            rpc: settings
+                .enabled
+                .then(|| Rpc::new(settings.url.clone(), None, 0, 0, 1.0)),
+            sample: settings.sample,
+            ttl: Duration::from_millis(settings.ttl),
+            network: settings.network.clone(),
+            stats: Mutex::new(ShadowStats::default()),
+        }
+    }
+
+    /// Whether a copy of this request should go to the shadow. Submissions never do.
+    pub fn should_mirror(&self, network: &Network, parts: &Parts) -> bool {
+        self.rpc.is_some()
+            && network.name == self.network
+            && is_hedgeable(parts)
+            && rand::thread_rng().gen_bool((self.sample / 100.0).clamp(0.0, 1.0))
+    }
+
+    /// Send the request to the shadow in the background and compare what it answers
+    /// with `primary`, which took `primary_latency` to come back.
+    ///
+    /// The shadow gets pinned to the ledger version `primary` was served at, so it
+    /// only differs if the shadow is actually wrong.
+    pub fn mirror(
+        self: &Arc<Self>,
+        mut parts: Parts,
+        bytes: Bytes,
+        primary: &RpcResponse,
+        primary_latency: Duration,
+    ) {
+        let rpc = match &self.rpc {
+            Some(rpc) => rpc.clone(),
+            None => return,
+        };
+        if let Some(version) = primary.ledger_version() {
+            pin_to_version(&mut parts, version);
+        }
+
+        let shadow = Arc::clone(self);
+        let primary_status = primary.status;
+        let primary_body = normalize(&primary.body);
+
+        tokio::task::spawn(async move {
+            let path = parts.uri.to_string();
+            let time = Instant::now();
+            let result = timeout(shadow.ttl, rpc.send_request(parts, bytes)).await;
+            let latency = time.elapsed();
+
+            let mut stats = shadow.stats.lock().unwrap_or_else(|e| e.into_inner());
+            stats.samples += 1;
+            stats.primary_latency += primary_latency.as_micros();
+
+            let response = match result {
+                Ok(Ok(response)) => response,
+                _ => {
+                    stats.errors += 1;
+                    return;
+                }
+            };
+            stats.shadow_latency += latency.as_micros();
+
+            if response.status != primary_status {
+                stats.status_mismatches += 1;
+                log_wrn!(
+                    "Shadow answered {} with {} instead of {}",
+                    path,
+                    response.status,
+                    primary_status
+                );
+            } else if normalize(&response.body) != primary_body {
+                stats.body_mismatches += 1;
+                log_wrn!("Shadow answered {} with a different body", path);
+            }
+        });
+    }
+
+    /// Looks like `{"url": ..., "samples": 120, "errors": 0, "status_mismatches": 1, ...}`.
+    /// Latencies are averages in ms.
+    pub fn export(&self) -> Value {
+        let rpc = match &self.rpc {
+            Some(rpc) => rpc,
+            None => return Value::Null,
+        };
+        let stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+
+        let average = |total: u128, count: u64| match count {
+            0 => None,
+            count => Some(total as f64 / count as f64 / 1000.0),
+        };
+
+        json!({
+            "url": rpc.name,
+            "samples": stats.samples,
+            "errors": stats.errors,
+            "status_mismatches": stats.status_mismatches,
+            "body_mismatches": stats.body_mismatches,
+            "shadow_latency": average(stats.shadow_latency, stats.samples - stats.errors),
+            "primary_latency": average(stats.primary_latency, stats.samples),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::{
+        network,
+        parts,
+        upstream,
+    };
+    use http::HeaderMap;
+
+    fn shadow(url: &str, enabled: bool, sample: f64) -> Arc<Shadow> {
+        Arc::new(Shadow::new(&ShadowSettings {
+            enabled,
+            url: url.to_string(),
+            sample,
+            ttl: 5000,
+            network: None,
+        }))
+    }
+
+    fn primary(body: &'static str) -> RpcResponse {
+        RpcResponse {
+            body: Bytes::from(body),
+            status: 200,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    #[test]
+    fn what_gets_mirrored() {
+        let url = "http://127.0.0.1:9110";
+        let read = parts("GET", "/v1/accounts/0x1", &[]);
+
+        assert!(shadow(url, true, 100.0).should_mirror(&network(None), &read));
+        assert!(!shadow(url, true, 0.0).should_mirror(&network(None), &read));
+        assert!(!shadow(url, false, 100.0).should_mirror(&network(None), &read));
+        assert!(!shadow(url, true, 100.0).should_mirror(&network(Some("testnet")), &read));
+        let write = parts("POST", "/v1/transactions", &[]);
+        assert!(!shadow(url, true, 100.0).should_mirror(&network(None), &write));
+        assert_eq!(shadow(url, false, 100.0).export(), Value::Null);
+    }
+
+    #[tokio::test]
+    async fn answers_get_compared() {
+        let shadow = shadow(&upstream("{\"a\":1}").await, true, 100.0);
+
+        // Same body, formatted differently
+        let read = || parts("GET", "/v1", &[]);
+        shadow.mirror(read(), Bytes::new(), &primary("{ \"a\": 1 }"), Duration::from_millis(2));
+        shadow.mirror(read(), Bytes::new(), &primary("{\"a\":2}"), Duration::from_millis(4));
+
+        let mut stats = shadow.export();
+        for _ in 0..100 {
+            if stats["samples"] == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            stats = shadow.export();
+        }
+
+        assert_eq!(stats["samples"], 2);
+        assert_eq!(stats["errors"], 0);
+        assert_eq!(stats["status_mismatches"], 0);
+        assert_eq!(stats["body_mismatches"], 1);
+        assert_eq!(stats["primary_latency"], 3.0);
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::{
+    atomic::{
+        AtomicUsize,
+        Ordering,
+    },
+    Arc,
+};
+use tokio::sync::{
+    watch,
+    Notify,
+};
+
+/// Tells listeners and open connections when we're shutting down, and keeps
+/// count of the connections so we know when they've drained.
+#[derive(Debug)]
+pub struct Shutdown {
+    // Flips to true once we've been asked to stop
+    stopping: watch::Sender<bool>,
+    open: AtomicUsize,
+    drained: Notify,
+}
+
+/// Held for as long as a connection is open
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    shutdown: Arc<Shutdown>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if self.shutdown.open.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shutdown.drained.notify_waiters();
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            stopping: watch::channel(false).0,
+            open: AtomicUsize::new(0),
+            drained: Notify::new(),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.stopping.send_replace(true);
+    }
+
+    /// Resolves once `trigger` gets called
+    pub fn stopping(&self) -> impl std::future::Future<Output = ()> {
+        let mut rx = self.stopping.subscribe();
+        async move {
+            let _ = rx.wait_for(|stopping| *stopping).await;
+        }
+    }
+
+    pub fn connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.open.fetch_add(1, Ordering::AcqRel);
+        ConnectionGuard {
+            shutdown: Arc::clone(self),
+        }
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.open.load(Ordering::Acquire)
+    }
+
+    /// Resolves once every connection is closed
+    pub async fn drained(&self) {
+        loop {
+            // Register before checking, so a close in between isn't missed
+            let notified = self.drained.notified();
+            if self.open_connections() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Resolves once we get SIGTERM or SIGINT
+pub async fn wait_for_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{
+            signal,
+            SignalKind,
+        };
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = terminate.recv() => Ok(()),
+            result = tokio::signal::ctrl_c() => result,
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn stopping_resolves_on_trigger() {
+        let shutdown = Shutdown::new();
+        let stopping = shutdown.stopping();
+        tokio::pin!(stopping);
+        assert!(timeout(Duration::from_millis(20), &mut stopping).await.is_err());
+
+        shutdown.trigger();
+        assert!(timeout(Duration::from_secs(1), stopping).await.is_ok());
+        // Late subscribers see it too
+        assert!(timeout(Duration::from_secs(1), shutdown.stopping()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn drains_once_connections_close() {
+        let shutdown = Arc::new(Shutdown::new());
+        assert!(timeout(Duration::from_secs(1), shutdown.drained()).await.is_ok());
+
+        let first = shutdown.connection();
+        let second = shutdown.connection();
+        assert_eq!(shutdown.open_connections(), 2);
+
+        let drained = {
+            let shutdown = Arc::clone(&shutdown);
+            tokio::spawn(async move { shutdown.drained().await })
+        };
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!drained.is_finished());
+
+        drop(second);
+        assert!(timeout(Duration::from_secs(1), drained).await.is_ok());
+        assert_eq!(shutdown.open_connections(), 0);
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{
+    core::networks::Network,
+    log_err,
+    Rpc,
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use sled::{
+    Db,
+    Tree,
+};
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+/// What we knew about an RPC the last time its stats were saved
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedRpc {
+    // In ms since the epoch
+    pub saved_at: u64,
+    pub latency_data: Vec<f64>,
+    // Was in the poverty list
+    pub is_erroring: bool,
+}
+
+/// Latency history and health of every upstream, saved in sled so a restart
+/// doesn't have to measure everything from scratch.
+///
+/// Entries are keyed `<network>/<url>`, with an empty network name for the
+/// default one. URLs aren't sanitized, since two RPCs can share a host.
+#[derive(Debug)]
+pub struct UpstreamStats {
+    tree: Tree,
+}
+
+impl UpstreamStats {
+    pub fn open(db: &Db) -> Result<Self, sled::Error> {
+        Ok(Self {
+            tree: db.open_tree("upstream_stats")?,
+        })
+    }
+
+    /// Save the stats of every RPC on every network
+    pub fn save(&self, networks: &[Network]) {
+        let saved_at = now_millis();
+
+        for network in networks {
+            let rpcs: Vec<Rpc> = {
+                let rpc_list = network.rpc_list.read().unwrap();
+                let poverty_list = network.poverty_list.read().unwrap();
+                rpc_list.iter().chain(poverty_list.iter()).cloned().collect()
+            };
+
+            for rpc in rpcs {
+                let saved = SavedRpc {
+                    saved_at,
+                    latency_data: rpc.status.latency_data.clone(),
+                    is_erroring: rpc.status.is_erroring,
+                };
+                let key = stats_key(network.name.as_deref(), &rpc);
+                if let Err(e) = self
+                    .tree
+                    .insert(key.as_bytes(), serde_json::to_vec(&saved).unwrap())
+                {
+                    log_err!("Could not save stats for {}: {}", rpc.name, e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Saved stats of `rpc`, unless they're older than `max_age` ms
+    pub fn load(&self, network: Option<&str>, rpc: &Rpc, max_age: u64) -> Option<SavedRpc> {
+        let raw = self.tree.get(stats_key(network, rpc)).ok()??;
+        let saved: SavedRpc = serde_json::from_slice(&raw).ok()?;
+        (now_millis().saturating_sub(saved.saved_at) <= max_age).then_some(saved)
+    }
+}
+
+fn stats_key(network: Option<&str>, rpc: &Rpc) -> String {
+    format!("{}/{}", network.unwrap_or_default(), rpc.url)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Failed to get current time")
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::network;
+    use std::sync::{
+        Arc,
+        RwLock,
+    };
+
+    fn rpc(port: u16, latency_data: Vec<f64>) -> Rpc {
+        let mut rpc = Rpc::new(format!("http://127.0.0.1:{}", port), None, 15, 0, 5.0);
+        rpc.restore_latency(latency_data);
+        rpc
+    }
+
+    fn with_rpcs(name: Option<&str>, rpc_list: Vec<Rpc>, poverty_list: Vec<Rpc>) -> Network {
+        Network {
+            rpc_list: Arc::new(RwLock::new(rpc_list)),
+            poverty_list: Arc::new(RwLock::new(poverty_list)),
+            ..network(name)
+        }
+    }
+
+    #[test]
+    fn saved_per_network() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let stats = UpstreamStats::open(&db).unwrap();
+
+        let mut down = rpc(9102, vec![5.0]);
+        down.status.is_erroring = true;
+        stats.save(&[
+            with_rpcs(None, vec![rpc(9101, vec![1.0, 2.0])], vec![down.clone()]),
+            with_rpcs(Some("testnet"), vec![rpc(9101, vec![9.0])], Vec::new()),
+        ]);
+
+        let saved = stats.load(None, &rpc(9101, Vec::new()), 60_000).unwrap();
+        assert_eq!(saved.latency_data, vec![1.0, 2.0]);
+        assert!(!saved.is_erroring);
+        assert!(stats.load(None, &down, 60_000).unwrap().is_erroring);
+
+        let saved = stats.load(Some("testnet"), &rpc(9101, Vec::new()), 60_000).unwrap();
+        assert_eq!(saved.latency_data, vec![9.0]);
+        assert!(stats.load(Some("testnet"), &down, 60_000).is_none());
+    }
+
+    #[test]
+    fn old_stats_are_ignored() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let stats = UpstreamStats::open(&db).unwrap();
+
+        let old = SavedRpc {
+            saved_at: now_millis() - 10_000,
+            latency_data: vec![1.0],
+            is_erroring: false,
+        };
+        let rpc = rpc(9101, Vec::new());
+        stats
+            .tree
+            .insert(stats_key(None, &rpc), serde_json::to_vec(&old).unwrap())
+            .unwrap();
+
+        assert!(stats.load(None, &rpc, 60_000).is_some());
+        assert!(stats.load(None, &rpc, 1_000).is_none());
+    }
+}
@@ -0,0 +1,339 @@
+use crate::{
+    config::types::WarmRequest,
+    core::{
+        algo::pick_where,
+        cache::{
+            cache_key,
+            ResponseCache,
+        },
+        networks::{
+            select_network,
+            Network,
+        },
+        routes::match_route,
+    },
+    log_dbg,
+    log_info,
+    log_wrn,
+    utils::{
+        aptos::{
+            endpoints::{
+                is_immutable,
+                is_pending_transaction,
+            },
+            ledger::LedgerVersioned,
+        },
+        rpc::Rpc,
+    },
+    Settings,
+};
+
+use bytes::Bytes;
+use http::{
+    header,
+    request::Parts,
+    Request,
+};
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+use tokio::time::timeout;
+
+/// Longest we wait for an RPC to have room for another warming request
+const MAX_WAIT: Duration = Duration::from_secs(1);
+
+/// A warm request, ready to send to the network it's for
+struct Prepared {
+    network: usize,
+    parts: Parts,
+    body: Bytes,
+    key: String,
+    ttl: Option<u64>,
+}
+
+/// Prefetch the `[cache] warm` requests on boot, then again whenever a network
+/// has moved `warm_every` versions past the last time.
+pub async fn warm_cache(
+    networks: Arc<Vec<Network>>,
+    config: Arc<RwLock<Settings>>,
+    cache: Arc<ResponseCache>,
+) {
+    let (warm, warm_every, ttl) = {
+        let config_guard = config.read().unwrap();
+        (
+            config_guard.cache.warm.clone(),
+            config_guard.cache.warm_every,
+            Duration::from_millis(config_guard.ttl as u64),
+        )
+    };
+    let requests = prepare(&networks, &config, &warm);
+
+    // Ledger version each network was at when we last warmed it
+    let mut warmed_at: Vec<Option<u64>> = Vec::with_capacity(networks.len());
+    for (index, network) in networks.iter().enumerate() {
+        warm_network(network, index, &requests, &cache, ttl).await;
+        warmed_at.push(network.head_version());
+    }
+
+    let warm_every = match warm_every {
+        Some(warm_every) => warm_every,
+        None => return,
+    };
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        for (index, network) in networks.iter().enumerate() {
+            let head = match network.head_version() {
+                Some(head) => head,
+                None => continue,
+            };
+            match warmed_at[index] {
+                Some(at) if head < at.saturating_add(warm_every) => {}
+                // We didn't know where it was at on boot, so count from here
+                None => warmed_at[index] = Some(head),
+                Some(_) => {
+                    warm_network(network, index, &requests, &cache, ttl).await;
+                    warmed_at[index] = Some(head);
+                }
+            }
+        }
+    }
+}
+
+/// Figure out the network, cache key and TTL of every warm request, the same
+/// way we would if a client sent it. Requests routes don't cache are skipped.
+fn prepare(
+    networks: &[Network],
+    config: &RwLock<Settings>,
+    warm: &[WarmRequest],
+) -> Vec<Prepared> {
+    let config_guard = config.read().unwrap();
+
+    warm.iter()
+        .filter_map(|request| {
+            let mut builder = Request::builder()
+                .method(request.method.as_str())
+                .uri(request.path.as_str());
+            if !request.body.is_empty() {
+                builder = builder.header(header::CONTENT_TYPE, "application/json");
+            }
+            let (mut parts, _) = match builder.body(()) {
+                Ok(request) => request.into_parts(),
+                Err(e) => {
+                    log_wrn!("Not warming {}: {}", request.path, e);
+                    return None;
+                }
+            };
+
+            let network = select_network(networks, None, &mut parts);
+            let route = match_route(&config_guard.routes, &parts).map(|(_, route)| route);
+            let cached = route
+                .and_then(|route| route.cache)
+                .unwrap_or_else(|| is_immutable(&parts));
+            if cfg!(feature = "no-cache") || !cached {
+                log_wrn!("Not warming {}, its route isn't cached", request.path);
+                return None;
+            }
+
+            let body = Bytes::from(request.body.clone());
+            Some(Prepared {
+                network,
+                key: cache_key(networks[network].cache_namespace(), &parts, &body),
+                ttl: route.and_then(|route| route.cache_ttl),
+                parts,
+                body,
+            })
+        })
+        .collect()
+}
+
+/// Send every warm request for the network at `index`, one at a time
+async fn warm_network(
+    network: &Network,
+    index: usize,
+    requests: &[Prepared],
+    cache: &ResponseCache,
+    ttl: Duration,
+) {
+    let requests: Vec<&Prepared> = requests
+        .iter()
+        .filter(|request| request.network == index)
+        .collect();
+    if requests.is_empty() {
+        return;
+    }
+
+    let mut warmed = 0;
+    for request in &requests {
+        let rpc = match spare_rpc(network).await {
+            Some(rpc) => rpc,
+            None => {
+                log_wrn!("No RPC has room for cache warming, trying again later");
+                break;
+            }
+        };
+
+        let response = timeout(ttl, rpc.send_request(request.parts.clone(), request.body.clone()));
+        match response.await {
+            Ok(Ok(response)) if response.status == 200 && !is_pending_transaction(&response.body) => {
+                // Same as for live traffic, a write invalidation has seen since would go missing
+                let head_cache = network.invalidator.read().await;
+                if head_cache.is_outdated(&request.key, response.ledger_version()) {
+                    log_dbg!("Not warming {}, it changed while in flight", request.key);
+                    continue;
+                }
+                cache
+                    .insert(
+                        &request.key,
+                        response.status,
+                        response.content_type(),
+                        &response.body,
+                        response.ledger_version(),
+                        request.ttl,
+                    )
+                    .await;
+                warmed += 1;
+            }
+            Ok(Ok(response)) => {
+                log_wrn!("Could not warm {}, got {}", request.key, response.status);
+            }
+            Ok(Err(e)) => {
+                log_wrn!("Could not warm {}: {}", request.key, e);
+            }
+            Err(_) => {
+                log_wrn!("Could not warm {}: timed out", request.key);
+            }
+        }
+    }
+
+    log_info!("Warmed {}/{} cache entries", warmed, requests.len());
+}
+
+/// An RPC that isn't over its `max_per_second`, so warming only ever uses
+/// capacity live traffic left over. Waits for one if they're all busy.
+async fn spare_rpc(network: &Network) -> Option<Rpc> {
+    let started = now_micros();
+
+    loop {
+        let now = now_micros();
+        let (rpc, rpc_position) = {
+            let mut rpc_list = network.rpc_list.write().unwrap();
+            pick_where(&mut rpc_list, |rpc| {
+                !rpc.status.is_backing_off() && now.saturating_sub(rpc.last_used) > rpc.min_time_delta
+            })
+        };
+        if rpc_position.is_some() {
+            return Some(rpc);
+        }
+
+        if now.saturating_sub(started) > MAX_WAIT.as_micros() {
+            return None;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+fn now_micros() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Failed to get current time")
+        .as_micros()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::types::{
+            CacheSettings,
+            RouteRule,
+        },
+        core::{
+            backend::SledBackend,
+            cache::Lookup,
+        },
+        utils::testing::{
+            network,
+            upstream,
+        },
+    };
+
+    fn warm(method: &str, path: &str) -> WarmRequest {
+        WarmRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: String::new(),
+        }
+    }
+
+    fn config() -> RwLock<Settings> {
+        RwLock::new(Settings {
+            routes: vec![RouteRule {
+                path: Some("/v1/accounts/*".to_string()),
+                cache: Some(true),
+                cache_ttl: Some(500),
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn requests_are_prepared_like_live_ones() {
+        let networks = vec![
+            network(None),
+            Network {
+                path_prefix: Some("/testnet".to_string()),
+                ..network(Some("testnet"))
+            },
+        ];
+        let prepared = prepare(
+            &networks,
+            &config(),
+            &[
+                warm("GET", "/v1/accounts/0x1"),
+                warm("GET", "/testnet/v1/blocks/by_height/5"),
+                // Not cached, so there's no point
+                warm("GET", "/v1/transactions"),
+            ],
+        );
+
+        assert_eq!(prepared.len(), 2);
+        assert_eq!((prepared[0].network, prepared[0].ttl), (0, Some(500)));
+        assert_eq!(prepared[0].parts.uri, "/v1/accounts/0x1");
+        assert_eq!((prepared[1].network, prepared[1].ttl), (1, None));
+        assert_eq!(prepared[1].parts.uri, "/v1/blocks/by_height/5");
+        assert!(prepared[1].key.starts_with("testnet"));
+    }
+
+    #[tokio::test]
+    async fn responses_land_in_the_cache() {
+        let rpc = Rpc::new(upstream("{\"warm\":true}").await, None, 15, 0, 3.0);
+        let networks = vec![network(None)];
+        networks[0].rpc_list.write().unwrap().push(rpc);
+        let prepared = prepare(&networks, &config(), &[warm("GET", "/v1/accounts/0x1")]);
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cache = ResponseCache::open(
+            Box::new(SledBackend::open(&db).unwrap()),
+            &CacheSettings::default(),
+            0,
+        )
+        .await
+        .unwrap();
+        warm_network(&networks[0], 0, &prepared, &cache, Duration::from_secs(5)).await;
+
+        match cache.get(&prepared[0].key, 0).await {
+            Lookup::Fresh(cached) => assert_eq!(&cached.body[..], b"{\"warm\":true}"),
+            lookup => panic!("expected a fresh entry, got {:?}", lookup),
+        }
+    }
+}
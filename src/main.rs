@@ -1,14 +1,63 @@
+mod admin;
 mod config;
 mod core;
 mod utils;
 
 use crate::{
-    config::{cli_args::create_match, types::Settings},
-    core::accept_incoming::{accept_request, ConnectionParams, RequestChannels},
+    admin::listener::{listen_for_admin_requests, AdminParams},
+    config::{
+        cli_args::create_match,
+        setup::prepare_network,
+        types::{
+            CacheBackendKind,
+            Settings,
+        },
+    },
+    core::{
+        accept_incoming::{accept_request, ConnectionParams, RequestChannels},
+        backend::{
+            CacheBackend,
+            RedisBackend,
+            SledBackend,
+        },
+        budget::Budget,
+        cache::{
+            open_cache_db,
+            ResponseCache,
+        },
+        coalesce::InFlight,
+        invalidation::{
+            purge_accounts,
+            watch_ledger,
+            Invalidator,
+        },
+        keys::ApiKeys,
+        networks::Network,
+        quorum::QuorumStats,
+        ratelimit::RateLimiter,
+        sessions::SessionTracker,
+        shadow::Shadow,
+        shutdown::{
+            wait_for_signal,
+            Shutdown,
+        },
+        upstream_stats::UpstreamStats,
+        warm::warm_cache,
+    },
     utils::check::health_check,
     utils::rpc::Rpc,
+    utils::systemd::{
+        listen_fds,
+        notify_or_warn,
+        watchdog,
+        watchdog_interval,
+        Heartbeat,
+        HEARTBEAT,
+    },
 };
 
+use futures::future::join_all;
+
 use std::{
     sync::{Arc, RwLock},
     time::Duration,
@@ -23,8 +72,21 @@ use hyper_util_blutgang::rt::TokioIo;
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Sockets systemd holds on to across restarts, if it's socket activating us.
+    // Taken before the runtime starts any threads, as that's when it's safe to
+    // clear the environment variables that pass them.
+    let activated = listen_fds();
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(activated))
+}
+
+async fn run(
+    mut activated: Vec<(String, std::net::TcpListener)>,
+) -> Result<(), Box<dyn std::error::Error>> {
     // Get all the cli args and set them
     let config = Arc::new(RwLock::new(Settings::new(create_match()).await));
 
@@ -34,15 +96,203 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         (config_guard.address, config_guard.health_check)
     };
 
-    let rpc_poverty_list: Arc<RwLock<Vec<Rpc>>> =
-        Arc::new(RwLock::new(config.read().unwrap().poverty_list.clone()));
+    // The default network comes first, followed by the named ones.
+    // Each gets its own rpc and poverty list, wrapped in a rwlock.
+    let networks: Arc<Vec<Network>> = {
+        let config_guard = config.read().unwrap();
+        let mut networks = vec![Network {
+            name: None,
+            chain_id: config_guard.chain_id,
+            path_prefix: None,
+            hosts: Vec::new(),
+            rpc_list: Arc::new(RwLock::new(config_guard.rpc_list.clone())),
+            poverty_list: Arc::new(RwLock::new(config_guard.poverty_list.clone())),
+            invalidator: Arc::new(Invalidator::new()),
+        }];
+        for network in &config_guard.networks {
+            networks.push(Network {
+                name: Some(network.name.clone()),
+                chain_id: network.chain_id,
+                path_prefix: Some(network.path_prefix.clone()),
+                hosts: network.hosts.clone(),
+                rpc_list: Arc::new(RwLock::new(network.rpc_list.clone())),
+                poverty_list: Arc::new(RwLock::new(network.poverty_list.clone())),
+                invalidator: Arc::new(Invalidator::new()),
+            });
+        }
+        Arc::new(networks)
+    };
+
+    // Shared across all connections so hedging and retries are capped globally
+    let hedge_budget = Arc::new(Budget::new(config.read().unwrap().hedge_budget, 10.0));
+    let retry_budget = Arc::new(Budget::new(config.read().unwrap().retry_budget, 10.0));
+
+    let rate_limiter = Arc::new(RateLimiter::new());
+    {
+        let rate_limiter = Arc::clone(&rate_limiter);
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                rate_limiter.prune();
+            }
+        });
+    }
+
+    let sessions = Arc::new(SessionTracker::new());
+    {
+        let sessions = Arc::clone(&sessions);
+        let idle_timeout = Duration::from_secs(config.read().unwrap().sessions.idle_timeout);
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                sessions.prune(idle_timeout);
+            }
+        });
+    }
+
+    let quorum_stats = Arc::new(QuorumStats::new());
+    let shadow = Arc::new(Shadow::new(&config.read().unwrap().shadow));
+    let in_flight = Arc::new(InFlight::new());
+
+    // Open the sled DB, which holds API keys, usage counters and upstream stats
+    let db = config.read().unwrap().sled_config.open()?;
+
+    // Restore what we knew about the upstreams before the restart, or measure them from scratch
+    let upstream_stats = Arc::new(UpstreamStats::open(&db)?);
+    {
+        let (sort_on_startup, ma_length, stats_max_age, stats_interval) = {
+            let config_guard = config.read().unwrap();
+            (
+                config_guard.sort_on_startup,
+                config_guard.ma_length,
+                config_guard.stats_max_age,
+                config_guard.stats_interval,
+            )
+        };
+        for network in networks.iter() {
+            if let Err(e) =
+                prepare_network(network, &upstream_stats, sort_on_startup, ma_length, stats_max_age)
+                    .await
+            {
+                panic!("{:?}", e);
+            }
+        }
+
+        if stats_interval > 0 {
+            let upstream_stats = Arc::clone(&upstream_stats);
+            let networks = Arc::clone(&networks);
+            tokio::task::spawn(async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(stats_interval)).await;
+                    upstream_stats.save(&networks);
+                }
+            });
+        }
+    }
+
+    let (cache_settings, grace) = {
+        let config_guard = config.read().unwrap();
+        // Expired entries have to stick around for as long as any route might serve them stale
+        let grace = config_guard
+            .routes
+            .iter()
+            .map(|route| {
+                route
+                    .stale_while_revalidate
+                    .max(route.stale_if_error)
+                    .unwrap_or(0)
+            })
+            .max()
+            .unwrap_or(0);
+        (config_guard.cache.clone(), grace)
+    };
+    let backend: Box<dyn CacheBackend> = match &cache_settings.backend {
+        CacheBackendKind::Sled => {
+            let cache_db = open_cache_db(&config.read().unwrap().sled_config)?;
+            Box::new(SledBackend::open(&cache_db)?)
+        }
+        CacheBackendKind::Redis(url) => Box::new(RedisBackend::new(
+            url,
+            &cache_settings.redis_prefix,
+            Duration::from_millis(cache_settings.redis_timeout),
+        )?),
+    };
+    let cache = Arc::new(ResponseCache::open(backend, &cache_settings, grace).await?);
+    {
+        let cache = Arc::clone(&cache);
+        let eviction_interval = Duration::from_millis(config.read().unwrap().cache.eviction_interval);
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(eviction_interval).await;
+                // Walking every entry in sled blocks, so it's kept off the runtime's workers
+                let cache = Arc::clone(&cache);
+                let runtime = tokio::runtime::Handle::current();
+                let _ = tokio::task::spawn_blocking(move || runtime.block_on(cache.evict())).await;
+            }
+        });
+    }
+    // Follow each network's ledger and drop cached account data as transactions touch it
+    if config.read().unwrap().cache.invalidate {
+        let settings = config.read().unwrap().cache.clone();
+        for network in networks.iter() {
+            purge_accounts(network, &cache).await;
+            tokio::task::spawn(watch_ledger(
+                network.clone(),
+                Arc::clone(&cache),
+                settings.clone(),
+            ));
+        }
+    }
+    // Prefetch what clients are known to ask for, after anything stale got purged
+    if !config.read().unwrap().cache.warm.is_empty() {
+        tokio::task::spawn(warm_cache(
+            Arc::clone(&networks),
+            Arc::clone(&config),
+            Arc::clone(&cache),
+        ));
+    }
+    let api_keys = Arc::new(ApiKeys::open(&db)?);
 
-    // Make the list a rwlock
-    let rpc_list_rwlock = Arc::new(RwLock::new(config.read().unwrap().rpc_list.clone()));
+    // Spawn the admin namespace on its own address if enabled
+    if config.read().unwrap().admin.enabled {
+        let admin_params = AdminParams {
+            config: Arc::clone(&config),
+            api_keys: Arc::clone(&api_keys),
+            quorum_stats: Arc::clone(&quorum_stats),
+            shadow: Arc::clone(&shadow),
+            cache: Arc::clone(&cache),
+        };
+        tokio::task::spawn(async move {
+            if let Err(e) = listen_for_admin_requests(admin_params).await {
+                log_err!("Admin namespace error: {}", e);
+            }
+        });
+    }
 
-    // We create a TcpListener and bind it to 127.0.0.1:3000
-    let listener = TcpListener::bind(addr).await?;
-    log_info!("Bound to: {}", addr);
+    // Activated sockets named after a network serve that network, the first other one serves everything
+    let network_names: Vec<String> = config
+        .read()
+        .unwrap()
+        .networks
+        .iter()
+        .map(|network| network.name.clone())
+        .collect();
+    let listener = match activated
+        .iter()
+        .position(|(name, _)| !network_names.contains(name))
+    {
+        Some(position) => {
+            let listener = into_tokio(activated.remove(position).1)?;
+            log_info!("Listening on socket passed by systemd: {}", listener.local_addr()?);
+            listener
+        }
+        None => {
+            // We create a TcpListener and bind it to 127.0.0.1:3000
+            let listener = TcpListener::bind(addr).await?;
+            log_info!("Bound to: {}", addr);
+            listener
+        }
+    };
 
     let (_blocknum_tx, _blocknum_rx) = watch::channel(0);
     let (_finalized_tx, finalized_rx) = watch::channel(0);
@@ -50,37 +300,177 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let finalized_rx_arc = Arc::new(finalized_rx.clone());
 
     if do_health_check {
-        let rpc_list_health = Arc::clone(&rpc_list_rwlock);
-        let poverty_list_health = Arc::clone(&rpc_poverty_list);
         let health_check_ttl = config.read().unwrap().health_check_ttl;
+        let max_block_lag = config.read().unwrap().max_block_lag;
+
+        // Run the first round before telling systemd we're ready
+        join_all(networks.iter().map(|network| {
+            health_check(
+                Arc::clone(&network.rpc_list),
+                Arc::clone(&network.poverty_list),
+                network.chain_id,
+                max_block_lag,
+            )
+        }))
+        .await;
 
+        for network in networks.iter() {
+            let rpc_list_health = Arc::clone(&network.rpc_list);
+            let poverty_list_health = Arc::clone(&network.poverty_list);
+            let chain_id = network.chain_id;
+
+            tokio::task::spawn(async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(health_check_ttl)).await;
+                    let _ = health_check(
+                        Arc::clone(&rpc_list_health),
+                        Arc::clone(&poverty_list_health),
+                        chain_id,
+                        max_block_lag,
+                    )
+                    .await;
+                }
+            });
+        }
+    }
+
+    let channels = RequestChannels::new(finalized_rx_arc.clone());
+
+    let shutdown = Arc::new(Shutdown::new());
+    let heartbeat = Arc::new(Heartbeat::new());
+
+    let connection_params = ConnectionParams::new(
+        &networks,
+        channels,
+        &config,
+        &hedge_budget,
+        &retry_budget,
+        &rate_limiter,
+        &api_keys,
+        &cache,
+        &sessions,
+        &quorum_stats,
+        &shadow,
+        &in_flight,
+    );
+
+    // Networks with their own address get a listener that only serves them
+    let network_addresses: Vec<_> = config
+        .read()
+        .unwrap()
+        .networks
+        .iter()
+        .map(|network| (network.name.clone(), network.address))
+        .collect();
+    for (index, (name, address)) in network_addresses.into_iter().enumerate() {
+        let network_listener = match activated.iter().position(|(fd_name, _)| *fd_name == name) {
+            Some(position) => {
+                let network_listener = into_tokio(activated.remove(position).1)?;
+                log_info!(
+                    "Network {} listening on socket passed by systemd: {}",
+                    name,
+                    network_listener.local_addr()?
+                );
+                network_listener
+            }
+            None => {
+                let address = match address {
+                    Some(address) => address,
+                    None => continue,
+                };
+                let network_listener = TcpListener::bind(address).await?;
+                log_info!("Network {} bound to: {}", name, address);
+                network_listener
+            }
+        };
+
+        // The default network sits at index 0
+        let connection_params = connection_params.with_network(index + 1);
+        let shutdown = Arc::clone(&shutdown);
+        let heartbeat = Arc::clone(&heartbeat);
         tokio::task::spawn(async move {
-            loop {
-                let _ = health_check(
-                    Arc::clone(&rpc_list_health),
-                    Arc::clone(&poverty_list_health),
-                )
-                .await;
-                tokio::time::sleep(Duration::from_millis(health_check_ttl)).await;
+            if let Err(e) = serve(network_listener, connection_params, shutdown, heartbeat).await {
+                log_err!("Error accepting connections for network {}: {}", name, e);
             }
         });
     }
+    for (name, _) in activated {
+        log_wrn!("Ignoring socket {} passed by systemd, it doesn't belong to any network", name);
+    }
+
+    // Everything is bound and health checked, so we can take traffic
+    notify_or_warn(&format!("READY=1\nSTATUS=Serving on {}", listener.local_addr()?));
+    if let Some(interval) = watchdog_interval() {
+        let heartbeat = Arc::clone(&heartbeat);
+        tokio::task::spawn(async move { watchdog(&heartbeat, interval).await });
+    }
+
+    tokio::select! {
+        result = serve(listener, connection_params, Arc::clone(&shutdown), heartbeat) => result?,
+        result = wait_for_signal() => result?,
+    }
+
+    // Stop taking new connections and give the open ones a chance to finish
+    log_info!("Shutting down, draining connections");
+    notify_or_warn("STOPPING=1\nSTATUS=Draining connections");
+    shutdown.trigger();
+    let drain_timeout = Duration::from_millis(config.read().unwrap().drain_timeout);
+    if tokio::time::timeout(drain_timeout, shutdown.drained()).await.is_err() {
+        log_wrn!(
+            "{} connections still open after {}ms, closing them",
+            shutdown.open_connections(),
+            drain_timeout.as_millis()
+        );
+    }
+
+    // Save what we'd otherwise lose, cache entries included
+    upstream_stats.save(&networks);
+    cache.flush().await;
+    db.flush_async().await?;
+    log_info!("Shut down cleanly");
+    Ok(())
+}
+
+/// Continuously accept incoming connections on `listener`, until we start shutting down
+async fn serve(
+    listener: TcpListener,
+    connection_params: ConnectionParams,
+    shutdown: Arc<Shutdown>,
+    heartbeat: Arc<Heartbeat>,
+) -> Result<(), std::io::Error> {
+    let stopping = shutdown.stopping();
+    tokio::pin!(stopping);
+    // Lets the systemd watchdog know we're still accepting, even while idle
+    let mut ticks = tokio::time::interval(HEARTBEAT);
 
-    // We start a loop to continuously accept incoming connections
     loop {
-        let (stream, socketaddr) = listener.accept().await?;
+        let (stream, socketaddr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = ticks.tick() => {
+                heartbeat.beat();
+                continue;
+            }
+            _ = &mut stopping => return Ok(()),
+        };
         // log_info!("Connection from: {}", socketaddr);
         // Use an adapter to access something implementing `tokio::io` traits as if they implement
         // `hyper::rt` IO traits.
         let io = TokioIo::new(stream);
 
-        let channels = RequestChannels::new(finalized_rx_arc.clone());
-
-        let connection_params = ConnectionParams::new(&rpc_list_rwlock, channels, &config);
+        let connection_params = connection_params.with_remote_addr(socketaddr);
 
         // Spawn a tokio task to serve multiple connections concurrently
+        let connection = shutdown.connection();
+        let stopping = shutdown.stopping();
         tokio::task::spawn(async move {
-            accept!(io, connection_params.clone());
+            accept!(io, connection_params.clone(), stopping);
+            drop(connection);
         });
     }
 }
+
+/// Hand a socket systemd passed us over to tokio
+fn into_tokio(listener: std::net::TcpListener) -> Result<TcpListener, std::io::Error> {
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
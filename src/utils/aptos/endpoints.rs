@@ -0,0 +1,204 @@
+use http::{
+    request::Parts,
+    Method,
+};
+
+/// Broad kind of Aptos API call, based on what it costs an RPC to answer it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestClass {
+    // Plain reads, including POST endpoints that only read state
+    Read,
+    // Calls that run Move code, like view functions and simulations
+    View,
+    // Transaction submissions. These must never be sent twice.
+    Submit,
+}
+
+impl RequestClass {
+    pub fn from_parts(parts: &Parts) -> Self {
+        match parts.method {
+            Method::GET | Method::HEAD => return RequestClass::Read,
+            Method::POST => {}
+            // Nothing else is safe to send twice
+            _ => return RequestClass::Submit,
+        }
+
+        let path = parts.uri.path().trim_end_matches('/');
+        match path {
+            "/v1/view" | "/v1/transactions/simulate" => RequestClass::View,
+            "/v1/transactions/encode_submission" => RequestClass::Read,
+            // `/v1/tables/{handle}/item` and `/v1/tables/{handle}/raw_item`
+            _ if path.starts_with("/v1/tables/") => RequestClass::Read,
+            // Anything else we don't know about gets treated as a submission to be safe
+            _ => RequestClass::Submit,
+        }
+    }
+}
+
+/// Matches an API path against a pattern like `/v1/accounts/*/resources`.
+///
+/// `*` matches exactly one path segment, and a trailing `**` matches whatever is left,
+/// including nothing. Everything else has to match exactly.
+pub fn path_matches(pattern: &str, path: &str) -> bool {
+    let mut pattern = pattern.trim_end_matches('/').split('/');
+    let mut path = path.trim_end_matches('/').split('/');
+
+    loop {
+        match (pattern.next(), path.next()) {
+            (Some("**"), _) => return true,
+            (Some("*"), Some(_)) => {}
+            (Some(expected), Some(segment)) if expected == segment => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Returns true if the response to the request can never change, so it's safe to cache
+/// without an expiry.
+///
+/// That covers lookups by version, height or hash, and reads pinned to a `ledger_version`.
+pub fn is_immutable(parts: &Parts) -> bool {
+    if parts.method != Method::GET && parts.method != Method::POST {
+        return false;
+    }
+
+    let pinned = parts
+        .uri
+        .query()
+        .is_some_and(|query| query.split('&').any(|param| param.starts_with("ledger_version=")));
+
+    let path = parts.uri.path();
+    if parts.method == Method::POST {
+        return pinned && path.trim_end_matches('/') == "/v1/view";
+    }
+
+    pinned
+        || [
+            "/v1/transactions/by_hash/*",
+            "/v1/transactions/by_version/*",
+            "/v1/blocks/by_height/*",
+            "/v1/blocks/by_version/*",
+        ]
+        .iter()
+        .any(|pattern| path_matches(pattern, path))
+}
+
+// Endpoints that read state at the version given in a `ledger_version` query.
+// Everything else ignores it, or rejects it as an unknown parameter.
+const VERSIONED_ENDPOINTS: &[(Method, &str)] = &[
+    (Method::GET, "/v1/accounts/*"),
+    (Method::GET, "/v1/accounts/*/resources"),
+    (Method::GET, "/v1/accounts/*/resource/*"),
+    (Method::GET, "/v1/accounts/*/modules"),
+    (Method::GET, "/v1/accounts/*/module/*"),
+    (Method::GET, "/v1/accounts/*/balance/*"),
+    (Method::POST, "/v1/tables/*/item"),
+    (Method::POST, "/v1/tables/*/raw_item"),
+    (Method::POST, "/v1/view"),
+];
+
+/// Returns true if the endpoint can be asked for its answer at a specific ledger version
+pub fn supports_ledger_version(parts: &Parts) -> bool {
+    let path = parts.uri.path();
+    VERSIONED_ENDPOINTS
+        .iter()
+        .any(|(method, pattern)| parts.method == method && path_matches(pattern, path))
+}
+
+/// Transactions looked up by hash can still be pending, in which case the answer will change
+pub fn is_pending_transaction(body: &[u8]) -> bool {
+    memchr::memmem::find(body, b"\"pending_transaction\"").is_some()
+}
+
+// Errors for things that don't exist (yet). Bots poll these in tight loops.
+const NOT_FOUND_ERRORS: &[&str] = &[
+    "account_not_found",
+    "resource_not_found",
+    "module_not_found",
+    "table_item_not_found",
+];
+
+/// True for a 404 saying an account, resource, module or table item doesn't exist
+pub fn is_not_found(status: u16, body: &[u8]) -> bool {
+    if status != 404 {
+        return false;
+    }
+
+    serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .and_then(|error| error["error_code"].as_str().map(|code| code.to_string()))
+        .is_some_and(|code| NOT_FOUND_ERRORS.contains(&code.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::parts;
+
+    #[test]
+    fn classes() {
+        let class =
+            |method: Method, uri| RequestClass::from_parts(&parts(method.as_str(), uri, &[]));
+
+        assert_eq!(class(Method::GET, "/v1/accounts/0x1"), RequestClass::Read);
+        assert_eq!(class(Method::HEAD, "/v1"), RequestClass::Read);
+        assert_eq!(class(Method::POST, "/v1/view"), RequestClass::View);
+        assert_eq!(class(Method::POST, "/v1/transactions/simulate/"), RequestClass::View);
+        assert_eq!(class(Method::POST, "/v1/tables/0xabc/item"), RequestClass::Read);
+        assert_eq!(class(Method::POST, "/v1/transactions"), RequestClass::Submit);
+        assert_eq!(class(Method::POST, "/v1/something_new"), RequestClass::Submit);
+        for method in [Method::PUT, Method::DELETE, Method::PATCH] {
+            assert_eq!(class(method, "/v1/accounts/0x1"), RequestClass::Submit);
+        }
+    }
+
+    #[test]
+    fn path_patterns() {
+        assert!(path_matches("/v1/accounts/*/resources", "/v1/accounts/0x1/resources"));
+        assert!(path_matches("/v1/accounts/*/resources", "/v1/accounts/0x1/resources/"));
+        assert!(!path_matches("/v1/accounts/*/resources", "/v1/accounts/resources"));
+        assert!(!path_matches("/v1/accounts/*", "/v1/accounts/0x1/resources"));
+        assert!(path_matches("/v1/**", "/v1"));
+        assert!(path_matches("/v1/**", "/v1/accounts/0x1/resources"));
+        assert!(!path_matches("/v1/view", "/v1/views"));
+    }
+
+    #[test]
+    fn versioned_endpoints() {
+        assert!(supports_ledger_version(&parts("GET", "/v1/accounts/0x1", &[])));
+        let resource = "/v1/accounts/0x1/resource/0x1::account::Account";
+        assert!(supports_ledger_version(&parts("GET", resource, &[])));
+        assert!(supports_ledger_version(&parts("POST", "/v1/tables/0xabc/item", &[])));
+        assert!(supports_ledger_version(&parts("POST", "/v1/view/", &[])));
+        assert!(!supports_ledger_version(&parts("GET", "/v1/view", &[])));
+        assert!(!supports_ledger_version(&parts("GET", "/v1/accounts/0x1/transactions", &[])));
+        assert!(!supports_ledger_version(&parts("GET", "/v1/transactions", &[])));
+        assert!(!supports_ledger_version(&parts("GET", "/v1/blocks/by_height/5", &[])));
+        assert!(!supports_ledger_version(&parts("POST", "/v1/transactions/simulate", &[])));
+    }
+
+    #[test]
+    fn immutable_requests() {
+        assert!(is_immutable(&parts("GET", "/v1/blocks/by_height/5", &[])));
+        assert!(is_immutable(&parts("GET", "/v1/transactions/by_hash/0xab", &[])));
+        assert!(is_immutable(&parts("GET", "/v1/accounts/0x1/resources?ledger_version=10", &[])));
+        assert!(is_immutable(&parts("POST", "/v1/view?ledger_version=10", &[])));
+        assert!(!is_immutable(&parts("POST", "/v1/view", &[])));
+        assert!(!is_immutable(&parts("POST", "/v1/transactions?ledger_version=10", &[])));
+        assert!(!is_immutable(&parts("GET", "/v1/accounts/0x1/resources", &[])));
+        assert!(!is_immutable(&parts("DELETE", "/v1/blocks/by_height/5", &[])));
+    }
+
+    #[test]
+    fn not_found_errors() {
+        let body = br#"{"message": "nope", "error_code": "account_not_found"}"#;
+        assert!(is_not_found(404, body));
+        assert!(!is_not_found(200, body));
+        assert!(!is_not_found(404, br#"{"error_code": "web_framework_error"}"#));
+        assert!(!is_not_found(404, b"not json"));
+
+        assert!(is_pending_transaction(br#"{"type":"pending_transaction"}"#));
+        assert!(!is_pending_transaction(br#"{"type":"user_transaction"}"#));
+    }
+}
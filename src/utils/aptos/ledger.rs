@@ -0,0 +1,100 @@
+use crate::utils::{
+    aptos::endpoints::supports_ledger_version,
+    rpc::RpcResponse,
+};
+
+use http::{
+    request::Parts,
+    HeaderMap,
+    Uri,
+};
+use hyper::body::Incoming;
+
+pub const LEDGER_VERSION_HEADER: &str = "X-Aptos-Ledger-Version";
+pub const BLOCK_HEIGHT_HEADER: &str = "X-Aptos-Block-Height";
+
+/// Header prefix of the ledger info Aptos nodes attach to every response
+pub const APTOS_HEADER_PREFIX: &str = "x-aptos-";
+
+/// Ledger version the response was served at, from its `X-Aptos-Ledger-Version` header
+pub fn ledger_version(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(LEDGER_VERSION_HEADER)
+        .and_then(|version| version.to_str().ok())
+        .and_then(|version| version.parse::<u64>().ok())
+}
+
+/// Block height the response was served at, from its `X-Aptos-Block-Height` header
+pub fn block_height(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(BLOCK_HEIGHT_HEADER)
+        .and_then(|height| height.to_str().ok())
+        .and_then(|height| height.parse::<u64>().ok())
+}
+
+/// Anything an RPC answered with that can tell us which ledger version it's from
+pub trait LedgerVersioned {
+    fn ledger_version(&self) -> Option<u64>;
+}
+
+impl LedgerVersioned for RpcResponse {
+    fn ledger_version(&self) -> Option<u64> {
+        ledger_version(&self.headers)
+    }
+}
+
+impl LedgerVersioned for hyper::Response<Incoming> {
+    fn ledger_version(&self) -> Option<u64> {
+        ledger_version(self.headers())
+    }
+}
+
+/// Add a `ledger_version` query to the request, unless the client set one already
+/// or the endpoint doesn't take one.
+pub fn pin_to_version(parts: &mut Parts, version: u64) {
+    if !supports_ledger_version(parts) {
+        return;
+    }
+
+    let query = parts.uri.query().unwrap_or_default();
+    if query.split('&').any(|pair| pair.starts_with("ledger_version=")) {
+        return;
+    }
+
+    let path_and_query = match query {
+        "" => format!("{}?ledger_version={}", parts.uri.path(), version),
+        query => format!("{}?{}&ledger_version={}", parts.uri.path(), query, version),
+    };
+    if let Ok(uri) = path_and_query.parse::<Uri>() {
+        parts.uri = uri;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::parts;
+
+    fn pinned(method: &str, uri: &str) -> String {
+        let mut parts = parts(method, uri, &[]);
+        pin_to_version(&mut parts, 42);
+        parts.uri.to_string()
+    }
+
+    #[test]
+    fn pinning() {
+        assert_eq!(pinned("GET", "/v1/accounts/0x1"), "/v1/accounts/0x1?ledger_version=42");
+        assert_eq!(
+            pinned("POST", "/v1/view?foo=bar"),
+            "/v1/view?foo=bar&ledger_version=42"
+        );
+        // The client's own version wins
+        assert_eq!(
+            pinned("GET", "/v1/accounts/0x1/resources?ledger_version=7"),
+            "/v1/accounts/0x1/resources?ledger_version=7"
+        );
+        // Endpoints without a ledger_version parameter are left alone
+        assert_eq!(pinned("GET", "/v1/transactions"), "/v1/transactions");
+        assert_eq!(pinned("GET", "/v1/blocks/by_height/5"), "/v1/blocks/by_height/5");
+    }
+}
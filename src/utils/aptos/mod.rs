@@ -1 +1,3 @@
-pub mod requests;
\ No newline at end of file
+pub mod endpoints;
+pub mod requests;
+pub mod ledger;
@@ -3,7 +3,13 @@ use std::sync::{Arc, RwLock};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 
-use crate::{utils::error::HealthError, utils::rpc::Rpc};
+use crate::{
+    log_dbg,
+    log_info,
+    log_wrn,
+    utils::error::HealthError,
+    utils::rpc::Rpc,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct AptosApiResponse {
@@ -22,9 +28,8 @@ pub async fn send_health_request(
     url: String,
     client: Client,
 ) -> Result<String, crate::utils::error::RpcError> {
-    #[cfg(feature = "debug-verbose")]
-    println!("Sending request: {}", tx.clone());
     let url = format!("{}/v1", url);
+    log_dbg!("Sending health request to {}", url);
     let response = match client.get(url).send().await {
         Ok(response) => response,
         Err(err) => {
@@ -33,7 +38,6 @@ pub async fn send_health_request(
             ))
         }
     };
-    #[cfg(not(feature = "debug-verbose"))]
     Ok(response.text().await.unwrap())
 }
 
@@ -41,50 +45,107 @@ pub fn is_valid_api_response(json_response: &str) -> bool {
     serde_json::from_str::<AptosApiResponse>(json_response).is_ok()
 }
 
+/// Returns true if the RPC answers its ledger info with a 200 and,
+/// if we know which chain it should be on, the right `chain_id`.
+///
+/// Also records the RPC's ledger version and block height while we're at it.
+async fn is_healthy(client: &Client, rpc: &Rpc, chain_id: Option<u32>) -> bool {
+    let url = format!("{}/v1", &rpc.url);
+    let response = match client.get(url).send().await {
+        Ok(response) if response.status() == 200 => response,
+        Ok(response) => {
+            log_wrn!(UPSTREAM = rpc.name, STATUS = "failed";
+                "APTOS RPC CHECK {:?} : FAILED! {:?}", &rpc.name, response.status());
+            return false;
+        }
+        Err(e) => {
+            log_wrn!(UPSTREAM = rpc.name, STATUS = "failed";
+                "APTOS RPC CHECK {:?} : FAILED! {}", &rpc.name, e);
+            return false;
+        }
+    };
+
+    let info = match response.json::<AptosApiResponse>().await {
+        Ok(info) => info,
+        Err(e) => {
+            log_wrn!(UPSTREAM = rpc.name, STATUS = "invalid_response";
+                "APTOS RPC CHECK {:?} : INVALID RESPONSE! {}", &rpc.name, e);
+            return false;
+        }
+    };
+    if let (Ok(version), Ok(block_height)) =
+        (info.ledger_version.parse::<u64>(), info.block_height.parse::<u64>())
+    {
+        rpc.ledger.set(version, block_height);
+    }
+
+    match chain_id {
+        Some(expected) if info.chain_id != expected => {
+            log_wrn!(UPSTREAM = rpc.name, STATUS = "wrong_chain";
+                "APTOS RPC CHECK {:?} : WRONG CHAIN! expected {}, got {}",
+                &rpc.name, expected, info.chain_id
+            );
+            false
+        }
+        _ => true,
+    }
+}
+
 pub async fn check_aptos_rpc_status(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    chain_id: Option<u32>,
+    max_block_lag: Option<u64>,
 ) -> Result<(), HealthError> {
     let rpc_clone = rpc_list.read().unwrap().clone();
+    let poverty_clone = poverty_list.read().unwrap().clone();
     let mut status: bool = true;
     let mut to_remove = Vec::new();
     let mut to_add = Vec::new();
+    let mut recovered = Vec::new();
 
+    let client = reqwest::Client::new();
+    let mut healthy = Vec::new();
     for rpc in &rpc_clone {
-        let client = reqwest::Client::new();
-        let url = format!("{}/v1", &rpc.url);
-        let response = client.get(url).send().await?;
-        println!("RPC IN LIST {:?}", &rpc.name);
-
-        if response.status() == 200 {
-            println!(
-                "APTOS RPC CHECK {:?} : OK! {:?}",
-                &rpc.name,
-                response.status()
-            );
-        } else {
-            println!("APTOS RPC CHECK {:?} : FAILED!", &rpc.name);
+        log_dbg!(UPSTREAM = rpc.name; "RPC IN LIST {:?}", &rpc.name);
+        healthy.push(is_healthy(&client, rpc, chain_id).await);
+    }
+    let mut poverty_healthy = Vec::new();
+    for rpc in &poverty_clone {
+        log_dbg!(UPSTREAM = rpc.name; "RPC IN POVERTY LIST {:?}", &rpc.name);
+        poverty_healthy.push(is_healthy(&client, rpc, chain_id).await);
+    }
+
+    // Block heights also come from proxied responses, so this is usually fresher
+    // than the probes above
+    let head = rpc_clone
+        .iter()
+        .chain(&poverty_clone)
+        .filter_map(|rpc| rpc.ledger.block_height())
+        .max();
+
+    for (rpc, healthy) in rpc_clone.iter().zip(healthy) {
+        if !healthy {
+            status = false;
+            to_remove.push(rpc.clone());
+        } else if rpc.ledger.is_lagging(head, max_block_lag) {
+            log_wrn!(UPSTREAM = rpc.name, STATUS = "lagging";
+                "APTOS RPC CHECK {:?} : LAGGING! head is {:?}", &rpc.name, head);
             status = false;
             to_remove.push(rpc.clone());
+        } else {
+            log_dbg!(UPSTREAM = rpc.name, STATUS = "ok"; "APTOS RPC CHECK {:?} : OK!", &rpc.name);
+            if rpc.status.is_erroring {
+                recovered.push(rpc.url.clone());
+            }
         }
     }
 
-    let poverty_clone = poverty_list.read().unwrap().clone();
-    for rpc in &poverty_clone {
-        let client = reqwest::Client::new();
-        let url = format!("{}/v1", &rpc.url);
-        let response = client.get(url).send().await?;
-        println!("RPC IN POVERTY LIST {:?}", &rpc.name);
-
-        if response.status() == 200 {
-            println!(
-                "RPC BACK ONLINE {:?} : OK! {:?}",
-                &rpc.name,
-                response.status()
-            );
+    for (rpc, healthy) in poverty_clone.iter().zip(poverty_healthy) {
+        if healthy && !rpc.ledger.is_lagging(head, max_block_lag) {
+            log_info!(UPSTREAM = rpc.name, STATUS = "ok"; "RPC BACK ONLINE {:?} : OK!", &rpc.name);
             to_add.push(rpc.clone());
         } else {
-            println!("APTOS RPC CHECK {:?} : FAILED!", &rpc.name);
             status = false;
         }
     }
@@ -93,16 +154,27 @@ pub async fn check_aptos_rpc_status(
     let mut rpc_list_guard = rpc_list.write().unwrap();
     let mut poverty_list_guard = poverty_list.write().unwrap();
 
+    // Passing a check is enough to count as recovered, it doesn't have to win a request first
+    for rpc in rpc_list_guard.iter_mut() {
+        if recovered.contains(&rpc.url) {
+            rpc.status.is_erroring = false;
+        }
+    }
+
     for rpc in to_remove.iter() {
-        println!("Removing RPC from list {:?} ", &rpc.name);
+        log_wrn!(UPSTREAM = rpc.name, STATUS = "removed"; "Removing RPC from list {:?}", &rpc.name);
         rpc_list_guard.retain(|r| r.url != rpc.url);
-        poverty_list_guard.push(rpc.clone());
+        let mut rpc = rpc.clone();
+        rpc.status.is_erroring = true;
+        poverty_list_guard.push(rpc);
     }
 
     for rpc in to_add.iter() {
-        println!("Adding RPC back to list {:?}", &rpc.name);
+        log_info!(UPSTREAM = rpc.name, STATUS = "restored"; "Adding RPC back to list {:?}", &rpc.name);
         poverty_list_guard.retain(|r| r.url != rpc.url);
-        rpc_list_guard.push(rpc.clone());
+        let mut rpc = rpc.clone();
+        rpc.status.is_erroring = false;
+        rpc_list_guard.push(rpc);
     }
 
     if status {
@@ -112,3 +184,28 @@ pub async fn check_aptos_rpc_status(
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::upstream;
+
+    const LEDGER_INFO: &str = r#"{"chain_id":1,"epoch":"1","ledger_version":"100","oldest_ledger_version":"0","ledger_timestamp":"0","node_role":"full_node","oldest_block_height":"0","block_height":"50","git_hash":""}"#;
+
+    #[tokio::test]
+    async fn passing_a_check_clears_errors() {
+        let mut rpc = Rpc::new(upstream(LEDGER_INFO).await, None, 15, 0, 3.0);
+        rpc.status.mark_erroring();
+        let rpc_list = Arc::new(RwLock::new(vec![rpc]));
+        let poverty_list = Arc::new(RwLock::new(Vec::new()));
+
+        check_aptos_rpc_status(&rpc_list, &poverty_list, Some(1), None)
+            .await
+            .unwrap();
+
+        let rpc_list = rpc_list.read().unwrap();
+        assert_eq!(rpc_list.len(), 1);
+        assert!(!rpc_list[0].status.is_erroring);
+        assert_eq!(rpc_list[0].ledger.version(), Some(100));
+    }
+}
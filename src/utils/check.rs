@@ -8,8 +8,10 @@ use std::sync::{Arc, RwLock};
 pub async fn health_check(
     rpc_list: Arc<RwLock<Vec<Rpc>>>,
     poverty_list: Arc<RwLock<Vec<Rpc>>>,
+    chain_id: Option<u32>,
+    max_block_lag: Option<u64>,
 ) -> Result<(), HealthError> {
-    check(&rpc_list, &poverty_list).await?;
+    check(&rpc_list, &poverty_list, chain_id, max_block_lag).await?;
     Ok(())
 }
 
@@ -22,6 +24,8 @@ impl From<reqwest::Error> for HealthError {
 async fn check(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    chain_id: Option<u32>,
+    max_block_lag: Option<u64>,
 ) -> Result<(), HealthError> {
-    check_aptos_rpc_status(rpc_list, poverty_list).await
+    check_aptos_rpc_status(rpc_list, poverty_list, chain_id, max_block_lag).await
 }
@@ -1,4 +1,8 @@
 pub mod aptos;
 pub mod check;
 pub mod error;
+pub mod resp;
 pub mod rpc;
+pub mod systemd;
+#[cfg(test)]
+pub mod testing;
@@ -1,18 +1,39 @@
+use crate::utils::aptos::ledger::{
+    block_height,
+    ledger_version,
+};
 use crate::utils::aptos::requests::is_valid_api_response;
 use crate::utils::aptos::requests::send_health_request;
 use reqwest::Client;
 use url::Url;
 
 use http::{HeaderMap, Request};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
 
 use bytes::Bytes;
 use http_body_util::BodyExt;
 use http_body_util::Full;
-use hyper::http::request::Parts;
+use http_body_util::Limited;
+use hyper::{
+    body::Incoming,
+    http::request::Parts,
+};
 use hyper_tls::HttpsConnector;
 use hyper_util::client::legacy::Client as HyperClient;
 use hyper_util::rt::TokioExecutor;
 
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 64 * 1024 * 1024;
+
+// How long an RPC we couldn't reach sits out before we send it requests again, in microseconds
+const ERROR_BACKOFF: u64 = 1_000_000;
+
 // All as floats so we have an easier time getting averages, stats and terminology copied from flood.
 #[derive(Debug, Clone, Default)]
 pub struct Status {
@@ -29,6 +50,106 @@ pub struct Status {
     // pub throughput: f64,
 }
 
+impl Status {
+    /// Marks the RPC as erroring after we couldn't reach it
+    pub fn mark_erroring(&mut self) {
+        self.is_erroring = true;
+        self.last_error = now_micros();
+    }
+
+    /// True for `ERROR_BACKOFF` after the RPC last failed to respond
+    pub fn is_backing_off(&self) -> bool {
+        self.is_erroring && now_micros().saturating_sub(self.last_error) < ERROR_BACKOFF
+    }
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Failed to get current time")
+        .as_micros() as u64
+}
+
+/// Ledger version and block height we've seen an RPC at. Health checks set them to
+/// what the RPC reports, so one that got reset or restored from an older snapshot
+/// doesn't look caught up forever. The headers of every response in between only
+/// move them forward, as those can arrive out of order.
+///
+/// Shared between all clones of the same `Rpc`, so whoever talks to it can update it.
+#[derive(Debug, Default)]
+pub struct LedgerState {
+    // 0 if we don't know yet
+    version: AtomicU64,
+    block_height: AtomicU64,
+}
+
+impl LedgerState {
+    /// Take what a health check reported as where the RPC is at now
+    pub fn set(&self, version: u64, block_height: u64) {
+        self.version.store(version, Ordering::Relaxed);
+        self.block_height.store(block_height, Ordering::Relaxed);
+    }
+
+    pub fn observe(&self, version: u64) {
+        self.version.fetch_max(version, Ordering::Relaxed);
+    }
+
+    pub fn observe_block_height(&self, block_height: u64) {
+        self.block_height.fetch_max(block_height, Ordering::Relaxed);
+    }
+
+    /// Record whatever ledger info the response headers carry
+    pub fn observe_headers(&self, headers: &HeaderMap) {
+        if let Some(version) = ledger_version(headers) {
+            self.observe(version);
+        }
+        if let Some(block_height) = block_height(headers) {
+            self.observe_block_height(block_height);
+        }
+    }
+
+    pub fn version(&self) -> Option<u64> {
+        match self.version.load(Ordering::Relaxed) {
+            0 => None,
+            version => Some(version),
+        }
+    }
+
+    pub fn block_height(&self) -> Option<u64> {
+        match self.block_height.load(Ordering::Relaxed) {
+            0 => None,
+            block_height => Some(block_height),
+        }
+    }
+
+    /// True if we know the RPC is more than `max_lag` blocks behind `head`
+    pub fn is_lagging(&self, head: Option<u64>, max_lag: Option<u64>) -> bool {
+        match (self.block_height(), head, max_lag) {
+            (Some(block_height), Some(head), Some(max_lag)) => {
+                head.saturating_sub(block_height) > max_lag
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A fully buffered response from an RPC
+#[derive(Debug, Clone)]
+pub struct RpcResponse {
+    pub body: Bytes,
+    pub status: u16,
+    pub headers: HeaderMap,
+}
+
+impl RpcResponse {
+    /// The Content-Type the RPC answered with, if it sent a readable one
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Rpc {
     pub name: String,           // sanitized name for appearing in logs
@@ -36,12 +157,19 @@ pub struct Rpc {
     client: Client,             // Reqwest client
     pub ws_url: Option<String>, // url of the websocket we're forwarding requests to.
     pub status: Status,         // stores stats related to the rpc.
+    pub ledger: Arc<LedgerState>, // where the rpc is at on the chain.
     // For max_consecutive
     pub max_consecutive: u32, // max times we can call an rpc in a row
     pub consecutive: u32,
     // For max_per_second
     pub last_used: u128,      // last time we sent a querry to this node
     pub min_time_delta: u128, // microseconds
+    // Upstream group API keys can be pinned to
+    pub group: Option<String>,
+    // Lower is preferred, RPCs with a higher value only take spillover
+    pub priority: u32,
+    // Largest response body we're willing to buffer
+    pub max_response_size: usize,
 }
 
 /// Sanitizes URLs so secrets don't get outputed.
@@ -73,10 +201,14 @@ impl Default for Rpc {
             ws_url: None,
             client: Client::new(),
             status: Status::default(),
+            ledger: Arc::new(LedgerState::default()),
             max_consecutive: 0,
             consecutive: 0,
             last_used: 0,
             min_time_delta: 0,
+            group: None,
+            priority: 0,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
         }
     }
 }
@@ -99,19 +231,24 @@ impl Rpc {
                 ma_length,
                 ..Default::default()
             },
+            ledger: Arc::new(LedgerState::default()),
             max_consecutive,
             consecutive: 0,
             last_used: 0,
             min_time_delta,
+            group: None,
+            priority: 0,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
         }
     }
 
-    // Send requests using hyper
-    pub async fn send_request(
+    // Send requests using hyper and hand back the response as soon as the headers arrive.
+    // The body is left to the caller, so it can either be buffered or streamed.
+    pub async fn request(
         &self,
         parts: Parts,
         body_bytes: Bytes, /* other params */
-    ) -> Result<(String, u16), Box<dyn std::error::Error>> {
+    ) -> Result<hyper::Response<Incoming>, Box<dyn std::error::Error + Send + Sync>> {
         let allowed_headers = vec![
             "Content-Type".to_string(),
             "Authorization".to_string(),
@@ -149,11 +286,33 @@ impl Rpc {
         *new_request.headers_mut() = filtered_headers;
 
         let response = client.request(new_request).await?;
+
+        // Every Aptos response says where the node is at, so we get to track it for free
+        self.ledger.observe_headers(response.headers());
+
+        Ok(response)
+    }
+
+    // Send requests using hyper and buffer the whole response body.
+    // Bodies over `max_response_size` fail with a `LengthLimitError`.
+    pub async fn send_request(
+        &self,
+        parts: Parts,
+        body_bytes: Bytes,
+    ) -> Result<RpcResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.request(parts, body_bytes).await?;
         let status = response.status().as_u16();
+        let (parts, body) = response.into_parts();
         // Convert the response body
-        let body_bytes = response.collect().await?.to_bytes();
-        let body_string = String::from_utf8(body_bytes.to_vec())?;
-        Ok((body_string, status))
+        let body = Limited::new(body, self.max_response_size)
+            .collect()
+            .await?
+            .to_bytes();
+        Ok(RpcResponse {
+            body,
+            status,
+            headers: parts.headers,
+        })
     }
 
     //function to send and get aptos rpc status response
@@ -172,6 +331,34 @@ impl Rpc {
         Ok(is_valid)
     }
 
+    /// Returns the latency (in ns) at `percentile` of the recorded latency history,
+    /// or `None` if we don't have any data for this RPC yet.
+    pub fn latency_percentile(&self, percentile: f64) -> Option<f64> {
+        if self.status.latency_data.is_empty() {
+            return None;
+        }
+
+        let mut data = self.status.latency_data.clone();
+        data.sort_by(|a, b| a.total_cmp(b));
+
+        let rank = ((percentile / 100.0) * (data.len() - 1) as f64).round() as usize;
+        Some(data[rank.min(data.len() - 1)])
+    }
+
+    /// Pick up where a previous run left off. Only the most recent `ma_length` samples are kept.
+    pub fn restore_latency(&mut self, mut latency_data: Vec<f64>) {
+        let excess = latency_data
+            .len()
+            .saturating_sub(self.status.ma_length as usize);
+        latency_data.drain(..excess);
+
+        self.status.latency = match latency_data.len() {
+            0 => 0.0,
+            len => latency_data.iter().sum::<f64>() / len as f64,
+        };
+        self.status.latency_data = latency_data;
+    }
+
     /// Update the latency of the last n calls.
     /// We don't do it within send_request because we might kill it if it times out.
     pub fn update_latency(&mut self, latest: f64) {
@@ -186,3 +373,100 @@ impl Rpc {
             self.status.latency_data.iter().sum::<f64>() / self.status.latency_data.len() as f64;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::{
+        parts,
+        upstream,
+    };
+    use http_body_util::LengthLimitError;
+
+    #[tokio::test]
+    async fn buffered_responses_are_limited() {
+        let url = upstream("{\"chain_id\":1}").await;
+        let mut rpc = Rpc::new(url, None, 15, 0, 3.0);
+
+        let response = rpc.send_request(parts("GET", "/v1", &[]), Bytes::new()).await.unwrap();
+        assert_eq!(response.body, Bytes::from("{\"chain_id\":1}"));
+        assert_eq!(response.content_type(), Some("application/json"));
+
+        rpc.max_response_size = 4;
+        let error = rpc.send_request(parts("GET", "/v1", &[]), Bytes::new()).await.unwrap_err();
+        assert!(error.downcast_ref::<LengthLimitError>().is_some());
+    }
+
+    #[test]
+    fn health_checks_can_move_the_ledger_back() {
+        let ledger = LedgerState::default();
+        assert_eq!(ledger.version(), None);
+
+        ledger.set(100, 50);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-aptos-ledger-version", "90".parse().unwrap());
+        headers.insert("x-aptos-block-height", "45".parse().unwrap());
+        ledger.observe_headers(&headers);
+        // Responses that were sent earlier don't move it back
+        assert_eq!(ledger.version(), Some(100));
+        assert_eq!(ledger.block_height(), Some(50));
+
+        // But a health check that finds the RPC further behind does
+        ledger.set(80, 40);
+        assert_eq!(ledger.version(), Some(80));
+        assert_eq!(ledger.block_height(), Some(40));
+    }
+
+    #[test]
+    fn lagging() {
+        let ledger = LedgerState::default();
+        assert!(!ledger.is_lagging(Some(100), Some(10)));
+
+        ledger.set(200, 89);
+        assert!(ledger.is_lagging(Some(100), Some(10)));
+        assert!(!ledger.is_lagging(Some(99), Some(10)));
+        assert!(!ledger.is_lagging(Some(100), None));
+    }
+
+    #[test]
+    fn latency_percentiles() {
+        let mut rpc = Rpc::new("http://127.0.0.1:9101".to_string(), None, 15, 0, 5.0);
+        assert_eq!(rpc.latency_percentile(95.0), None);
+
+        rpc.restore_latency(vec![40.0, 10.0, f64::NAN, 30.0, 20.0]);
+        assert_eq!(rpc.latency_percentile(0.0), Some(10.0));
+        assert_eq!(rpc.latency_percentile(50.0), Some(30.0));
+        // NaN sorts last instead of panicking
+        assert!(rpc.latency_percentile(100.0).unwrap().is_nan());
+    }
+
+    #[test]
+    fn backoff() {
+        let mut status = Status::default();
+        assert!(!status.is_backing_off());
+
+        status.mark_erroring();
+        assert!(status.is_erroring);
+        assert!(status.is_backing_off());
+
+        // Sat out long enough
+        status.last_error -= ERROR_BACKOFF;
+        assert!(!status.is_backing_off());
+    }
+
+    #[test]
+    fn restored_latency() {
+        let mut rpc = Rpc::new("http://127.0.0.1:9101".to_string(), None, 15, 0, 3.0);
+        rpc.restore_latency(vec![100.0, 1.0, 2.0, 3.0]);
+        // Only the last `ma_length` samples are kept
+        assert_eq!(rpc.status.latency_data, vec![1.0, 2.0, 3.0]);
+        assert_eq!(rpc.status.latency, 2.0);
+
+        rpc.update_latency(6.0);
+        assert_eq!(rpc.status.latency_data, vec![2.0, 3.0, 6.0]);
+        assert_eq!(rpc.status.latency, 11.0 / 3.0);
+
+        rpc.restore_latency(Vec::new());
+        assert_eq!(rpc.status.latency, 0.0);
+    }
+}
@@ -0,0 +1,257 @@
+use crate::log_wrn;
+
+use std::{
+    env,
+    sync::atomic::{
+        AtomicU64,
+        Ordering,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// How often accept loops check in with the watchdog
+pub const HEARTBEAT: Duration = Duration::from_secs(1);
+
+/// Send `state`, like `READY=1`, to systemd. Does nothing unless we were
+/// started by a unit with `Type=notify` or a watchdog.
+///
+/// Talks to `$NOTIFY_SOCKET` directly, so it works without libsystemd.
+pub fn notify(state: &str) -> std::io::Result<()> {
+    let socket = match env::var("NOTIFY_SOCKET") {
+        Ok(socket) if !socket.is_empty() => socket,
+        _ => return Ok(()),
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::net::UnixDatagram;
+
+        let datagram = UnixDatagram::unbound()?;
+        match socket.strip_prefix('@') {
+            // Sockets in the abstract namespace start with @
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::{
+                    linux::net::SocketAddrExt,
+                    unix::net::SocketAddr,
+                };
+
+                let address = SocketAddr::from_abstract_name(name)?;
+                datagram.send_to_addr(state.as_bytes(), &address)?;
+            }
+            _ => {
+                datagram.send_to(state.as_bytes(), &socket)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = (socket, state);
+        Ok(())
+    }
+}
+
+/// Same as `notify`, but only warns if systemd couldn't be told
+pub fn notify_or_warn(state: &str) {
+    if let Err(e) = notify(state) {
+        log_wrn!("Could not notify systemd: {}", e);
+    }
+}
+
+/// `WatchdogSec` of our unit, if it has one and it's meant for us
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Listening sockets systemd passed us through socket activation, with the
+/// `FileDescriptorName` they were given. Empty if they aren't meant for us.
+/// Anything passed that isn't a listening TCP socket is left alone.
+///
+/// Only call this once, the sockets belong to whoever gets them first. It clears
+/// the variables systemd passed them in, so it has to run before any other threads start.
+pub fn listen_fds() -> Vec<(String, std::net::TcpListener)> {
+    if env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok())
+        != Some(std::process::id())
+    {
+        return Vec::new();
+    }
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<i32>().ok())
+        .unwrap_or(0);
+    let names: Vec<String> = env::var("LISTEN_FDNAMES")
+        .map(|names| names.split(':').map(|name| name.to_string()).collect())
+        .unwrap_or_default();
+
+    // So nothing we start thinks they're meant for it
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    #[cfg(unix)]
+    {
+        use std::os::fd::FromRawFd;
+
+        // Passed sockets start right after stdin, stdout and stderr
+        const LISTEN_FDS_START: i32 = 3;
+
+        (0..count.max(0))
+            .filter_map(|index| {
+                let fd = LISTEN_FDS_START + index;
+                let name = names.get(index as usize).cloned().unwrap_or_default();
+                if !is_tcp_listener(fd) {
+                    log_wrn!("Ignoring socket {:?} passed by systemd, it isn't listening for TCP", name);
+                    return None;
+                }
+
+                // SAFETY: systemd opened these for us and nothing else in the process owns them
+                let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                Some((name, listener))
+            })
+            .collect()
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = (count, names);
+        Vec::new()
+    }
+}
+
+/// Whether `fd` is an IPv4 or IPv6 stream socket that's listening for connections
+#[cfg(unix)]
+fn is_tcp_listener(fd: i32) -> bool {
+    let option = |name| {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: `value` and `len` are valid for the size we pass, and the kernel
+        // checks `fd` itself
+        let result = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        (result == 0).then_some(value)
+    };
+
+    if option(libc::SO_TYPE) != Some(libc::SOCK_STREAM) || option(libc::SO_ACCEPTCONN) != Some(1) {
+        return false;
+    }
+
+    // Unix sockets are streams too
+    // SAFETY: all zeroes is a valid `sockaddr_storage`, which fits any address
+    let mut address: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // SAFETY: `address` and `len` are valid for the size we pass
+    let result = unsafe {
+        libc::getsockname(
+            fd,
+            &mut address as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    result == 0 && matches!(address.ss_family as libc::c_int, libc::AF_INET | libc::AF_INET6)
+}
+
+/// Last time the accept loop got around to checking in. Watchdog pings stop
+/// once it falls behind, so systemd restarts us if it's stuck or gone.
+#[derive(Debug)]
+pub struct Heartbeat {
+    started: Instant,
+    // In ms since `started`
+    last: AtomicU64,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn beat(&self) {
+        self.last
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Release);
+    }
+
+    pub fn since_last(&self) -> Duration {
+        self.started
+            .elapsed()
+            .saturating_sub(Duration::from_millis(self.last.load(Ordering::Acquire)))
+    }
+}
+
+/// Ping the systemd watchdog twice per `interval`, for as long as `heartbeat` keeps beating
+pub async fn watchdog(heartbeat: &Heartbeat, interval: Duration) {
+    let every = interval / 2;
+
+    loop {
+        tokio::time::sleep(every).await;
+
+        let since_last = heartbeat.since_last();
+        if since_last <= every + HEARTBEAT {
+            notify_or_warn("WATCHDOG=1");
+        } else {
+            log_wrn!(
+                "Accept loop hasn't checked in for {}ms, not pinging the watchdog",
+                since_last.as_millis()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn only_tcp_listeners_are_taken() {
+        use std::os::fd::AsRawFd;
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(is_tcp_listener(tcp.as_raw_fd()));
+
+        let connected = std::net::TcpStream::connect(tcp.local_addr().unwrap()).unwrap();
+        assert!(!is_tcp_listener(connected.as_raw_fd()));
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(!is_tcp_listener(udp.as_raw_fd()));
+        let (unix, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        assert!(!is_tcp_listener(unix.as_raw_fd()));
+        let file = std::fs::File::open("Cargo.toml").unwrap();
+        assert!(!is_tcp_listener(file.as_raw_fd()));
+    }
+
+    #[test]
+    fn heartbeat() {
+        let heartbeat = Heartbeat::new();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(heartbeat.since_last() >= Duration::from_millis(20));
+
+        heartbeat.beat();
+        assert!(heartbeat.since_last() < Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,74 @@
+// Fixtures shared by the unit tests, so they don't each build their own
+pub mod redis;
+
+use crate::core::{
+    invalidation::Invalidator,
+    networks::Network,
+};
+
+use http::{
+    request::Parts,
+    Request,
+};
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc,
+        RwLock,
+    },
+};
+use tokio::{
+    io::{
+        AsyncReadExt,
+        AsyncWriteExt,
+    },
+    net::TcpListener,
+};
+
+/// Head of a `method` request to `uri` with `headers` set
+pub fn parts(method: &str, uri: &str, headers: &[(&str, &str)]) -> Parts {
+    let mut request = Request::builder().method(method).uri(uri);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request.body(()).unwrap().into_parts().0
+}
+
+/// Network without any RPCs. Set whatever else a test needs with `..network(name)`.
+pub fn network(name: Option<&str>) -> Network {
+    Network {
+        name: name.map(|name| name.to_string()),
+        chain_id: None,
+        path_prefix: None,
+        hosts: Vec::new(),
+        rpc_list: Arc::new(RwLock::new(Vec::new())),
+        poverty_list: Arc::new(RwLock::new(Vec::new())),
+        invalidator: Arc::new(Invalidator::new()),
+    }
+}
+
+/// Listener on a free local port, along with its address
+pub async fn listen() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    (listener, address)
+}
+
+/// Stand-in upstream that answers every request with `body` as JSON, then hangs up.
+/// Returns its URL.
+pub async fn upstream(body: &'static str) -> String {
+    let (listener, address) = listen().await;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+    format!("http://{}", address)
+}
@@ -0,0 +1,136 @@
+// Just enough of a Redis server for tests: GET, SET, DEL, SCAN, AUTH and SELECT
+// on a single map. SCAN hands out two keys per call, so callers have to page.
+use super::listen;
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+use tokio::{
+    io::{
+        AsyncBufReadExt,
+        AsyncReadExt,
+        AsyncWriteExt,
+        BufStream,
+    },
+    net::TcpStream,
+};
+
+#[derive(Debug, Default)]
+pub struct Server {
+    pub data: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+    // Every command received, first argument only
+    pub commands: Mutex<Vec<String>>,
+}
+
+/// Start a server on a free port, returns it along with its `redis://` URL
+pub async fn spawn() -> (Arc<Server>, String) {
+    let (listener, address) = listen().await;
+    let url = format!("redis://{}", address);
+    let server = Arc::new(Server::default());
+
+    let shared = Arc::clone(&server);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(Arc::clone(&shared), stream));
+        }
+    });
+    (server, url)
+}
+
+async fn serve(server: Arc<Server>, stream: TcpStream) {
+    let mut stream = BufStream::new(stream);
+    while let Some(args) = read_command(&mut stream).await {
+        let reply = server.execute(&args);
+        if stream.write_all(&reply).await.is_err() || stream.flush().await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn read_command(stream: &mut BufStream<TcpStream>) -> Option<Vec<Vec<u8>>> {
+    let mut line = String::new();
+    stream.read_line(&mut line).await.ok()?;
+    let count: usize = line.strip_prefix('*')?.trim_end().parse().ok()?;
+
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        stream.read_line(&mut line).await.ok()?;
+        let length: usize = line.strip_prefix('$')?.trim_end().parse().ok()?;
+        let mut arg = vec![0; length + 2];
+        stream.read_exact(&mut arg).await.ok()?;
+        arg.truncate(length);
+        args.push(arg);
+    }
+    Some(args)
+}
+
+fn bulk(value: &[u8]) -> Vec<u8> {
+    let mut reply = format!("${}\r\n", value.len()).into_bytes();
+    reply.extend_from_slice(value);
+    reply.extend_from_slice(b"\r\n");
+    reply
+}
+
+impl Server {
+    fn execute(&self, args: &[Vec<u8>]) -> Vec<u8> {
+        let command = String::from_utf8_lossy(&args[0]).to_uppercase();
+        self.commands.lock().unwrap().push(command.clone());
+        let mut data = self.data.lock().unwrap();
+
+        match (command.as_str(), &args[1..]) {
+            ("AUTH" | "SELECT", _) => b"+OK\r\n".to_vec(),
+            ("GET", [key]) => match data.get(key) {
+                Some(value) => bulk(value),
+                None => b"$-1\r\n".to_vec(),
+            },
+            // Expiry isn't simulated
+            ("SET", [key, value, ..]) => {
+                data.insert(key.clone(), value.clone());
+                b"+OK\r\n".to_vec()
+            }
+            ("DEL", [key]) => format!(":{}\r\n", data.remove(key).map_or(0, |_| 1)).into_bytes(),
+            ("SCAN", [cursor, _, pattern, ..]) => {
+                let cursor: usize = String::from_utf8_lossy(cursor).parse().unwrap();
+                let keys: Vec<&Vec<u8>> = data.keys().skip(cursor).take(2).collect();
+                let next = match cursor + keys.len() {
+                    next if next >= data.len() => 0,
+                    next => next,
+                };
+
+                let matched: Vec<&&Vec<u8>> =
+                    keys.iter().filter(|key| glob_matches(pattern, key)).collect();
+                let mut reply = b"*2\r\n".to_vec();
+                reply.extend(bulk(next.to_string().as_bytes()));
+                reply.extend(format!("*{}\r\n", matched.len()).into_bytes());
+                for key in matched {
+                    reply.extend(bulk(key));
+                }
+                reply
+            }
+            _ => format!("-ERR unknown command '{}'\r\n", command).into_bytes(),
+        }
+    }
+}
+
+/// Redis glob matching, without character classes
+pub fn glob_matches(pattern: &[u8], key: &[u8]) -> bool {
+    match (pattern.first(), key.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_matches(&pattern[1..], key)
+                || (!key.is_empty() && glob_matches(pattern, &key[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_matches(&pattern[1..], &key[1..]),
+        (Some(b'\\'), Some(c)) if pattern.get(1) == Some(c) => {
+            glob_matches(&pattern[2..], &key[1..])
+        }
+        (Some(b'\\'), _) => false,
+        (Some(p), Some(c)) if p == c => glob_matches(&pattern[1..], &key[1..]),
+        _ => false,
+    }
+}